@@ -11,6 +11,12 @@ pub enum Error {
     InvalidAddr,
     #[error("failed to send packet!")]
     FailedToSend,
+    #[error("invalid key: expected 32 bytes, or 64 hexadecimal characters")]
+    InvalidKey,
+    #[error("the peer is not known to this endpoint")]
+    UnknownPeer,
+    #[error("the peer is this node itself")]
+    SelfConnection,
 }
 
 impl From<async_std::io::Error> for Error {
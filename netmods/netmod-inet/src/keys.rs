@@ -0,0 +1,142 @@
+//! Node keys used to authenticate connections
+//!
+//! Each endpoint has a static x25519 keypair, which is used in the
+//! handshake of every connection.  Peers are identified by their
+//! public key, which can be pinned to only accept connections from
+//! trusted nodes.
+
+use crate::{Error, Result};
+use std::{collections::BTreeSet, convert::TryInto, fmt, str::FromStr};
+use x25519_dalek::StaticSecret;
+
+/// The public key of an inet endpoint
+///
+/// Keys are written as 64 hexadecimal characters.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    /// Get the raw bytes of this key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub(crate) fn from_slice(buf: &[u8]) -> Option<Self> {
+        buf.try_into().ok().map(Self)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<PublicKey: {}>", self)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(Error::InvalidKey);
+        }
+
+        let mut buf = [0; 32];
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| Error::InvalidKey)?;
+        }
+        Ok(Self(buf))
+    }
+}
+
+/// The static keypair of an inet endpoint
+#[derive(Clone)]
+pub struct Keypair {
+    secret: [u8; 32],
+    public: PublicKey,
+}
+
+impl Keypair {
+    /// Generate a new random keypair
+    pub fn generate() -> Self {
+        let secret = snow::Builder::new(crate::noise::PARAMS.parse().unwrap())
+            .generate_keypair()
+            .unwrap()
+            .private;
+        Self::from_bytes(&secret).unwrap()
+    }
+
+    /// Load a keypair from a secret key
+    pub fn from_bytes(secret: &[u8]) -> Result<Self> {
+        let secret: [u8; 32] = secret.try_into().map_err(|_| Error::InvalidKey)?;
+        let public = x25519_dalek::PublicKey::from(&StaticSecret::from(secret));
+        Ok(Self {
+            secret,
+            public: PublicKey(public.to_bytes()),
+        })
+    }
+
+    /// Get the secret key to store it
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.secret
+    }
+
+    /// Get the public key of this keypair
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<Keypair: {}>", self.public)
+    }
+}
+
+/// The local keypair and the set of trusted peer keys
+#[derive(Debug)]
+pub(crate) struct Keys {
+    pub(crate) keypair: Keypair,
+    trusted: BTreeSet<PublicKey>,
+}
+
+impl Keys {
+    pub(crate) fn new(keypair: Keypair, trusted: Vec<PublicKey>) -> Self {
+        Self {
+            keypair,
+            trusted: trusted.into_iter().collect(),
+        }
+    }
+
+    /// Check whether connections are limited to pinned keys
+    pub(crate) fn pinned(&self) -> bool {
+        !self.trusted.is_empty()
+    }
+
+    /// Check whether a peer with this key may connect
+    ///
+    /// If no keys were pinned, any peer is accepted.
+    pub(crate) fn trusts(&self, key: &PublicKey) -> bool {
+        !self.pinned() || self.trusted.contains(key)
+    }
+}
+
+#[test]
+fn parse_public_key() {
+    let key = Keypair::generate().public();
+    assert_eq!(key.to_string().parse::<PublicKey>().unwrap(), key);
+    assert!("abcd".parse::<PublicKey>().is_err());
+    assert!("zz".repeat(32).parse::<PublicKey>().is_err());
+}
+
+#[test]
+fn restore_keypair() {
+    let keys = Keypair::generate();
+    let restored = Keypair::from_bytes(&keys.to_bytes()).unwrap();
+    assert_eq!(keys.public(), restored.public());
+}
@@ -5,17 +5,28 @@ extern crate tracing;
 
 mod error;
 mod io;
+mod keys;
+mod local;
+mod noise;
 mod peer;
+mod pex;
 mod proto;
 mod ptr;
+mod queue;
 mod resolve;
 mod routes;
 mod server;
 
 pub use error::{Error, Result};
+pub use keys::{Keypair, PublicKey};
+pub use peer::{PeerInfo, PeerState};
 
 pub(crate) use io::IoPair;
-pub(crate) use peer::{DstAddr, Peer, PeerState, SourceAddr};
+pub(crate) use keys::Keys;
+pub(crate) use local::Local;
+pub(crate) use noise::{NoiseReader, NoiseStream, NoiseWriter};
+pub(crate) use peer::{DstAddr, Peer, SourceAddr};
+pub(crate) use pex::Pex;
 pub(crate) use proto::{Packet, PacketBuilder};
 pub(crate) use ptr::AtomPtr;
 pub(crate) use resolve::Resolver;
@@ -26,12 +37,14 @@ use async_std::{net::SocketAddr, sync::Arc};
 use async_trait::async_trait;
 use netmod::{self, Endpoint as EndpointExt, Frame, Target};
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::{sync::atomic::Ordering, time::SystemTime};
 
 /// Define the runtime mode for this endpount
 ///
 /// In dynamic mode any new peer can introduce itself to start a link,
-/// while in static mode only known peers will be accepted.
+/// while in static mode only known peers will be accepted.  If a set
+/// of trusted keys was given, peers are known by their key instead of
+/// their address.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Mode {
     Static,
@@ -46,15 +59,15 @@ pub enum Mode {
 /// networks this isn't possible.  While `Bidirect` is a good default,
 /// it's possible to override this behaviour.
 ///
-/// `Limited` will open connections to peers with a special flag that
-/// makes it use a different reverse-channel strategy.  The server
-/// won't try to create full reverse channels, and instead use the
-/// incoming message stream.
+/// `Limited` links use a single connection in both directions.  The
+/// server won't try to open a reverse connection, and instead sends
+/// its packets via the incoming connection.  This allows nodes behind
+/// a NAT to peer with nodes that have a public address.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum LinkType {
     /// Default connection type
     Bidirect,
-    /// Fallback connection type
+    /// Single connection, opened by the local node
     Limited,
 }
 
@@ -64,40 +77,67 @@ impl Default for LinkType {
     }
 }
 
+/// An internet overlay endpoint
+///
+/// All connections are encrypted and authenticated with the
+/// endpoint's keypair.
 #[derive(Clone)]
 pub struct Endpoint {
-    #[allow(unused)]
-    pessimistic: Arc<AtomicBool>,
     server: Arc<Server>,
     routes: Arc<Routes>,
+    local: Arc<Local>,
 }
 
 impl Endpoint {
     /// Create a new endpoint on an interface and port
-    #[tracing::instrument(level = "info")]
+    ///
+    /// A random keypair is generated for this endpoint, and peers are
+    /// accepted with any key.
     pub async fn new(bind: &str, name: &str, mode: Mode) -> Result<Arc<Self>> {
+        Self::with_keys(bind, name, mode, Keypair::generate(), vec![]).await
+    }
+
+    /// Create a new endpoint with a keypair and a set of trusted keys
+    ///
+    /// If `trusted` isn't empty, only connections from peers with one
+    /// of these keys are accepted, and outgoing connections to peers
+    /// with any other key are dropped.
+    #[tracing::instrument(level = "info", skip(keypair, trusted))]
+    pub async fn with_keys(
+        bind: &str,
+        name: &str,
+        mode: Mode,
+        keypair: Keypair,
+        trusted: Vec<PublicKey>,
+    ) -> Result<Arc<Self>> {
         info!("Initialising Tcp backend");
 
-        let pessimistic = Arc::new(false.into());
         let socket: SocketAddr = bind.parse().map_err(|_| Error::InvalidAddr)?;
-        let routes = Routes::new(socket.port());
-        let server = Server::new(
-            Arc::clone(&routes),
-            socket,
-            socket.port(),
-            mode,
-            Arc::clone(&pessimistic),
-        )
-        .await?;
+        let keys = Keys::new(keypair, trusted);
+        let local = Arc::new(Local::new(socket.port(), keys));
+        let routes = Routes::new(Arc::clone(&local));
+        let server = Server::new(Arc::clone(&routes), Arc::clone(&local), socket, mode).await?;
+
+        if mode == Mode::Dynamic {
+            local.pex.set_max_peers(pex::DEFAULT_MAX_PEERS);
+        }
 
         server.run();
+        pex::run(Arc::clone(&routes), Arc::clone(&local));
         Ok(Arc::new(Self {
-            pessimistic,
             server,
             routes,
+            local,
         }))
     }
 
+    /// Get the public key of this endpoint
+    ///
+    /// Other nodes can pin this key to trust this endpoint.
+    pub fn public_key(&self) -> PublicKey {
+        self.local.keys.keypair.public()
+    }
+
     /// Get the current runtime mode
     pub fn mode(&self) -> Mode {
         self.server.mode()
@@ -115,11 +155,36 @@ impl Endpoint {
     /// connection type after a short timeout during which no reverse
     /// connection was established.
     pub fn pessimistic(&self) {
-        self.pessimistic.fetch_or(true, Ordering::Relaxed);
+        self.local.pessimistic.fetch_or(true, Ordering::Relaxed);
+    }
+
+    /// Connect to peers shared by other peers
+    ///
+    /// Connected peers share the addresses of peers they can reach
+    /// with each other.  The endpoint connects to these addresses
+    /// until it knows `max_peers` peers.  This is enabled with a
+    /// default of 8 peers in dynamic mode, and disabled by setting
+    /// `max_peers` to 0.
+    pub fn peer_exchange(&self, max_peers: usize) {
+        self.local.pex.set_max_peers(max_peers);
+    }
+
+    /// Limit the bandwidth used for each peer
+    ///
+    /// At most `bytes_per_second` are sent to each peer, with bursts
+    /// of up to one second.  Frames are queued for a peer while it's
+    /// at its limit, and dropped once its queue is full; `peers`
+    /// reports how many were dropped.  A limit of 0 (the default)
+    /// disables rate limiting.
+    pub fn rate_limit(&self, bytes_per_second: u64) {
+        self.local
+            .rate_limit
+            .store(bytes_per_second, Ordering::Relaxed);
     }
 
     pub async fn stop(&self) {
         self.server.stop();
+        self.local.pex.stop();
         self.routes.stop_all().await;
     }
 
@@ -147,6 +212,59 @@ impl Endpoint {
 
         Ok(())
     }
+
+    /// Remove a set of peers from the routing table
+    ///
+    /// Peers are given in the same format as for `add_peers`.  Their
+    /// connections are closed, and they will no longer be contacted
+    /// unless they are added again.  Unknown peers are skipped.
+    pub async fn remove_peers(&self, peers: Vec<String>) -> Result<()> {
+        for p in peers.into_iter() {
+            if p.is_empty() {
+                continue;
+            }
+
+            match self.remove_peer(&p).await {
+                Ok(()) => trace!("Removed peer: {}", p),
+                Err(e) => warn!("Failed to remove peer '{}': {}... skipping", p, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove a single peer from the routing table
+    ///
+    /// The peer is given in the same format as for `add_peers`, and
+    /// can also be one that connected to this endpoint.  Its
+    /// connection is closed and all of its tasks are stopped.
+    pub async fn remove_peer(&self, peer: &str) -> Result<()> {
+        let (dst, _) = Resolver::resolve(peer).await.ok_or(Error::InvalidAddr)?;
+        match self.routes.remove_via_dst(&dst).await {
+            true => Ok(()),
+            false => Err(Error::UnknownPeer),
+        }
+    }
+
+    /// List all peers along with their connection state
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        self.routes
+            .all()
+            .await
+            .iter()
+            .filter_map(|p| p.info())
+            .collect()
+    }
+
+    /// Get the most recent state changes of a peer, oldest first
+    ///
+    /// The peer is given in the same format as for `add_peers`.
+    /// Returns `None` if the peer isn't known.
+    pub async fn peer_history(&self, peer: &str) -> Option<Vec<(SystemTime, PeerState)>> {
+        let (dst, _) = Resolver::resolve(peer).await?;
+        let id = self.routes.find_via_dst(&dst).await?;
+        self.routes.get_peer(id).await.map(|p| p.history())
+    }
 }
 
 #[async_trait]
@@ -156,11 +274,16 @@ impl EndpointExt for Endpoint {
     }
 
     async fn send(&self, frame: Frame, target: Target) -> netmod::Result<()> {
+        let packet = Packet::Frame(frame);
+        if packet.size() > noise::MAX_PACKET_LEN {
+            return Err(netmod::Error::FrameTooLarge);
+        }
+
         match target {
             Target::Flood => {
                 let dsts = self.routes.all_dst().await;
                 for peer in dsts {
-                    peer.send(Packet::Frame(frame.clone())).await;
+                    peer.flood(packet.clone());
                 }
             }
             Target::Single(id) => {
@@ -168,7 +291,7 @@ impl EndpointExt for Endpoint {
                     Some(p) => Ok(p),
                     None => Err(netmod::Error::ConnectionLost),
                 }?;
-                peer.send(Packet::Frame(frame)).await;
+                peer.send(packet);
             }
         }
 
@@ -179,3 +302,219 @@ impl EndpointExt for Endpoint {
         Ok(self.server.next().await)
     }
 }
+
+#[cfg(test)]
+async fn exchange_frames(a: &Endpoint, b: &Endpoint) {
+    use async_std::future::timeout;
+    use std::time::Duration;
+
+    let t = Duration::from_secs(10);
+    let frame = Frame::dummy();
+    a.send(frame.clone(), Target::Flood).await.unwrap();
+    let (f, target) = timeout(t, b.next()).await.unwrap().unwrap();
+    assert_eq!(f, frame);
+
+    b.send(frame.clone(), target).await.unwrap();
+    let (f, _) = timeout(t, a.next()).await.unwrap().unwrap();
+    assert_eq!(f, frame);
+}
+
+#[async_std::test]
+async fn bidirect_link() {
+    let a = Endpoint::new("127.0.0.1:19301", "a", Mode::Static)
+        .await
+        .unwrap();
+    let b = Endpoint::new("127.0.0.1:19302", "b", Mode::Dynamic)
+        .await
+        .unwrap();
+    a.add_peers(vec!["127.0.0.1:19302".into()]).await.unwrap();
+
+    exchange_frames(&a, &b).await;
+    a.stop().await;
+    b.stop().await;
+}
+
+#[async_std::test]
+async fn limited_link() {
+    // Without a listener, frames can only reach the node via its own
+    // connection, as if it was behind a NAT
+    let a = Endpoint::new("127.0.0.1:19303", "a", Mode::Static)
+        .await
+        .unwrap();
+    let b = Endpoint::new("127.0.0.1:19304", "b", Mode::Dynamic)
+        .await
+        .unwrap();
+    a.server.stop();
+    a.add_peers(vec!["127.0.0.1:19304L".into()]).await.unwrap();
+
+    exchange_frames(&a, &b).await;
+    a.stop().await;
+    b.stop().await;
+}
+
+#[async_std::test]
+async fn reconnect_with_queued_frame() {
+    use async_std::{future::timeout, task};
+    use std::time::Duration;
+
+    let a = Endpoint::new("127.0.0.1:19305", "a", Mode::Static)
+        .await
+        .unwrap();
+    a.add_peers(vec!["127.0.0.1:19306".into()]).await.unwrap();
+
+    // The frame is held until the peer comes up
+    let frame = Frame::dummy();
+    a.send(frame.clone(), Target::Flood).await.unwrap();
+    task::sleep(Duration::from_secs(1)).await;
+
+    let b = Endpoint::new("127.0.0.1:19306", "b", Mode::Dynamic)
+        .await
+        .unwrap();
+    let (f, _) = timeout(Duration::from_secs(10), b.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(f, frame);
+
+    let history = a.peer_history("127.0.0.1:19306").await.unwrap();
+    assert_eq!(history.first().unwrap().1, PeerState::Disconnected);
+    assert_ne!(history.last().unwrap().1, PeerState::Disconnected);
+
+    a.stop().await;
+    b.stop().await;
+}
+
+#[async_std::test]
+async fn list_and_remove_peers() {
+    use async_std::{future::timeout, task};
+    use std::time::Duration;
+
+    let a = Endpoint::new("127.0.0.1:19307", "a", Mode::Static)
+        .await
+        .unwrap();
+    let b = Endpoint::new("127.0.0.1:19308", "b", Mode::Dynamic)
+        .await
+        .unwrap();
+    a.add_peers(vec!["127.0.0.1:19308".into()]).await.unwrap();
+    exchange_frames(&a, &b).await;
+
+    let peers = a.peers().await;
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].addr, "127.0.0.1:19308".parse().unwrap());
+    assert_eq!(peers[0].link, LinkType::Bidirect);
+    assert!(peers[0].bytes_sent > 0 && peers[0].bytes_received > 0);
+    assert!(peers[0].last_seen.is_some());
+
+    // Both connections are closed once the peer is removed
+    a.remove_peer("127.0.0.1:19308").await.unwrap();
+    assert!(a.peers().await.is_empty());
+    assert!(a.remove_peer("127.0.0.1:19308").await.is_err());
+
+    timeout(Duration::from_secs(5), async {
+        while b
+            .peers()
+            .await
+            .iter()
+            .any(|p| p.state != PeerState::Disconnected)
+        {
+            task::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    a.stop().await;
+    b.stop().await;
+}
+
+#[async_std::test]
+async fn peer_exchange() {
+    use async_std::{future::timeout, task};
+    use std::time::Duration;
+
+    // B and C only know A, and learn about each other from it
+    let a = Endpoint::new("127.0.0.1:19309", "a", Mode::Dynamic)
+        .await
+        .unwrap();
+    let b = Endpoint::new("127.0.0.1:19310", "b", Mode::Dynamic)
+        .await
+        .unwrap();
+    let c = Endpoint::new("127.0.0.1:19311", "c", Mode::Dynamic)
+        .await
+        .unwrap();
+    b.add_peers(vec!["127.0.0.1:19309".into()]).await.unwrap();
+    c.add_peers(vec!["127.0.0.1:19309".into()]).await.unwrap();
+
+    let c_addr = "127.0.0.1:19311".parse().unwrap();
+    timeout(Duration::from_secs(10), async {
+        while !b
+            .peers()
+            .await
+            .iter()
+            .any(|p| p.addr == c_addr && p.state == PeerState::Duplex)
+        {
+            task::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    a.stop().await;
+    b.stop().await;
+    c.stop().await;
+}
+
+#[async_std::test]
+async fn connect_to_self() {
+    use async_std::{future::timeout, task};
+    use std::time::Duration;
+
+    let a = Endpoint::new("127.0.0.1:19314", "a", Mode::Static)
+        .await
+        .unwrap();
+    a.add_peers(vec!["127.0.0.1:19314".into()]).await.unwrap();
+
+    // The peer is given up instead of being retried
+    let addr = "127.0.0.1:19314".parse().unwrap();
+    timeout(Duration::from_secs(5), async {
+        while !a.local.pex.is_own(&addr) {
+            task::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(a
+        .peers()
+        .await
+        .iter()
+        .all(|p| p.state == PeerState::Disconnected));
+    a.stop().await;
+}
+
+#[async_std::test]
+async fn drop_floods_for_slow_peer() {
+    // Use free ports so no other test's endpoint answers in between
+    let a = Endpoint::new("127.0.0.1:0", "a", Mode::Static)
+        .await
+        .unwrap();
+    let unreachable = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .unwrap();
+    a.rate_limit(1024);
+    a.add_peers(vec![unreachable.to_string()]).await.unwrap();
+
+    // The peer is unreachable, so its queue fills up and further
+    // floods are dropped, while unicast frames are still queued
+    for _ in 0..64 {
+        a.send(Frame::dummy(), Target::Flood).await.unwrap();
+    }
+    let id = a.routes.all().await[0].id;
+    a.send(Frame::dummy(), Target::Single(id as u16))
+        .await
+        .unwrap();
+
+    let peers = a.peers().await;
+    assert!(peers[0].dropped_flood > 0);
+    assert_eq!(peers[0].dropped_unicast, 0);
+    a.stop().await;
+}
@@ -0,0 +1,35 @@
+//! State of the local endpoint that is shared with all peers
+
+use crate::{IoPair, Keys, Pex};
+use netmod::Frame;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+
+/// Settings and channels needed to open and serve connections
+#[derive(Debug)]
+pub(crate) struct Local {
+    /// The port this endpoint is listening on
+    pub(crate) port: u16,
+    /// Keys used to authenticate connections
+    pub(crate) keys: Keys,
+    /// Fall back to limited links if no reverse connection is made
+    pub(crate) pessimistic: AtomicBool,
+    /// The most bytes per second sent to each peer, or 0 for no limit
+    pub(crate) rate_limit: AtomicU64,
+    /// Frames received from any peer, along with the peer ID
+    pub(crate) incoming: IoPair<(Frame, usize)>,
+    /// Addresses shared with and learned from peers
+    pub(crate) pex: Pex,
+}
+
+impl Local {
+    pub(crate) fn new(port: u16, keys: Keys) -> Self {
+        Self {
+            port,
+            keys,
+            pessimistic: false.into(),
+            rate_limit: 0.into(),
+            incoming: IoPair::default(),
+            pex: Pex::default(),
+        }
+    }
+}
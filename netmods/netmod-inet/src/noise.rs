@@ -0,0 +1,354 @@
+//! Encrypted connections between peers
+//!
+//! Every TCP connection starts with a Noise XX handshake, during
+//! which both sides prove ownership of their static key.  Afterwards
+//! each packet is sent as a series of encrypted Noise messages: the
+//! first one holds the packet length, the following ones its data.
+//! On the wire, every Noise message is prepended with its length as
+//! a two byte big-endian integer.  Packets longer than
+//! `MAX_PACKET_LEN` are rejected before any of their data is read.
+//!
+//! After the handshake a stream is split into a reading and a writing
+//! half, which keep their own nonces.  This way a connection can be
+//! read from while packets are being sent on it.
+
+use crate::{Error, Keys, PublicKey};
+use async_std::{
+    future::timeout,
+    io::{self, prelude::*},
+    net::{Shutdown, SocketAddr, TcpStream},
+};
+use byteorder::{BigEndian, ByteOrder};
+use snow::{Builder, StatelessTransportState};
+use std::{iter, sync::Arc, time::Duration};
+
+/// The Noise protocol used for all connections
+pub(crate) const PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// The longest message allowed by the Noise specification
+const MAX_MSG_LEN: usize = 65535;
+
+/// The longest packet that can be sent or received
+pub(crate) const MAX_PACKET_LEN: usize = 1024 * 1024;
+
+/// The length of the authentication tag in each encrypted message
+const TAG_LEN: usize = 16;
+
+/// How long a peer may take to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A TCP stream after a successful handshake
+#[derive(Debug)]
+pub(crate) struct NoiseStream {
+    reader: NoiseReader,
+    writer: NoiseWriter,
+    remote: PublicKey,
+}
+
+/// The receiving half of a `NoiseStream`
+#[derive(Debug)]
+pub(crate) struct NoiseReader {
+    inner: TcpStream,
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+/// The sending half of a `NoiseStream`
+#[derive(Debug)]
+pub(crate) struct NoiseWriter {
+    inner: TcpStream,
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl NoiseStream {
+    /// Perform the handshake on an outgoing connection
+    pub(crate) async fn connect(stream: TcpStream, keys: &Keys) -> io::Result<Self> {
+        Self::handshake(stream, keys, true).await
+    }
+
+    /// Perform the handshake on an incoming connection
+    pub(crate) async fn accept(stream: TcpStream, keys: &Keys) -> io::Result<Self> {
+        Self::handshake(stream, keys, false).await
+    }
+
+    async fn handshake(mut inner: TcpStream, keys: &Keys, initiator: bool) -> io::Result<Self> {
+        let secret = keys.keypair.to_bytes();
+        let builder = Builder::new(PARAMS.parse().unwrap()).local_private_key(&secret);
+        let mut hs = match initiator {
+            true => builder.build_initiator(),
+            false => builder.build_responder(),
+        }
+        .map_err(noise_err)?;
+
+        let mut buf = vec![0; MAX_MSG_LEN];
+        timeout(HANDSHAKE_TIMEOUT, async {
+            while !hs.is_handshake_finished() {
+                if hs.is_my_turn() {
+                    let len = hs.write_message(&[], &mut buf).map_err(noise_err)?;
+                    inner.write_all(&frame(&buf[..len])).await?;
+                } else {
+                    let msg = read_msg(&mut inner).await?;
+                    hs.read_message(&msg, &mut buf).map_err(noise_err)?;
+                }
+            }
+            io::Result::Ok(())
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+
+        let remote = hs
+            .get_remote_static()
+            .and_then(PublicKey::from_slice)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing peer key"))?;
+        if remote == keys.keypair.public() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                Error::SelfConnection,
+            ));
+        }
+        if !keys.trusts(&remote) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("untrusted peer key {}", remote),
+            ));
+        }
+
+        let state = Arc::new(hs.into_stateless_transport_mode().map_err(noise_err)?);
+        Ok(Self {
+            reader: NoiseReader {
+                inner: inner.clone(),
+                state: Arc::clone(&state),
+                nonce: 0,
+            },
+            writer: NoiseWriter {
+                inner,
+                state,
+                nonce: 0,
+            },
+            remote,
+        })
+    }
+
+    /// Get the address of the remote peer
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.writer.peer_addr()
+    }
+
+    /// Get the static key of the remote peer
+    pub(crate) fn remote_key(&self) -> PublicKey {
+        self.remote
+    }
+
+    /// Split the stream into its receiving and sending halves
+    pub(crate) fn split(self) -> (NoiseReader, NoiseWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+/// Check whether a handshake failed because the node connected to
+/// itself
+pub(crate) fn is_self_connection(e: &io::Error) -> bool {
+    matches!(
+        e.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+        Some(Error::SelfConnection)
+    )
+}
+
+impl NoiseWriter {
+    /// Get the address of the remote peer
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// Encrypt and send a packet
+    pub(crate) async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() > MAX_PACKET_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet too large",
+            ));
+        }
+
+        let mut len = [0; 8];
+        BigEndian::write_u64(&mut len, data.len() as u64);
+
+        let mut out = vec![];
+        let mut buf = vec![0; MAX_MSG_LEN];
+        for chunk in iter::once(&len[..]).chain(data.chunks(MAX_MSG_LEN - TAG_LEN)) {
+            let len = self
+                .state
+                .write_message(self.nonce, chunk, &mut buf)
+                .map_err(noise_err)?;
+            self.nonce += 1;
+            out.extend(frame(&buf[..len]));
+        }
+
+        self.inner.write_all(&out).await
+    }
+
+    /// Close the connection in both directions
+    pub(crate) fn shutdown(&self) {
+        let _ = self.inner.shutdown(Shutdown::Both);
+    }
+
+    /// Check whether this half belongs to the same connection as a reader
+    pub(crate) fn pairs(&self, reader: &NoiseReader) -> bool {
+        Arc::ptr_eq(&self.state, &reader.state)
+    }
+}
+
+impl NoiseReader {
+    /// Receive and decrypt the next packet
+    pub(crate) async fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.recv_msg().await?;
+        if len.len() != 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid packet length",
+            ));
+        }
+
+        let len = BigEndian::read_u64(&len);
+        if len > MAX_PACKET_LEN as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("packet of {} bytes is too large", len),
+            ));
+        }
+
+        let len = len as usize;
+        let mut data = vec![];
+        while data.len() < len {
+            data.extend(self.recv_msg().await?);
+        }
+        Ok(data)
+    }
+
+    async fn recv_msg(&mut self) -> io::Result<Vec<u8>> {
+        let msg = read_msg(&mut self.inner).await?;
+        let mut buf = vec![0; msg.len()];
+        let len = self
+            .state
+            .read_message(self.nonce, &msg, &mut buf)
+            .map_err(noise_err)?;
+        self.nonce += 1;
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
+
+fn noise_err(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Prepend a message with its length
+fn frame(msg: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; 2];
+    BigEndian::write_u16(&mut buf, msg.len() as u16);
+    buf.extend_from_slice(msg);
+    buf
+}
+
+async fn read_msg(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+
+    let mut msg = vec![0; BigEndian::read_u16(&len) as usize];
+    stream.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+#[cfg(test)]
+async fn connected(
+    client: &Keys,
+    server: &Keys,
+) -> (io::Result<NoiseStream>, io::Result<NoiseStream>) {
+    use async_std::{net::TcpListener, prelude::FutureExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connect = async {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        NoiseStream::connect(stream, client).await
+    };
+    let accept = async {
+        let (stream, _) = listener.accept().await.unwrap();
+        NoiseStream::accept(stream, server).await
+    };
+    connect.join(accept).await
+}
+
+#[async_std::test]
+async fn send_large_packet() {
+    use crate::Keypair;
+    use async_std::prelude::FutureExt;
+
+    let client = Keys::new(Keypair::generate(), vec![]);
+    let server = Keys::new(Keypair::generate(), vec![]);
+    let (a, b) = connected(&client, &server).await;
+    let (a, b) = (a.unwrap(), b.unwrap());
+
+    assert_eq!(a.remote_key(), server.keypair.public());
+    assert_eq!(b.remote_key(), client.keypair.public());
+
+    let (mut a_rx, mut a_tx) = a.split();
+    let (mut b_rx, mut b_tx) = b.split();
+    assert!(a_tx.pairs(&a_rx) && !a_tx.pairs(&b_rx));
+
+    let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+    let (sent, received) = a_tx.send(&data).join(b_rx.recv()).await;
+    sent.unwrap();
+    assert_eq!(received.unwrap(), data);
+
+    b_tx.send(b"ACK").await.unwrap();
+    assert_eq!(a_rx.recv().await.unwrap(), b"ACK");
+}
+
+#[async_std::test]
+async fn reject_oversized_packet() {
+    use crate::Keypair;
+
+    let client = Keys::new(Keypair::generate(), vec![]);
+    let server = Keys::new(Keypair::generate(), vec![]);
+    let (a, b) = connected(&client, &server).await;
+    let (_, mut a_tx) = a.unwrap().split();
+    let (mut b_rx, _) = b.unwrap().split();
+
+    let data = vec![0; MAX_PACKET_LEN + 1];
+    let err = a_tx.send(&data).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    // Announce a huge packet without sending its data
+    let mut len = [0; 8];
+    BigEndian::write_u64(&mut len, u64::MAX);
+    let mut buf = vec![0; MAX_MSG_LEN];
+    let n = a_tx
+        .state
+        .write_message(a_tx.nonce, &len, &mut buf)
+        .unwrap();
+    a_tx.inner.write_all(&frame(&buf[..n])).await.unwrap();
+
+    let err = b_rx.recv().await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[async_std::test]
+async fn reject_untrusted_key() {
+    use crate::Keypair;
+
+    let client = Keys::new(Keypair::generate(), vec![]);
+    let server = Keys::new(Keypair::generate(), vec![Keypair::generate().public()]);
+    let (_, b) = connected(&client, &server).await;
+    assert_eq!(b.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[async_std::test]
+async fn reject_own_key() {
+    use crate::Keypair;
+
+    let keys = Keys::new(Keypair::generate(), vec![]);
+    let (a, b) = connected(&keys, &keys).await;
+    assert!(is_self_connection(&a.unwrap_err()));
+    assert!(is_self_connection(&b.unwrap_err()));
+}
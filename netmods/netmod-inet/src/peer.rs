@@ -10,27 +10,67 @@
 //!                                |            |
 //!                                |            |
 //!                                v            v
-//!                       REVERSE stream       Send a HELLO to init
-//!                      (already) exists       reverse connection
-//!                               |                      |
-//!                               |                      |
-//!                               |                      v
-//!                               v               Wait for REVERSE connection
-//!                      Valid DUPLEX                   |
+//!                       REVERSE stream       Send a HELLO and
+//!                      (already) exists      wait for the ACK
+//!                               |              |          |
+//!                               |           LIMITED    BIDIRECT
+//!                               |              |          |
+//!                               |              |          v
+//!                               v              |   Wait for REVERSE
+//!                      Valid DUPLEX  <---------+     connection
 //!                       connection  <-----------------+
 //! ```
 //!
-//! If at any point sending a message fails, this re-connection needs
-//! to be repeated and the packet held until then.
+//! `Limited` links receive frames on the same connection that they
+//! send them on, which means that only one of the two nodes needs to
+//! be reachable.
 //!
-//! All operations on a peer are async, and will be queued via a
-//! channel, which means they will return immediately, even if the
-//! connection is currently down.
-
-use crate::{AtomPtr, IoPair, LinkType, LockedStream, Packet, PacketBuilder};
-use async_std::{future::timeout, io::prelude::WriteExt, net::TcpStream, sync::Arc, task};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::{net::SocketAddr, time::Duration};
+//! Each peer that opens its own connections is watched by a
+//! supervisor.  It pings the peer regularly, and re-connects with an
+//! exponential backoff whenever the connection drops.  Packets are
+//! held until then.
+//!
+//! Packets for a peer are queued, which means sending returns
+//! immediately, even if the connection is currently down.  Unicast
+//! packets take precedence over flooded frames, and packets are sent
+//! no faster than the endpoint's per-peer rate limit allows (see
+//! `queue.rs`).
+
+use crate::{
+    noise, proto,
+    queue::{Class, RateLimit, SendQueue},
+    AtomPtr, IoPair, LinkType, Local, LockedStream, NoiseReader, NoiseStream, NoiseWriter, Packet,
+    PacketBuilder,
+};
+use async_std::{future::timeout, io, net::TcpStream, sync::Arc, task};
+use rand::Rng;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Mutex,
+};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How long a peer may take to accept a HELLO
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often an idle connection is pinged
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a connection may stay silent before it is considered dead
+pub(crate) const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The delay after the first failed connection attempt
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+
+/// The longest delay between two connection attempts
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// How many state changes are kept for each peer
+const HISTORY_LEN: usize = 32;
 
 /// Utility module to generate monotonic peer IDs
 mod id {
@@ -50,8 +90,8 @@ pub(crate) type SourceAddr = SocketAddr;
 pub(crate) type DstAddr = SocketAddr;
 
 /// Encode the different states a `Peer` can be in
-#[derive(Debug, PartialEq)]
-pub(crate) enum PeerState {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerState {
     /// Only a receiving channel exists
     ///
     /// This is either the case for unknown dynamic peers, or a
@@ -64,12 +104,44 @@ pub(crate) enum PeerState {
     TxOnly,
     /// A valid two-way connection
     Duplex,
-    /// Something has gone really wrong
-    #[allow(unused)]
-    Invalid,
+    /// No connection exists, or the previous one was dropped
+    Disconnected,
+}
+
+/// A snapshot of a peer's connection, as listed by `Endpoint::peers`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerInfo {
+    /// The address this peer is reached at
+    pub addr: SocketAddr,
+    /// The current state of the connection
+    pub state: PeerState,
+    /// The type of link to this peer
+    pub link: LinkType,
+    /// Bytes sent to this peer, including protocol overhead
+    pub bytes_sent: u64,
+    /// Bytes received from this peer, including protocol overhead
+    pub bytes_received: u64,
+    /// When the last packet was received from this peer
+    pub last_seen: Option<SystemTime>,
+    /// Frames for this peer that were dropped because its queue was full
+    pub dropped_unicast: u64,
+    /// Flooded frames that were dropped because this peer's queue was full
+    pub dropped_flood: u64,
 }
 
-#[derive(Clone, Debug, Default)]
+/// Compute the delay before a connection attempt
+///
+/// The delay doubles with each failed attempt, and is randomised so
+/// that peers which lost their connections at the same time don't
+/// reconnect in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let base = BACKOFF_MIN
+        .checked_mul(1 << attempt.min(16))
+        .map_or(BACKOFF_MAX, |d| d.min(BACKOFF_MAX));
+    base / 2 + base.mul_f64(rand::thread_rng().gen_range(0.0, 0.5))
+}
+
+#[derive(Clone, Debug)]
 pub(crate) struct Peer {
     /// Unique numeric Id for each peer
     pub(crate) id: usize,
@@ -79,13 +151,32 @@ pub(crate) struct Peer {
     dst: Option<DstAddr>,
     /// Sending stream for this peer (if it existst)
     sender: AtomPtr<LockedStream>,
+    /// The connection this peer opened to us (if any)
+    incoming: AtomPtr<LockedStream>,
     /// The type of link this maintains
-    _type: LinkType,
+    _type: AtomPtr<LinkType>,
+    /// Whether this peer opens connections itself
+    ///
+    /// Peers that connected via a `Limited` link can't be reached
+    /// directly, and instead have to re-connect to the local node.
+    dial: bool,
     /// Secret run condition
     #[doc(hidden)]
     _run: Arc<AtomicBool>,
     /// Store packets until they can be delivered
-    io: Arc<IoPair<Packet>>,
+    queue: Arc<SendQueue>,
+    /// Wake up the supervisor when the connection drops
+    wake: Arc<IoPair<()>>,
+    /// The most recent state changes of this peer
+    history: Arc<Mutex<VecDeque<(SystemTime, PeerState)>>>,
+    /// Bytes sent to this peer
+    bytes_sent: Arc<AtomicU64>,
+    /// Bytes received from this peer
+    bytes_received: Arc<AtomicU64>,
+    /// When the last packet was received from this peer
+    last_seen: AtomPtr<Option<SystemTime>>,
+    /// The local endpoint state
+    local: Arc<Local>,
 }
 
 impl Peer {
@@ -97,13 +188,8 @@ impl Peer {
     /// which will be resolved soon (because the local peer
     /// initialisation loop hasn't spawned the sending channel yet),
     /// or an unknown peer when running in `dynamic` mode
-    pub(crate) fn from_src(src: SourceAddr) -> Arc<Self> {
-        Arc::new(Self {
-            id: id::next(),
-            src: AtomPtr::new(Some(src)),
-            _run: Arc::new(true.into()),
-            ..Default::default()
-        })
+    pub(crate) fn from_src(src: SourceAddr, local: Arc<Local>) -> Arc<Self> {
+        Arc::new(Self::new(Some(src), None, LinkType::Bidirect, false, local))
     }
 
     /// Open a connection to this peer
@@ -111,16 +197,10 @@ impl Peer {
     /// While this function returns immediately, it spawns an async
     /// worker that will try to establish a connection to the peer,
     /// exiting until `stop()` is called on this peer
-    #[tracing::instrument(level = "trace")]
-    pub(crate) fn open(dst: DstAddr, port: u16, _type: LinkType) -> Arc<Self> {
+    #[tracing::instrument(level = "trace", skip(local))]
+    pub(crate) fn open(dst: DstAddr, _type: LinkType, local: Arc<Local>) -> Arc<Self> {
         trace!("Start peer handler for {:?}", dst);
-        let p = Arc::new(Self {
-            id: id::next(),
-            dst: Some(dst),
-            _run: Arc::new(true.into()),
-            _type,
-            ..Default::default()
-        });
+        let p = Arc::new(Self::new(None, Some(dst), _type, true, local));
 
         // Start a timeout that will check whether this connection was
         // fully opened in 10 seconds
@@ -128,46 +208,194 @@ impl Peer {
             let p = Arc::clone(&p);
             task::spawn(async move {
                 task::sleep(Duration::from_secs(10)).await;
-                if p.state() != PeerState::Duplex {
+                if p.state() == PeerState::Duplex || !p.alive() {
+                    return;
+                }
+
+                if p.local.pessimistic.load(Ordering::Relaxed)
+                    && p.link_type() == LinkType::Bidirect
+                {
+                    warn!("[10 second timeout] No reverse connection from '{:?}'; falling back to a LIMITED link", p.dst);
+                    p._type.swap(LinkType::Limited);
+
+                    // Drop the current connection, so that the
+                    // supervisor re-introduces this node as limited
+                    let r = p.sender.get_ref();
+                    p.reset(&mut *r.write().await);
+                    p.record();
+                } else {
                     warn!("[10 second timeout] Peering with '{:?}' has not resulted in a DUPLEX link.  Is a valid connection present?", p.dst);
                 }
             });
         }
 
-        // Start sender loop and send a hello
-        Arc::clone(&p).run_io_sender(port, _type);
-        task::block_on(async { Arc::clone(&p).send(Packet::Hello { port, _type }).await });
+        // Start the connection supervisor and sender loop
+        task::spawn(Arc::clone(&p).supervise());
+        Arc::clone(&p).run_io_sender();
+        p
+    }
+
+    /// Create a peer that is only reachable via its own connection
+    ///
+    /// This is the case for peers that introduced themselves with a
+    /// `Limited` link.  All packets are sent via the incoming
+    /// connection, and the local node never tries to connect to it.
+    pub(crate) fn reverse(
+        src: SourceAddr,
+        dst: DstAddr,
+        stream: LockedStream,
+        local: Arc<Local>,
+    ) -> Arc<Self> {
+        let p = Self::new(Some(src), Some(dst), LinkType::Limited, false, local);
+        p.sender.swap(stream);
+        p.record();
+
+        let p = Arc::new(p);
+        Arc::clone(&p).run_io_sender();
+        p
+    }
 
-        return p;
+    fn new(
+        src: Option<SourceAddr>,
+        dst: Option<DstAddr>,
+        _type: LinkType,
+        dial: bool,
+        local: Arc<Local>,
+    ) -> Self {
+        let p = Self {
+            id: id::next(),
+            src: AtomPtr::new(src),
+            dst,
+            sender: Default::default(),
+            incoming: Default::default(),
+            _type: AtomPtr::new(_type),
+            dial,
+            _run: Arc::new(true.into()),
+            queue: Default::default(),
+            wake: Default::default(),
+            history: Default::default(),
+            bytes_sent: Default::default(),
+            bytes_received: Default::default(),
+            last_seen: Default::default(),
+            local,
+        };
+        p.record();
+        p
     }
 
     /// Set this peer's source address
     pub(crate) fn set_src<O: Into<Option<SourceAddr>>>(&self, src: O) {
         self.src.swap(src.into());
+        self.record();
     }
 
     pub(crate) async fn set_stream(&self, s: LockedStream) {
         self.sender.swap(s);
+        self.record();
+    }
+
+    /// Remember the connection this peer opened to us
+    ///
+    /// It is closed when the peer is stopped.
+    pub(crate) fn set_incoming(&self, s: LockedStream) {
+        self.incoming.swap(s);
+    }
+
+    /// Check whether a sending stream is currently available
+    pub(crate) async fn connected(&self) -> bool {
+        self.sender.get_ref().read().await.is_some()
     }
 
     /// Stop all tasks associated with this peer
-    pub(crate) fn stop(&self) {
+    ///
+    /// The connection to the peer is closed, and packets which
+    /// haven't been sent yet are dropped.
+    pub(crate) async fn stop(&self) {
         self._run.fetch_and(false, Ordering::Relaxed);
+        self.queue.close();
+        let _ = self.wake.tx.try_send(());
+
+        if let Some(s) = self.sender.get_ref().write().await.take() {
+            s.shutdown();
+        }
+        if let Some(s) = self.incoming.get_ref().write().await.take() {
+            s.shutdown();
+        }
+        self.record();
+    }
+
+    /// Count a packet that was received from this peer
+    pub(crate) fn received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        self.last_seen.swap(Some(SystemTime::now()));
+    }
+
+    /// Get a snapshot of this peer's connection
+    ///
+    /// Returns `None` if the peer has neither a DST nor a SRC address,
+    /// which means that it is about to be removed.
+    pub(crate) fn info(&self) -> Option<PeerInfo> {
+        let (dropped_unicast, dropped_flood) = self.queue.dropped();
+        Some(PeerInfo {
+            addr: self.dst.or_else(|| self.get_src())?,
+            state: self.state(),
+            link: self.link_type(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            last_seen: **self.last_seen.get_ref(),
+            dropped_unicast,
+            dropped_flood,
+        })
     }
 
     /// Get the current state for this peer
     pub(crate) fn state(&self) -> PeerState {
-        match (self.get_src(), self.dst) {
-            (Some(_), Some(_)) => PeerState::Duplex,
-            (Some(_), None) => PeerState::RxOnly,
-            (None, Some(_)) => PeerState::TxOnly,
-            (None, None) => PeerState::Invalid,
+        // The stream is only locked while it is used or replaced, in
+        // which case it is assumed to exist
+        let tx = match self.sender.get_ref().try_read() {
+            Some(s) => s.is_some(),
+            None => true,
+        };
+
+        match (self.get_src(), tx) {
+            (Some(_), true) => PeerState::Duplex,
+            (Some(_), false) => PeerState::RxOnly,
+            (None, true) => PeerState::TxOnly,
+            (None, false) => PeerState::Disconnected,
         }
     }
 
+    /// Get the most recent state changes, oldest first
+    pub(crate) fn history(&self) -> Vec<(SystemTime, PeerState)> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Add the current state to the history if it has changed
+    fn record(&self) {
+        let state = self.state();
+        let mut history = self.history.lock().unwrap();
+        if history.back().map(|(_, s)| *s) == Some(state) {
+            return;
+        }
+
+        debug!("Peer {} ({:?}) is now {:?}", self.id, self.dst, state);
+        if state == PeerState::Duplex {
+            self.local.pex.wake();
+        }
+        if history.len() == HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back((SystemTime::now(), state));
+    }
+
     /// Get the type for this link
     pub(crate) fn link_type(&self) -> LinkType {
-        self._type
+        **self._type.get_ref()
+    }
+
+    /// Check whether the local node opens connections to this peer
+    pub(crate) fn dials(&self) -> bool {
+        self.dial
     }
 
     /// Internal utility to verify that this peer is still alive
@@ -175,110 +403,59 @@ impl Peer {
         self._run.load(Ordering::Relaxed)
     }
 
+    /// Close the current connection and wake up the supervisor
+    ///
+    /// The caller must hold the lock on the sending stream, and
+    /// should call `record()` after releasing it.
+    fn reset(&self, stream: &mut Option<NoiseWriter>) {
+        if let Some(s) = stream.take() {
+            s.shutdown();
+        }
+
+        if self.dial && self.link_type() == LinkType::Limited {
+            self.src.swap(None);
+        }
+        let _ = self.wake.tx.try_send(());
+    }
+
     /// Call for each packet in the output stream
     async fn send_packet(self: &Arc<Self>, p: &Packet) -> Option<()> {
         let r = self.sender.get_ref();
         let mut s = r.write().await;
-        match *s {
-            Some(ref mut stream) => {
-                let addr = match stream.peer_addr() {
-                    Ok(addr) => addr.to_string(),
-                    Err(_) => {
-                        std::mem::swap(&mut *s, &mut None);
-                        return None;
-                    }
-                };
-
-                // And woosh!
-                let buf = p.serialize();
-                if let Err(e) = stream.write_all(&buf).await {
-                    error!("Failed to send message: {}!", e.to_string());
-
-                    // We mark ourselves as missing uplink
-                    std::mem::swap(&mut *s, &mut None);
-
-                    return None;
-                }
-
-                match p {
-                    Packet::Hello { .. } => {
-                        trace!("Sending HELLO to {}", addr);
-
-                        if self._type == LinkType::Bidirect {
-                            let _self = Arc::clone(self);
-                            task::spawn(_self.wait_for_ack());
-                        }
-                    }
-                    _ => {}
-                }
-
-                Some(())
-            }
-            None => unreachable!(),
+        let stream = s.as_mut()?;
+
+        // And woosh!
+        let buf = p.serialize();
+        if let Err(e) = stream.send(&buf).await {
+            error!("Failed to send message: {}!", e.to_string());
+
+            // We mark ourselves as missing uplink
+            self.reset(&mut s);
+            drop(s);
+            self.record();
+            return None;
         }
-    }
-
-    /// Run a listener to wait for an ACK to be returned to this connection
-    async fn wait_for_ack(self: Arc<Self>) {
-        let t = timeout(Duration::from_secs(10), async {
-            loop {
-                let r = self.sender.get_ref();
-                let mut s = r.write().await;
-                if s.is_none() {
-                    break;
-                }
-
-                if timeout(Duration::from_millis(1), async {
-                    let mut pb = PacketBuilder::new((*s).as_mut().unwrap());
-                    match pb.parse().await {
-                        Ok(_) => match pb.build() {
-                            Some(Packet::Ack) => trace!("Received an ACK."),
-                            _ => error!("Invalid data (only ACKs)!"),
-                        },
-                        _ => {
-                            std::mem::swap(&mut *s, &mut None);
-                            error!("Failed to read ACK from sender stream");
-                        }
-                    }
-                })
-                .await
-                .is_ok()
-                {
-                    break;
-                }
-
-                drop(s);
-                task::sleep(Duration::from_millis(50)).await;
-            }
-        });
 
-        // If the top-level timeout is ever hit...
-        match t.await {
-            Ok(_) => {}
-            Err(_) => {
-                // Remove the stream because it's probably dead
-                let _ref = self.sender.get_ref();
-                let mut s = _ref.write().await;
-                std::mem::swap(&mut *s, &mut None);
-            }
-        }
+        self.bytes_sent
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Some(())
     }
 
-    /// This function will try sending a packet, initialising the
-    /// output stream if it doesn't yet exist
-    async fn send_or_introduce(self: &Arc<Self>, p: Packet, port: u16, _type: LinkType) {
-        loop {
-            if self.sender.get_ref().read().await.is_some() {
+    /// This function will try sending a packet, waiting for a
+    /// connection if it doesn't exist yet
+    ///
+    /// The packet is kept until it was sent, or the peer is stopped.
+    async fn send_or_wait(self: &Arc<Self>, p: Packet) {
+        while self.alive() {
+            if self.connected().await {
                 // Send the packet and re-run the loop if we failed to send
                 match self.send_packet(&p).await {
                     Some(_) => break,
                     None => continue, // send_packet sets sender = None if failed
                 }
             } else {
-                if _type == LinkType::Bidirect {
-                    trace!("Sender is None, opening a connection first...");
-                    Arc::clone(&self).introduce_blocking(port).await;
-                }
+                // Wait for the supervisor, or the peer to connect to us
+                task::sleep(Duration::from_millis(100)).await;
             }
         }
     }
@@ -286,17 +463,25 @@ impl Peer {
     /// Start an async worker to send packets to this peer
     ///
     /// The worker can be stopped after spawning by calling `stop()`.
-    /// If at any time sending was'n successful, this loop will
-    /// automatically re-init the connection.
+    /// If at any time sending was'n successful, the packet is held
+    /// until the connection was re-established.  Packets are paced
+    /// to the endpoint's per-peer rate limit.
     ///
     /// There's currently no way to get diagnostics from failed sends
     /// back to ratman.  **FIXME**: implement this!
-    pub(crate) fn run_io_sender(self: Arc<Self>, port: u16, _type: LinkType) {
+    pub(crate) fn run_io_sender(self: Arc<Self>) {
         debug!("Running IO sender");
         task::spawn(async move {
-            while let Ok(p) = self.io.rx.recv().await {
+            let mut limit = RateLimit::default();
+            while let Some(p) = self.queue.pop().await {
                 trace!("Queued packet {:?}", p);
-                self.send_or_introduce(p, port, _type).await;
+
+                let rate = self.local.rate_limit.load(Ordering::Relaxed);
+                let delay = limit.take(rate, p.size(), Instant::now());
+                if delay > Duration::from_secs(0) {
+                    task::sleep(delay).await;
+                }
+                self.send_or_wait(p).await;
 
                 if !self.alive() {
                     break;
@@ -307,69 +492,170 @@ impl Peer {
         });
     }
 
-    /// Loop on a connection until it could be established!
-    async fn introduce_blocking(self: Arc<Self>, _port: u16) {
-        let id = self.id.clone();
-        let dst = self.dst.clone().unwrap();
-
-        let run = Arc::clone(&self._run);
-        let sender = Arc::clone(&self.sender.get_ref());
-        let mut ctr = 0;
-
-        while run.load(Ordering::Relaxed) {
-            let pre = match ctr {
-                0 => "".into(),
-                n => format!("[retry #{}]", n),
-            };
-
-            // Exit if we are already connected
-            if sender.read().await.is_some() {
-                debug!(
-                    "Peer `{}` (ID: {}) is already connected!",
-                    dst.to_string(),
-                    id
-                );
-                break;
-            }
+    /// Keep a connection to this peer open until it is stopped
+    ///
+    /// Connection attempts are retried with an exponential backoff,
+    /// and open connections are pinged regularly so that dead links
+    /// are noticed by the reader.
+    async fn supervise(self: Arc<Self>) {
+        let dst = self.dst.unwrap();
+        let mut attempt = 0;
+
+        while self.alive() {
+            if !self.connected().await {
+                if let Err(e) = self.connect().await {
+                    // Retrying would only connect to this node again
+                    if noise::is_self_connection(&e) {
+                        warn!("Peer `{}` is this node itself; not connecting to it", dst);
+                        self.local.pex.add_own(dst);
+                        self.stop().await;
+                        break;
+                    }
 
-            debug!(
-                "{}: Attempting to connect to peer `{}`",
-                pre,
-                dst.to_string()
-            );
-            let s = match TcpStream::connect(dst).await {
-                Ok(s) => s,
-                Err(_) => {
+                    let delay = backoff(attempt);
                     error!(
-                        "Failed to connect to peer `{}`.  Starting timeout...",
-                        dst.to_string()
+                        "Failed to connect to peer `{}`: {}.  Retrying in {:?}...",
+                        dst, e, delay
                     );
 
-                    // FIXME: Make this configurable
-                    task::sleep(Duration::from_secs(5)).await;
-                    ctr += 1;
+                    attempt += 1;
+                    task::sleep(delay).await;
                     continue;
                 }
-            };
 
-            s.set_nodelay(true).unwrap();
+                attempt = 0;
+            }
+
+            // Wait for the next ping, unless the connection drops
+            let _ = timeout(KEEPALIVE_INTERVAL, self.wake.rx.recv()).await;
+            if self.alive() && self.connected().await {
+                trace!("Sending PING to {}", dst);
+                self.send_packet(&Packet::Ping).await;
+            }
+        }
+
+        debug!("Shutting down supervisor for peer {}", self.id);
+    }
+
+    /// Send a HELLO on a new connection and wait for it to be accepted
+    async fn hello(&self, reader: &mut NoiseReader, writer: &mut NoiseWriter) -> io::Result<()> {
+        let hello = Packet::Hello {
+            magic: proto::MAGIC,
+            version: proto::VERSION,
+            port: self.local.port,
+            _type: self.link_type(),
+        };
+        writer.send(&hello.serialize()).await?;
+        trace!("Sending HELLO to {:?}", self.dst);
+
+        let mut pb = PacketBuilder::new(reader);
+        self.received(io::timeout(ACK_TIMEOUT, pb.parse()).await?);
+        match pb.build()? {
+            Packet::Ack { version } if (proto::MIN_VERSION..=proto::VERSION).contains(&version) => {
+                trace!("Using protocol version {} with {:?}", version, self.dst);
+                Ok(())
+            }
+            Packet::Ack { version } => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported protocol version {}", version),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected an ACK",
+            )),
+        }
+    }
+
+    /// Open a connection and introduce this node to the peer
+    async fn connect(self: &Arc<Self>) -> io::Result<()> {
+        let dst = self.dst.unwrap();
+        debug!("Attempting to connect to peer `{}`", dst);
+
+        let s = TcpStream::connect(dst).await?;
+        s.set_nodelay(true)?;
+        let (mut reader, mut writer) = NoiseStream::connect(s, &self.local.keys).await?.split();
+        self.hello(&mut reader, &mut writer).await?;
+
+        info!("Successfully connected to peer `{}`", &dst);
+        *self.sender.get_ref().write().await = Some(writer);
+
+        // On limited links the peer sends its packets via this
+        // connection, instead of opening a reverse one
+        if self.link_type() == LinkType::Limited {
+            self.src.swap(Some(dst));
+        }
+        self.record();
+
+        task::spawn(Arc::clone(self).run_reader(reader));
+        Ok(())
+    }
+
+    /// Receive packets from an outgoing connection
+    ///
+    /// This carries frames on limited links, and the replies to pings
+    /// on all links.  If nothing was received for a while, the
+    /// connection is considered dead and reset.
+    async fn run_reader(self: Arc<Self>, mut reader: NoiseReader) {
+        while self.alive() {
+            let mut pb = PacketBuilder::new(&mut reader);
+            let packet = io::timeout(KEEPALIVE_TIMEOUT, pb.parse())
+                .await
+                .and_then(|len| {
+                    self.received(len);
+                    pb.build()
+                });
+
+            match packet {
+                Ok(Packet::Frame(f)) => self.local.incoming.tx.send((f, self.id)).await.unwrap(),
+                Ok(Packet::Pong) => trace!("Received PONG from {:?}", self.dst),
+                Ok(Packet::Peers(addrs)) => self.local.pex.learn(addrs),
+                Ok(_) => trace!("Received unexpected packet on an outgoing connection"),
+                Err(e) => {
+                    debug!("Connection to peer {:?} was closed: {}", self.dst, e);
+                    break;
+                }
+            }
+        }
 
-            info!("Successfully connected to peer `{}`", &dst);
-            let mut sender = sender.write().await;
-            *sender = Some(s);
-            break;
+        // Only reset the sender if no new connection was opened yet
+        let r = self.sender.get_ref();
+        let mut s = r.write().await;
+        if s.as_ref().map(|w| w.pairs(&reader)).unwrap_or(false) {
+            self.reset(&mut s);
+            drop(s);
+            self.record();
         }
     }
 
     /// Send some arbitrary packet to this peer
     ///
-    /// If the connection has become invalid in the meantime, this
-    /// function will automatically call introduce and wait for a
-    /// connection to occur before retrying.  In this case it will
-    /// spawn an async worker and return, even if the data was not
-    /// successfully delivered.
-    pub(crate) async fn send(&self, packet: Packet) {
-        self.io.tx.send(packet).await.unwrap();
+    /// If the connection has become invalid in the meantime, the
+    /// packet is held until the connection was re-established.  In
+    /// this case this function returns, even if the data was not
+    /// successfully delivered.  If too many packets are queued for
+    /// this peer already, the packet is dropped.
+    pub(crate) fn send(&self, packet: Packet) {
+        self.queue_packet(packet, Class::Unicast);
+    }
+
+    /// Send a flooded frame to this peer
+    ///
+    /// Flooded frames are only sent when no unicast packets are
+    /// waiting, or after a few of them were sent.
+    pub(crate) fn flood(&self, packet: Packet) {
+        self.queue_packet(packet, Class::Flood);
+    }
+
+    fn queue_packet(&self, packet: Packet, class: Class) {
+        if !self.queue.push(packet, class) {
+            match self.alive() {
+                true => debug!(
+                    "Queue of peer {} is full; dropping {:?} packet",
+                    self.id, class
+                ),
+                false => debug!("Peer {} was stopped; dropping packet", self.id),
+            }
+        }
     }
 
     pub(crate) fn get_src(&self) -> Option<SourceAddr> {
@@ -377,6 +663,14 @@ impl Peer {
     }
 
     pub(crate) fn get_dst(&self) -> Option<DstAddr> {
-        self.dst.clone()
+        self.dst
     }
 }
+
+#[test]
+fn backoff_grows() {
+    let within = |d: Duration, max: Duration| d >= max / 2 && d <= max;
+    assert!(within(backoff(0), BACKOFF_MIN));
+    assert!(within(backoff(3), BACKOFF_MIN * 8));
+    assert!(within(backoff(100), BACKOFF_MAX));
+}
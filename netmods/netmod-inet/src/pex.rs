@@ -0,0 +1,189 @@
+//! Peer exchange (PEX) between connected peers
+//!
+//! Every node regularly shares the addresses of peers that it can
+//! connect to with all of its connected peers, and again whenever a
+//! new connection is established.  Nodes which enabled peer exchange
+//! connect to the addresses they learn this way, until a maximum
+//! number of peers is known.  This means that a node only needs a
+//! single address to join the whole overlay.
+//!
+//! Peers that connected via a `Limited` link are never shared, since
+//! they usually can't be reached from the outside.  Addresses which
+//! turned out to belong to this node itself are never connected to.
+
+use crate::{IoPair, LinkType, Local, Packet, PeerState, Routes};
+use async_std::{future::timeout, sync::Arc, task};
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// How often known addresses are shared with all peers
+const SHARE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The number of peers to connect to in dynamic mode
+pub(crate) const DEFAULT_MAX_PEERS: usize = 8;
+
+/// Channels and settings for the peer exchange
+#[derive(Debug, Default)]
+pub(crate) struct Pex {
+    /// Share addresses before the next interval
+    wake: IoPair<()>,
+    /// Addresses that were shared by other peers
+    learned: IoPair<Vec<SocketAddr>>,
+    /// Connect to learned addresses until this many peers are known
+    max_peers: AtomicUsize,
+    /// Addresses that turned out to belong to this node
+    own: Mutex<BTreeSet<SocketAddr>>,
+}
+
+impl Pex {
+    /// Set the number of peers to connect to; 0 disables connecting
+    pub(crate) fn set_max_peers(&self, max: usize) {
+        self.max_peers.store(max, Ordering::Relaxed);
+    }
+
+    /// Remember an address that belongs to this node
+    pub(crate) fn add_own(&self, addr: SocketAddr) {
+        self.own.lock().unwrap().insert(addr);
+    }
+
+    /// Check whether an address belongs to this node
+    pub(crate) fn is_own(&self, addr: &SocketAddr) -> bool {
+        self.own.lock().unwrap().contains(addr)
+    }
+
+    /// Share the known addresses as soon as possible
+    pub(crate) fn wake(&self) {
+        let _ = self.wake.tx.try_send(());
+    }
+
+    /// Handle a set of addresses that was shared by a peer
+    ///
+    /// If addresses arrive faster than they can be handled, they
+    /// are dropped; they will be shared again later.
+    pub(crate) fn learn(&self, addrs: Vec<SocketAddr>) {
+        if self.learned.tx.try_send(addrs).is_err() {
+            trace!("Dropping shared peer addresses");
+        }
+    }
+
+    /// Stop sharing and learning addresses
+    pub(crate) fn stop(&self) {
+        self.wake.tx.close();
+        self.learned.tx.close();
+    }
+}
+
+/// Spawn the tasks sharing and learning peer addresses
+pub(crate) fn run(routes: Arc<Routes>, local: Arc<Local>) {
+    {
+        let (routes, local) = (Arc::clone(&routes), Arc::clone(&local));
+        task::spawn(async move {
+            loop {
+                // Wait for the next interval, unless the endpoint was
+                // stopped or a new peer is connected
+                if let Ok(Err(_)) = timeout(SHARE_INTERVAL, local.pex.wake.rx.recv()).await {
+                    break;
+                }
+                share(&routes).await;
+            }
+            debug!("Shutting down peer exchange");
+        });
+    }
+
+    task::spawn(async move {
+        while let Ok(addrs) = local.pex.learned.rx.recv().await {
+            connect(&routes, &local, addrs).await;
+        }
+    });
+}
+
+/// Send the addresses of all reachable peers to all connected peers
+async fn share(routes: &Arc<Routes>) {
+    let peers = routes.all().await;
+    let addrs: Vec<_> = peers
+        .iter()
+        .filter(|p| p.dials() && p.state() == PeerState::Duplex)
+        .filter_map(|p| p.get_dst())
+        .collect();
+
+    for peer in peers.iter() {
+        if !peer.connected().await {
+            continue;
+        }
+
+        // Peers don't need to learn their own address
+        let shared: Vec<_> = addrs
+            .iter()
+            .filter(|a| Some(**a) != peer.get_dst())
+            .copied()
+            .collect();
+        if !shared.is_empty() {
+            trace!("Sharing {} addresses with peer {}", shared.len(), peer.id);
+            peer.send(Packet::Peers(shared));
+        }
+    }
+}
+
+/// Count the peers which are connected, or which this node is trying
+/// to connect to
+///
+/// Peers which only connected to this node don't count, since they
+/// might go away again before a duplex link is set up.
+async fn count_peers(routes: &Arc<Routes>) -> usize {
+    routes
+        .all()
+        .await
+        .iter()
+        .filter(|p| (p.dials() && p.alive()) || p.state() == PeerState::Duplex)
+        .count()
+}
+
+/// Connect to shared addresses until enough peers are known
+async fn connect(routes: &Arc<Routes>, local: &Local, addrs: Vec<SocketAddr>) {
+    let max = local.pex.max_peers.load(Ordering::Relaxed);
+    for addr in addrs {
+        if count_peers(routes).await >= max {
+            break;
+        }
+
+        // Skip known peers and this node itself
+        let local_addr = addr.port() == local.port && addr.ip().is_loopback();
+        if local_addr || local.pex.is_own(&addr) || routes.find_via_dst(&addr).await.is_some() {
+            continue;
+        }
+
+        debug!("Connecting to shared peer address {}", addr);
+        routes.add_via_dst(addr, LinkType::default()).await;
+    }
+}
+
+#[async_std::test]
+async fn skip_src_only_and_own_peers() {
+    use crate::keys::{Keypair, Keys};
+
+    let local = Arc::new(Local::new(0, Keys::new(Keypair::generate(), vec![])));
+    local.pex.set_max_peers(1);
+    let routes = Routes::new(Arc::clone(&local));
+
+    // A peer which only connected to this node leaves room for one
+    // this node connects to itself
+    routes
+        .add_via_src(&"127.0.0.1:41313".parse().unwrap())
+        .await;
+    let own = "127.0.0.1:41314".parse().unwrap();
+    let shared = "127.0.0.1:41315".parse().unwrap();
+    local.pex.add_own(own);
+    connect(&routes, &local, vec![own, shared]).await;
+
+    assert!(routes.find_via_dst(&own).await.is_none());
+    assert!(routes.find_via_dst(&shared).await.is_some());
+    assert_eq!(count_peers(&routes).await, 1);
+    routes.stop_all().await;
+}
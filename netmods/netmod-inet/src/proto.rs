@@ -1,70 +1,110 @@
 //! TCP internal protocol used to share connection state
+//!
+//! Packets are encoded with bincode, so adding or changing a variant
+//! changes the wire format.  Every connection starts with a `Hello`,
+//! which carries a protocol magic and the newest version the sender
+//! speaks.  The receiver picks the newest version both nodes speak
+//! and sends it back in the `Ack`.  `Hello` and `Ack` must therefore
+//! never change, so that nodes with different versions can still
+//! negotiate.  Connections with a wrong magic or no common version,
+//! and packets that can't be decoded, are dropped.
 
-use crate::LinkType;
-use async_std::{
-    io::{self, prelude::ReadExt},
-    net::TcpStream,
-};
-use bincode::{deserialize, serialize};
-use byteorder::{BigEndian, ByteOrder};
+use crate::{LinkType, NoiseReader};
+use async_std::io;
+use bincode::{deserialize, serialize, serialized_size};
 use netmod::Frame;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// Identifies connections of the inet overlay protocol
+pub(crate) const MAGIC: [u8; 4] = *b"IRDi";
+
+/// The newest protocol version spoken by this node
+pub(crate) const VERSION: u16 = 1;
+
+/// The oldest protocol version still spoken by this node
+pub(crate) const MIN_VERSION: u16 = 1;
+
+/// Pick the protocol version to use with a peer
+///
+/// Returns `None` if the peer doesn't speak this protocol, or only
+/// speaks versions which are too old.
+pub(crate) fn negotiate(magic: [u8; 4], version: u16) -> Option<u16> {
+    match magic == MAGIC && version >= MIN_VERSION {
+        true => Some(version.min(VERSION)),
+        false => None,
+    }
+}
 
 /// An internally used packet format
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) enum Packet {
-    /// A general keep alive message
+    /// The first packet on every new connection
     ///
     /// Because tcp assumes a client-server architecture, an incoming
     /// connection can't accept data without one device explicitly
     /// being the server, and one being the client.  To build a p2p
-    /// network we create reverse connections.  When establishing a
-    /// connection, the hello message contains the port which is
-    /// swapped into the source address to connect to.
-    Hello { port: u16, _type: LinkType },
-    /// Response to a Hello on the sending stream
-    Ack,
+    /// network we either create reverse connections, or use the
+    /// connection in both directions for `Limited` links.  For
+    /// reverse connections the hello message contains the port which
+    /// is swapped into the source address to connect to.
+    Hello {
+        magic: [u8; 4],
+        version: u16,
+        port: u16,
+        _type: LinkType,
+    },
+    /// Accepts a Hello with the protocol version to use
+    Ack { version: u16 },
+    /// Sent regularly on outgoing connections to keep them alive
+    Ping,
+    /// Response to a Ping on the same connection
+    Pong,
     /// An actual data packet
     Frame(Frame),
+    /// Addresses of other peers that accept connections
+    Peers(Vec<SocketAddr>),
 }
 
 impl Packet {
-    /// Serialises the packet into a length prepended data stream
+    /// Serialises the packet to send it via a `NoiseWriter`
     pub(crate) fn serialize(&self) -> Vec<u8> {
-        let mut vec = serialize(self).unwrap();
-        let mut buf = vec![0; 8];
-        BigEndian::write_u64(&mut buf, vec.len() as u64);
-        buf.append(&mut vec);
-        buf
+        serialize(self).unwrap()
+    }
+
+    /// Get the length of the serialised packet
+    pub(crate) fn size(&self) -> usize {
+        serialized_size(self).unwrap() as usize
     }
 }
 
 /// A utility to read packets from an incoming TCP stream
 pub(crate) struct PacketBuilder<'s> {
-    stream: &'s mut TcpStream,
+    stream: &'s mut NoiseReader,
     data: Option<Vec<u8>>,
 }
 
 impl<'s> PacketBuilder<'s> {
     /// Create a new frame builder from a stream
-    pub(crate) fn new(stream: &'s mut TcpStream) -> Self {
+    pub(crate) fn new(stream: &'s mut NoiseReader) -> Self {
         Self { stream, data: None }
     }
 
     /// Parse incoming data and initialise the builder
-    pub(crate) async fn parse(&mut self) -> io::Result<()> {
-        let mut len_buf = [0; 8];
-        self.stream.read_exact(&mut len_buf).await?;
-        let len = BigEndian::read_u64(&len_buf);
-
-        let mut data_buf = vec![0; len as usize];
-        self.stream.read_exact(&mut data_buf).await?;
-        self.data = Some(data_buf);
-        Ok(())
+    ///
+    /// Returns the length of the received packet.
+    pub(crate) async fn parse(&mut self) -> io::Result<usize> {
+        let data = self.stream.recv().await?;
+        let len = data.len();
+        self.data = Some(data);
+        Ok(len)
     }
 
-    /// Consume the builder and maybe return a frame
-    pub(crate) fn build(self) -> Option<Packet> {
-        self.data.and_then(|vec| deserialize(&vec).ok())
+    /// Consume the builder and decode the packet
+    pub(crate) fn build(self) -> io::Result<Packet> {
+        let data = self
+            .data
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no packet received"))?;
+        deserialize(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 }
@@ -0,0 +1,218 @@
+//! Fair send queue and rate limiting for a single peer
+//!
+//! Packets for a peer are queued in two classes: unicast packets
+//! (frames addressed to this peer, and protocol packets) and flooded
+//! frames.  Unicast packets are sent first, but after a few of them
+//! one flooded frame is let through, so that a busy peer can't starve
+//! floods entirely.  Both queues are bounded.  A packet that doesn't
+//! fit is dropped and counted, instead of holding up the endpoint
+//! while this peer catches up.
+//!
+//! The sender takes packets from the queue at the pace allowed by a
+//! token bucket, which caps the bandwidth used for each peer.
+
+use crate::{IoPair, Packet};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How many unicast packets are held for a peer
+const UNICAST_LEN: usize = 64;
+
+/// How many flooded frames are held for a peer
+const FLOOD_LEN: usize = 32;
+
+/// How many unicast packets are sent before a flooded frame
+const UNICAST_BURST: usize = 4;
+
+/// The priority class of a queued packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Class {
+    Unicast,
+    Flood,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    unicast: VecDeque<Packet>,
+    flood: VecDeque<Packet>,
+    /// Unicast packets sent since the last flooded frame
+    streak: usize,
+    closed: bool,
+}
+
+/// The packets waiting to be sent to a peer
+#[derive(Debug, Default)]
+pub(crate) struct SendQueue {
+    state: Mutex<State>,
+    /// Wake up the sender when a packet was queued
+    wake: IoPair<()>,
+    dropped_unicast: AtomicU64,
+    dropped_flood: AtomicU64,
+}
+
+impl SendQueue {
+    /// Queue a packet, returning `false` if it was dropped
+    pub(crate) fn push(&self, packet: Packet, class: Class) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+
+        let (queue, max, dropped) = match class {
+            Class::Unicast => (&mut state.unicast, UNICAST_LEN, &self.dropped_unicast),
+            Class::Flood => (&mut state.flood, FLOOD_LEN, &self.dropped_flood),
+        };
+        if queue.len() == max {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        queue.push_back(packet);
+        let _ = self.wake.tx.try_send(());
+        true
+    }
+
+    /// Wait for the next packet to send
+    ///
+    /// Returns `None` once the queue was closed.
+    pub(crate) async fn pop(&self) -> Option<Packet> {
+        loop {
+            if let Some(packet) = self.next() {
+                return Some(packet);
+            }
+            if self.state.lock().unwrap().closed {
+                return None;
+            }
+
+            // Woken up by push or close; stale wake-ups just re-check
+            let _ = self.wake.rx.recv().await;
+        }
+    }
+
+    /// Take the next packet, according to priority
+    fn next(&self) -> Option<Packet> {
+        let mut state = self.state.lock().unwrap();
+        let flood_turn = state.streak >= UNICAST_BURST || state.unicast.is_empty();
+        match flood_turn {
+            true => match state.flood.pop_front() {
+                Some(p) => {
+                    state.streak = 0;
+                    Some(p)
+                }
+                None => state.unicast.pop_front(),
+            },
+            false => {
+                state.streak += 1;
+                state.unicast.pop_front()
+            }
+        }
+    }
+
+    /// Drop all queued packets and stop the sender
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.unicast.clear();
+        state.flood.clear();
+        self.wake.tx.close();
+    }
+
+    /// Get the number of dropped unicast and flooded packets
+    pub(crate) fn dropped(&self) -> (u64, u64) {
+        (
+            self.dropped_unicast.load(Ordering::Relaxed),
+            self.dropped_flood.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A token bucket limiting the bytes sent per second
+///
+/// The bucket holds up to one second worth of bytes, which allows for
+/// short bursts.  Packets larger than the bucket are sent anyway, and
+/// delay the ones after them.
+#[derive(Debug)]
+pub(crate) struct RateLimit {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            tokens: f64::MAX,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl RateLimit {
+    /// Take `len` bytes from the bucket, and get how long to wait
+    /// before sending them
+    ///
+    /// A `rate` of 0 bytes per second disables the limit.
+    pub(crate) fn take(&mut self, rate: u64, len: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.last = now;
+        if rate == 0 {
+            self.tokens = f64::MAX;
+            return Duration::from_secs(0);
+        }
+
+        let rate = rate as f64;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate) - len as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / rate),
+            false => Duration::from_secs(0),
+        }
+    }
+}
+
+#[test]
+fn flood_gets_a_turn() {
+    let q = SendQueue::default();
+    for _ in 0..UNICAST_BURST + 1 {
+        assert!(q.push(Packet::Ping, Class::Unicast));
+    }
+    assert!(q.push(Packet::Pong, Class::Flood));
+
+    let order: Vec<_> = std::iter::from_fn(|| q.next()).collect();
+    assert_eq!(order.len(), UNICAST_BURST + 2);
+    assert!(matches!(order[UNICAST_BURST], Packet::Pong));
+}
+
+#[test]
+fn drop_when_full() {
+    let q = SendQueue::default();
+    for _ in 0..FLOOD_LEN {
+        assert!(q.push(Packet::Ping, Class::Flood));
+    }
+    assert!(!q.push(Packet::Ping, Class::Flood));
+    assert!(q.push(Packet::Ping, Class::Unicast));
+    assert_eq!(q.dropped(), (0, 1));
+
+    q.close();
+    assert!(!q.push(Packet::Ping, Class::Unicast));
+    assert!(q.next().is_none());
+}
+
+#[test]
+fn rate_limit_delays_bursts() {
+    let now = Instant::now();
+    let mut r = RateLimit::default();
+
+    // The first second worth of bytes is sent right away
+    assert_eq!(r.take(1000, 1000, now), Duration::from_secs(0));
+    assert_eq!(r.take(1000, 500, now), Duration::from_millis(500));
+    assert_eq!(
+        r.take(1000, 500, now + Duration::from_secs(1)),
+        Duration::from_secs(0)
+    );
+    assert_eq!(r.take(0, 1_000_000, now), Duration::from_secs(0));
+}
@@ -11,16 +11,16 @@
 //! this table, and introduced to.  Once a peer worker has been
 //! spawned, it will make sure the duplex link is never dropped.
 
-use crate::{DstAddr, LinkType, LockedStream, Peer, SourceAddr};
+use crate::{DstAddr, LinkType, Local, LockedStream, Peer, SourceAddr};
 use async_std::sync::{Arc, RwLock};
 use std::collections::BTreeMap;
 use tracing::{trace, warn};
 
 /// Routing table for local IP scope
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub(crate) struct Routes {
-    /// Store which port this instance is listening to
-    port: u16,
+    /// State needed to open connections to peers
+    local: Arc<Local>,
     /// A map of all the peers known to this system
     peers: Arc<RwLock<BTreeMap<usize, Arc<Peer>>>>,
     /// Map source addresses to peer ID
@@ -31,19 +31,26 @@ pub(crate) struct Routes {
 
 impl Routes {
     /// Create a new empty routes table
-    pub(crate) fn new(port: u16) -> Arc<Self> {
+    pub(crate) fn new(local: Arc<Local>) -> Arc<Self> {
         Arc::new(Self {
-            port,
-            ..Self::default()
+            local,
+            peers: Default::default(),
+            src_map: Default::default(),
+            dst_map: Default::default(),
         })
     }
 
     pub(crate) async fn stop_all(self: &Arc<Self>) {
         for (_, peer) in self.peers.read().await.iter() {
-            peer.stop();
+            peer.stop().await;
         }
     }
 
+    /// Get all peers known to this system
+    pub(crate) async fn all(self: &Arc<Self>) -> Vec<Arc<Peer>> {
+        self.peers.read().await.values().cloned().collect()
+    }
+
     /// Get all peers that are currently connected via a DST link
     pub(crate) async fn all_dst(self: &Arc<Self>) -> Vec<Arc<Peer>> {
         self.peers
@@ -64,11 +71,20 @@ impl Routes {
 
     /// Find all parts of the SRC peer and delete them from the
     /// routing table
+    ///
+    /// A peer without a DST address can't be reached anymore once its
+    /// SRC connection is gone, so it is removed entirely.
     pub(crate) async fn purge_src(self: &Arc<Self>, src: SourceAddr) {
         if let Some(id) = self.find_via_src(&src).await {
-            let peers = self.peers.read().await;
-            let p = peers.get(&id).unwrap();
-            p.set_src(None);
+            let mut peers = self.peers.write().await;
+            match peers.get(&id) {
+                Some(p) if p.get_dst().is_none() => {
+                    trace!("Removing SRC-only peer {}", id);
+                    peers.remove(&id);
+                }
+                Some(p) => p.set_src(None),
+                None => {}
+            }
         }
 
         trace!("Removing existing SRC accociation: {:?}", src);
@@ -80,7 +96,7 @@ impl Routes {
     /// This function is called when adding a peer via the static set
     /// of peers to connect to.
     pub(crate) async fn add_via_dst(self: &Arc<Self>, dst: DstAddr, _type: LinkType) -> usize {
-        let p = Peer::open(dst.clone(), self.port, _type);
+        let p = Peer::open(dst, _type, Arc::clone(&self.local));
         let id = p.id;
 
         self.peers.write().await.insert(id, p);
@@ -88,6 +104,34 @@ impl Routes {
         id
     }
 
+    /// Remove a peer that was added via its destination address
+    ///
+    /// The peer is stopped, and its source address association is
+    /// removed as well.  Returns `false` if no such peer was known.
+    pub(crate) async fn remove_via_dst(self: &Arc<Self>, dst: &DstAddr) -> bool {
+        let peer = {
+            let mut peers = self.peers.write().await;
+            let mut src_map = self.src_map.write().await;
+            let id = match self.dst_map.write().await.remove(dst) {
+                Some(id) => id,
+                None => return false,
+            };
+
+            let peer = peers.remove(&id);
+            if let Some(src) = peer.as_ref().and_then(|p| p.get_src()) {
+                src_map.remove(&src);
+            }
+            peer
+        };
+
+        // Stop the peer without holding the routing table locks
+        if let Some(peer) = peer {
+            peer.stop().await;
+        }
+
+        true
+    }
+
     /// Add a peer via it's source address
     ///
     /// These peers are not valid and either need to be merged with a
@@ -95,7 +139,7 @@ impl Routes {
     /// be done later by caling `upgrade_merge(id, port)`.  The
     /// required port can be read from a valid HELLO packet.
     pub(crate) async fn add_via_src(self: &Arc<Self>, src: &SourceAddr) -> usize {
-        let p = Peer::from_src(src.clone());
+        let p = Peer::from_src(*src, Arc::clone(&self.local));
         let id = p.id;
 
         self.peers.write().await.insert(id, p);
@@ -103,9 +147,9 @@ impl Routes {
         id
     }
 
-    /// For a DST peer, simply add the SRC address to the mops
-    pub(crate) async fn add_src(self: &Arc<Self>, id: usize, src: SourceAddr) {
-        self.src_map.write().await.insert(src, id);
+    /// Perform a peer lookup via destination address
+    pub(crate) async fn find_via_dst(self: &Arc<Self>, dst: &DstAddr) -> Option<usize> {
+        self.dst_map.read().await.get(dst).copied()
     }
 
     /// Perform a peer lookup via source address
@@ -151,14 +195,18 @@ impl Routes {
     ///    Alternatively, when the node is running in DYNAMIC mode,
     ///    this might be an entirely new peer all together.  In this
     ///    case we spawn a new DST peer, and attach the SRC address to
-    ///    it, making it a full DUPLEX peer.
+    ///    it, making it a full DUPLEX peer.  If a `stream` was given
+    ///    (for `Limited` links), the peer uses it to send packets and
+    ///    never opens a connection itself.
     ///    
     /// 2. SRC peer found, and DST peer found
     ///
     ///    This will be the most common case, even in STATIC mode: we
     ///    have started a connection to the peer, and were waiting for
     ///    a reverse connection.  We remove the SRC peer and upgrade
-    ///    the DST peer with the SRC address.  Easy :)
+    ///    the DST peer with the SRC address.  Easy :)  If the link is
+    ///    `Limited` and the DST peer isn't connected, the stream is
+    ///    used to send packets.
     ///
     /// 3. Neither SRC nor DST peer found
     ///
@@ -169,7 +217,8 @@ impl Routes {
         self: &Arc<Self>,
         id: usize,
         port: u16,
-        stream: Option<LockedStream>,
+        _type: LinkType,
+        stream: LockedStream,
     ) -> usize {
         let mut peers = self.peers.write().await;
         let mut src_map = self.src_map.write().await;
@@ -196,30 +245,57 @@ impl Routes {
             // If a peer with the implied DST address exists, we drop the
             // SRC peer, and upgrade this to a duplex connection.
             Some(id) => {
-                if stream.is_some() {
-                    warn!("An outgoing stream exists for a LIMITED incoming stream! ignoring...");
+                trace!("Upgrading peer {} with SRC address", id);
+                let peer = peers.get(id).unwrap();
+                if _type == LinkType::Limited {
+                    match peer.connected().await {
+                        true => warn!("Peer is already connected; ignoring LIMITED stream"),
+                        false => peer.set_stream(Arc::clone(&stream)).await,
+                    }
                 }
+                peer.set_incoming(stream);
 
-                trace!("Upgrading peer {} with SRC address", id);
-                let peer = peers.get(&id).unwrap();
                 src_map.insert(src, peer.id);
                 peer.set_src(src);
                 peer.id
             }
             // If no such peer exists, we create one with SRC and DST addresses
             None => {
-                let p = Peer::open(dst, port, LinkType::Bidirect);
-                p.set_src(src);
-                if let Some(s) = stream {
-                    p.set_stream(s).await;
-                }
+                let p = match _type {
+                    LinkType::Limited => {
+                        Peer::reverse(src, dst, Arc::clone(&stream), Arc::clone(&self.local))
+                    }
+                    LinkType::Bidirect => {
+                        let p = Peer::open(dst, LinkType::Bidirect, Arc::clone(&self.local));
+                        p.set_src(src);
+                        p
+                    }
+                };
+                p.set_incoming(stream);
 
                 // Insert peer into lookup tables
-                src_map.insert(src, p.id);
-                dst_map.insert(dst, p.id);
-                peers.insert(p.id, p);
-                peer.id
+                let id = p.id;
+                src_map.insert(src, id);
+                dst_map.insert(dst, id);
+                peers.insert(id, p);
+                id
             }
         }
     }
 }
+
+#[async_std::test]
+async fn purge_src_only_peer() {
+    use crate::keys::{Keypair, Keys};
+
+    let local = Arc::new(Local::new(0, Keys::new(Keypair::generate(), vec![])));
+    let routes = Routes::new(local);
+
+    let src = "127.0.0.1:41312".parse().unwrap();
+    routes.add_via_src(&src).await;
+    assert_eq!(routes.all().await.len(), 1);
+
+    routes.purge_src(src).await;
+    assert!(routes.all().await.is_empty());
+    assert!(routes.find_via_src(&src).await.is_none());
+}
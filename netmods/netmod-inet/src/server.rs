@@ -1,51 +1,48 @@
 //! TCP incoming connection server
 
-use crate::{IoPair, LinkType, Mode, Packet, PacketBuilder, PeerState, Result, Routes, SourceAddr};
+use crate::{
+    peer::KEEPALIVE_TIMEOUT, proto, LinkType, Local, Mode, NoiseStream, NoiseWriter, Packet,
+    PacketBuilder, PeerState, Result, Routes, SourceAddr,
+};
 use async_std::{
-    io::prelude::*,
-    net::{SocketAddr, TcpListener, TcpStream},
+    io,
+    net::{SocketAddr, TcpListener},
     stream::StreamExt,
     sync::{Arc, RwLock},
     task,
 };
 use netmod::{Frame, Target};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
 
-pub(crate) type LockedStream = Arc<RwLock<Option<TcpStream>>>;
+pub(crate) type LockedStream = Arc<RwLock<Option<NoiseWriter>>>;
 
-fn locked_stream(s: TcpStream) -> LockedStream {
+fn locked_stream(s: NoiseWriter) -> LockedStream {
     Arc::new(RwLock::new(Some(s)))
 }
 
 /// The listening server part of the tcp driver
 pub(crate) struct Server {
     alive: Arc<AtomicBool>,
-    _pessimistic: Arc<AtomicBool>,
     inner: TcpListener,
     routes: Arc<Routes>,
+    local: Arc<Local>,
     mode: Mode,
-    _port: u16,
-    incoming: IoPair<(Frame, usize)>,
 }
 
 impl Server {
     /// Create a new tcp listening server, without running it
     pub(crate) async fn new(
         routes: Arc<Routes>,
+        local: Arc<Local>,
         bind: SocketAddr,
-        _port: u16,
         mode: Mode,
-        _pessimistic: Arc<AtomicBool>,
     ) -> Result<Arc<Self>> {
         Ok(TcpListener::bind(bind).await.map(|inner| {
             Arc::new(Self {
                 alive: Arc::new(true.into()),
-                incoming: IoPair::default(),
-                _pessimistic,
                 inner,
                 routes,
-                _port,
+                local,
                 mode,
             })
         })?)
@@ -56,9 +53,9 @@ impl Server {
     }
 
     pub(crate) fn port(&self) -> u16 {
-        self._port
+        self.local.port
     }
-    
+
     pub(crate) fn mode(&self) -> Mode {
         self.mode.clone()
     }
@@ -70,7 +67,8 @@ impl Server {
 
     /// Get the next available frame
     pub(crate) async fn next(self: &Arc<Self>) -> (Frame, Target) {
-        self.incoming
+        self.local
+            .incoming
             .rx
             .recv()
             .await
@@ -96,7 +94,12 @@ impl Server {
 
                 debug!("Accepting new connection...");
                 let s = Arc::clone(&s);
-                task::spawn(async move { s.accept_connection(locked_stream(stream)).await });
+                task::spawn(async move {
+                    match NoiseStream::accept(stream, &s.local.keys).await {
+                        Ok(stream) => s.accept_connection(stream).await,
+                        Err(e) => warn!("Handshake with incoming connection failed: {}", e),
+                    }
+                });
             }
 
             info!("Terminating tcp accept loop!");
@@ -104,47 +107,66 @@ impl Server {
     }
 
     /// loop over a stream of incoming data
-    async fn accept_connection(self: Arc<Self>, stream: LockedStream) {
-        let src_addr = match stream.write().await.as_mut().unwrap().peer_addr() {
+    async fn accept_connection(self: Arc<Self>, stream: NoiseStream) {
+        let src_addr = match stream.peer_addr() {
             Ok(a) => a,
             Err(_) => {
                 error!("Missing peer addr in stream; exiting!");
                 return;
             }
         };
+        debug!(
+            "Accepted connection from {} with key {}",
+            src_addr,
+            stream.remote_key()
+        );
+
+        // Packets for `Limited` links are sent via this connection,
+        // while it is being read from
+        let (mut reader, writer) = stream.split();
+        let writer = locked_stream(writer);
 
+        let mut introduced = false;
         loop {
+            // Peers ping their outgoing connections regularly, so a
+            // silent connection is considered dead
+            // Oversized or undecodable packets drop the connection
+            let mut fb = PacketBuilder::new(&mut reader);
+            let (len, f) = match io::timeout(KEEPALIVE_TIMEOUT, fb.parse()).await {
+                Ok(len) => match fb.build() {
+                    Ok(f) => (len, f),
+                    Err(e) => {
+                        error!("Malformed packet: {}; dropping connection!", e);
+                        break;
+                    }
+                },
+                Err(e) => {
+                    error!(
+                        "Failed to read from incoming packet stream: {}; dropping connection!",
+                        e
+                    );
+                    break;
+                }
+            };
+
             // Find the correct peer or create a temporary one.  If we
             // create a temporary one, we will need to upgrade it
             // before being able to accept valid connections.  We
             // update the peer on every iteration of the loop because
             // a previous packet might have upgraded the connection.
-            let pid = self
-                .routes
-                .find_via_src(&src_addr)
-                .await
-                .unwrap_or_else(|| {
-                    task::block_on(async { self.routes.add_via_src(&src_addr).await })
-                });
-            let peer = self.routes.get_peer(pid).await.unwrap();
-
-            let f = {
-                let mut stream = stream.write().await;
-
-                let mut fb = PacketBuilder::new(stream.as_mut().unwrap());
-                if let Err(_) = fb.parse().await {
-                    error!("Failed to read from incoming packet stream; dropping connection!");
+            let pid = match self.routes.find_via_src(&src_addr).await {
+                Some(id) => id,
+                None if introduced => {
+                    debug!("Peer {} was removed; dropping connection!", src_addr);
                     break;
                 }
-
-                match fb.build() {
-                    Some(f) => f,
-                    None => {
-                        error!("Malformed frame; skipping!");
-                        continue;
-                    }
-                }
+                None => self.routes.add_via_src(&src_addr).await,
+            };
+            let peer = match self.routes.get_peer(pid).await {
+                Some(p) => p,
+                None => break,
             };
+            peer.received(len);
 
             // Match on the peer-state, message payload tuple.  Each
             // scenario is documented on the handler function to keep
@@ -153,32 +175,69 @@ impl Server {
             use Packet::*;
             match (peer.state(), f) {
                 (_, Frame(f)) => self.handle_frame(peer.id, f).await,
-                (state, Hello { port, _type }) => {
-                    self.handle_hello(peer.id, state, &src_addr, port, _type, Arc::clone(&stream))
+                (
+                    state,
+                    Hello {
+                        magic,
+                        version,
+                        port,
+                        _type,
+                    },
+                ) => {
+                    let version = match proto::negotiate(magic, version) {
+                        Some(v) => v,
+                        None => {
+                            warn!(
+                                "Peer {} speaks an unsupported protocol (version {}); dropping connection!",
+                                src_addr, version
+                            );
+                            break;
+                        }
+                    };
+
+                    let stream = Arc::clone(&writer);
+                    if !self
+                        .handle_hello(peer.id, state, &src_addr, port, _type, stream)
                         .await
+                        || !self.send_reply(&writer, Ack { version }).await
+                    {
+                        break;
+                    }
+                    introduced = true;
+                }
+                (_, Ping) => {
+                    if !self.send_reply(&writer, Pong).await {
+                        break;
+                    }
+                }
+                (_, Peers(addrs)) => self.local.pex.learn(addrs),
+                (_, Ack { .. }) | (_, Pong) => {
+                    trace!("Received reply packet on wrong i/o stream. woops")
                 }
-                (_, Ack) => trace!("Received ACK packet on wrong i/o stream. woops"),
-                #[allow(unreachable_patterns)]
-                (state, packet) => panic!("state={:?}, packet={:?}", state, packet),
             }
         }
 
+        // Peers on a limited link can't use this connection anymore
+        *writer.write().await = None;
         self.routes.purge_src(src_addr).await;
         info!("Exiting connetion work-loop; was there a connection drop?");
     }
 
     /// Handle an incoming frame message
     async fn handle_frame(self: &Arc<Self>, peer_id: usize, p: Frame) {
-        self.incoming.tx.send((p, peer_id)).await.unwrap();
+        self.local.incoming.tx.send((p, peer_id)).await.unwrap();
     }
 
-    /// Handle an incoming HELLO message on Tx, or Rx only connections
+    /// Handle an incoming HELLO message
     ///
     /// A hello can come from a peer that we have said hello to before
-    /// (TxOnly), or a peer that has just introduced itself without us
-    /// knowing it before (RxOnly).  If the node is running in dynamic
-    /// mode, check if the peer is in the set of "theoretically known
-    /// peers" before accepting the hello.
+    /// (via the implied destination address), or a peer that has just
+    /// introduced itself without us knowing it before.  If the node
+    /// is running in static mode, check if the peer is in the set of
+    /// "theoretically known peers" before accepting the hello.
+    ///
+    /// Returns `false` if the connection should be dropped, and
+    /// `true` if the hello can be accepted with an ACK.
     async fn handle_hello(
         self: &Arc<Self>,
         rx_peer: usize,
@@ -187,65 +246,115 @@ impl Server {
         port: u16,
         _type: LinkType,
         stream: LockedStream,
-    ) {
+    ) -> bool {
         let maybe_id = self.routes.find_via_srcport(src, port).await;
         let upm = "Received HELLO from unknown peer.";
 
-        let s = match _type {
-            // This connection needs to be established as the
-            // reverse channel on this stream
-            LinkType::Limited => {
-                debug!("Receiving a limited incoming connection...");
-                Some(Arc::clone(&stream))
-            }
-            // This is the default outgoing stream
-            LinkType::Bidirect => None,
+        // A limited connection is established as the reverse
+        // channel on this stream
+        if _type == LinkType::Limited {
+            debug!("Receiving a limited incoming connection...");
+        }
+
+        // Peers are known by their key if any keys were pinned, so
+        // that their address doesn't matter
+        let mode = match self.local.keys.pinned() {
+            true => Mode::Dynamic,
+            false => self.mode,
         };
 
         use PeerState::*;
-        let _self = Arc::clone(self);
-        match (state, self.mode, maybe_id) {
+        match (state, mode, maybe_id) {
             // A peer we didn't know before, while running in dynamic mode
             (RxOnly, Mode::Dynamic, None) => {
-                let id = self.routes.upgrade(rx_peer, port, s).await;
-                trace!("Upgrading RX stream by sending Hello packet...");
-                self.send_hello(id, stream).await;
+                trace!("Upgrading RX stream for a new peer");
+                self.routes
+                    .upgrade(rx_peer, port, _type, Arc::clone(&stream))
+                    .await;
             }
-            // Reverse connection of a peer we have known before
+            // Connection of a peer we have known before
             (RxOnly, _, Some(_id)) => {
-                let id = self.routes.upgrade(rx_peer, port, s).await;
-                trace!("I don't really know what we are doing here...");
-                self.send_hello(id, stream).await;
-            }
-            (TxOnly, _, Some(id)) => {
-                trace!("Upgrading TX stream to BIDIRECT");
-                self.routes.add_src(id, *src).await;
-                self.send_hello(id, stream).await;
+                trace!("Upgrading RX stream for a known peer");
+                self.routes
+                    .upgrade(rx_peer, port, _type, Arc::clone(&stream))
+                    .await;
             }
+            // The peer has introduced itself on this connection before
+            (Duplex, _, _) => trace!("Received repeated HELLO"),
             // A peer we didn't know before, while running in static mode
             (_, Mode::Static, None) => {
-                debug!("{} Running STATIC: dropping packet!", upm);
-                return;
+                debug!("{} Running STATIC: dropping connection!", upm);
+                return false;
+            }
+            (link, mode, id) => {
+                error!("Invalid packet mode: {:?} {:?} {:?}", link, mode, id);
+                return false;
             }
-            (link, mode, id) => panic!("Invalid packet mode: {:?} {:?} {:?}", link, mode, id),
         }
+
+        true
     }
 
-    async fn send_hello(self: &Arc<Self>, id: usize, stream: LockedStream) {
+    /// Reply to a packet on the connection it was received on
+    async fn send_reply(self: &Arc<Self>, stream: &LockedStream, p: Packet) -> bool {
         let mut stream = stream.write().await;
-        let buf = Packet::Ack.serialize();
-        (*stream.as_mut().unwrap()).write_all(&buf).await.unwrap();
+        let stream = match stream.as_mut() {
+            Some(s) => s,
+            None => return false,
+        };
 
-        let s = Arc::clone(self);
-        task::spawn(async move {
-            if let Some(peer) = s.routes.get_peer(id).await {
-                task::sleep(Duration::from_secs(2)).await;
-                peer.send(Packet::Hello {
-                    port: s._port,
-                    _type: peer.link_type(),
-                })
-                .await;
+        match stream.send(&p.serialize()).await {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Failed to send {:?}: {}", p, e);
+                false
             }
-        });
+        }
+    }
+}
+
+#[async_std::test]
+async fn negotiate_version() {
+    use crate::{Endpoint, Keypair, Keys, NoiseReader};
+    use async_std::net::TcpStream;
+
+    let a = Endpoint::new("127.0.0.1:19312", "a", Mode::Dynamic)
+        .await
+        .unwrap();
+    let keys = Keys::new(Keypair::generate(), vec![]);
+    let hello = |version| Packet::Hello {
+        magic: proto::MAGIC,
+        version,
+        port: 19313,
+        _type: LinkType::Bidirect,
+    };
+    let connect = || async {
+        let s = TcpStream::connect("127.0.0.1:19312").await.unwrap();
+        NoiseStream::connect(s, &keys).await.unwrap().split()
+    };
+    async fn recv(reader: &mut NoiseReader) -> io::Result<Packet> {
+        let mut pb = PacketBuilder::new(reader);
+        pb.parse().await?;
+        pb.build()
     }
+
+    // Versions that are too old are rejected
+    let (mut rx, mut tx) = connect().await;
+    tx.send(&hello(0).serialize()).await.unwrap();
+    assert!(recv(&mut rx).await.is_err());
+
+    // Newer versions fall back to the version of the local node
+    let (mut rx, mut tx) = connect().await;
+    tx.send(&hello(proto::VERSION + 1).serialize())
+        .await
+        .unwrap();
+    match recv(&mut rx).await.unwrap() {
+        Packet::Ack { version } => assert_eq!(version, proto::VERSION),
+        p => panic!("expected an ACK, got {:?}", p),
+    }
+
+    // Undecodable packets drop the connection
+    tx.send(b"garbage").await.unwrap();
+    assert!(recv(&mut rx).await.is_err());
+    a.stop().await;
 }
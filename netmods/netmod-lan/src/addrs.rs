@@ -1,31 +1,36 @@
 //! Address resolution table module
 
 use async_std::{
-    net::{Ipv6Addr, SocketAddrV6},
+    net::SocketAddr,
     sync::{Arc, RwLock},
 };
 use std::collections::BTreeMap;
 
+/// The maximum number of peers in a table
+///
+/// Addresses are learned from every datagram, so without a limit any
+/// host on the LAN could fill the table by sending from many ports.
+const MAX_PEERS: usize = 1024;
+
 /// A small utility that creates sequential IDs
 struct IdMaker {
     last: Arc<RwLock<u16>>,
 }
 
 impl IdMaker {
-    async fn curr(&self) -> u16 {
-        *self.last.read().await
-    }
-
-    async fn incr(&self) -> &Self {
-        *self.last.write().await += 1;
-        self
+    /// Create the next ID, unless all of them were handed out
+    async fn next(&self) -> Option<u16> {
+        let mut last = self.last.write().await;
+        *last = last.checked_add(1)?;
+        Some(*last)
     }
 }
 
+/// Map the IPv4 and IPv6 addresses of peers to numeric IDs
 pub(crate) struct AddrTable {
     factory: IdMaker,
-    ips: Arc<RwLock<BTreeMap<u16, SocketAddrV6>>>,
-    ids: Arc<RwLock<BTreeMap<SocketAddrV6, u16>>>,
+    ips: Arc<RwLock<BTreeMap<u16, SocketAddr>>>,
+    ids: Arc<RwLock<BTreeMap<SocketAddr, u16>>>,
 }
 
 impl AddrTable {
@@ -45,26 +50,81 @@ impl AddrTable {
     /// Topology changes are handled additively, because it's not
     /// possible to find out what previous IP a node had, without
     /// performing deep packet inspection and looking at certain
-    /// Identity information.  As such, this table can only grow.
-    pub(crate) async fn set(&self, i: SocketAddrV6) -> u16 {
-        let id = self.factory.incr().await.curr().await;
-        let peer = i.into();
-        self.ips.write().await.insert(id, peer);
-        self.ids.write().await.insert(peer, id);
-        id
+    /// Identity information.  As such, this table can only grow, up
+    /// to `MAX_PEERS` addresses.  Addresses that are already known
+    /// keep their ID.
+    ///
+    /// Returns `None` if a new address doesn't fit into the table.
+    pub(crate) async fn set(&self, i: SocketAddr) -> Option<u16> {
+        let mut ids = self.ids.write().await;
+        if let Some(id) = ids.get(&i) {
+            return Some(*id);
+        }
+
+        if ids.len() >= MAX_PEERS {
+            return None;
+        }
+
+        let id = self.factory.next().await?;
+        self.ips.write().await.insert(id, i);
+        ids.insert(i, id);
+        Some(id)
     }
 
     /// Get the ID for a given Peer address
-    pub(crate) async fn id(&self, peer: SocketAddrV6) -> Option<u16> {
+    pub(crate) async fn id(&self, peer: SocketAddr) -> Option<u16> {
         self.ids.read().await.get(&peer).cloned()
     }
 
     /// Get the Peer for a given internal ID
-    pub(crate) async fn ip(&self, id: u16) -> Option<SocketAddrV6> {
+    pub(crate) async fn ip(&self, id: u16) -> Option<SocketAddr> {
         self.ips.read().await.get(&id).cloned()
     }
 
-    pub(crate) async fn all(&self) -> Vec<SocketAddrV6> {
+    pub(crate) async fn all(&self) -> Vec<SocketAddr> {
         self.ips.read().await.values().cloned().collect()
     }
 }
+
+#[test]
+fn keep_known_ids() {
+    async_std::task::block_on(async {
+        let table = AddrTable::new();
+        let a: SocketAddr = "[fe80::1]:9001".parse().unwrap();
+        let b: SocketAddr = "192.168.1.2:9001".parse().unwrap();
+
+        let id = table.set(a).await;
+        assert_eq!(table.set(a).await, id);
+        assert_ne!(table.set(b).await, id);
+        assert_eq!(table.all().await.len(), 2);
+    });
+}
+
+#[test]
+fn limit_table_size() {
+    async_std::task::block_on(async {
+        let table = AddrTable::new();
+        for port in 0..MAX_PEERS as u16 {
+            let addr = SocketAddr::from(([192, 168, 1, 2], port));
+            assert!(table.set(addr).await.is_some());
+        }
+
+        // Known addresses are still resolved, but new ones are ignored
+        let known = SocketAddr::from(([192, 168, 1, 2], 0));
+        assert_eq!(table.set(known).await, table.id(known).await);
+        let new = SocketAddr::from(([192, 168, 1, 3], 0));
+        assert_eq!(table.set(new).await, None);
+        assert_eq!(table.all().await.len(), MAX_PEERS);
+    });
+}
+
+#[test]
+fn ids_dont_overflow() {
+    async_std::task::block_on(async {
+        let factory = IdMaker {
+            last: Arc::new(RwLock::new(u16::MAX - 1)),
+        };
+        assert_eq!(factory.next().await, Some(u16::MAX));
+        assert_eq!(factory.next().await, None);
+    });
+}
@@ -0,0 +1,14 @@
+//! Local discovery specific error handling
+
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// An error while setting up the discovery socket
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to find interface '{0}': {1}")]
+    InvalidIface(String, io::Error),
+    #[error("failed to open socket: {0}")]
+    Io(#[from] io::Error),
+}
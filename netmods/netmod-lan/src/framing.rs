@@ -29,8 +29,9 @@ impl Envelope {
         bincode::serialize(self).unwrap()
     }
 
-    pub(crate) fn from_bytes(vec: &Vec<u8>) -> Self {
-        bincode::deserialize(&vec).unwrap()
+    /// Decode an envelope, or `None` if the data is malformed
+    pub(crate) fn from_bytes(buf: &[u8]) -> Option<Self> {
+        bincode::deserialize(buf).ok()
     }
 }
 
@@ -40,3 +41,14 @@ impl Envelope {
 /// a payload
 #[derive(Debug, Clone)]
 pub(crate) struct FrameExt(pub(crate) Frame, pub(crate) Target);
+
+#[test]
+fn reject_malformed_envelope() {
+    let env = Envelope::Data(vec![1, 2, 3]).as_bytes();
+    assert!(matches!(
+        Envelope::from_bytes(&env),
+        Some(Envelope::Data(_))
+    ));
+    assert!(Envelope::from_bytes(&env[..env.len() - 1]).is_none());
+    assert!(Envelope::from_bytes(&[0xFF; 4]).is_none());
+}
@@ -7,6 +7,9 @@ extern crate tracing;
 mod addrs;
 pub(crate) use addrs::AddrTable;
 
+mod error;
+pub use error::{Error, Result};
+
 mod socket;
 pub(crate) use socket::Socket;
 
@@ -15,9 +18,53 @@ pub(crate) use framing::{Envelope, FrameExt};
 
 use async_std::{sync::Arc, task};
 use async_trait::async_trait;
-use netmod::{Endpoint as EndpointExt, Frame, Recipient, Result, Target};
+use netmod::{self, Endpoint as EndpointExt, Frame, Recipient, Target};
 use pnet::datalink::interfaces;
-use std::net::ToSocketAddrs;
+use std::{fmt, net::ToSocketAddrs, str::FromStr};
+
+/// How peers on the local network are discovered and flooded to
+///
+/// IPv6 multicast is used by default.  The IPv4 modes are meant for
+/// networks where IPv6 is disabled, and need the interface to have an
+/// IPv4 address.  All nodes on a network must use the same mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Multicast to `ff02::1312`
+    Ipv6,
+    /// Multicast to `239.255.13.12`
+    Ipv4Multicast,
+    /// Broadcast to the broadcast address of the interface
+    Ipv4Broadcast,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Self::Ipv6
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "ipv6" => Ok(Self::Ipv6),
+            "ipv4-multicast" => Ok(Self::Ipv4Multicast),
+            "ipv4-broadcast" => Ok(Self::Ipv4Broadcast),
+            m => Err(format!("unknown discovery mode '{}'", m)),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ipv6 => "ipv6",
+            Self::Ipv4Multicast => "ipv4-multicast",
+            Self::Ipv4Broadcast => "ipv4-broadcast",
+        })
+    }
+}
 
 #[derive(Clone)]
 pub struct Endpoint {
@@ -27,13 +74,23 @@ pub struct Endpoint {
 
 impl Endpoint {
     /// Create a new endpoint and spawn a dispatch task
-    pub fn spawn(iface: &str, port: u16) -> Arc<Self> {
+    ///
+    /// Fails if the interface doesn't exist, or the socket can't be
+    /// bound to the port.
+    pub fn spawn(iface: &str, port: u16) -> Result<Arc<Self>> {
+        Self::spawn_with_mode(iface, port, Mode::default())
+    }
+
+    /// Create a new endpoint with a discovery mode
+    ///
+    /// The IPv4 modes fail if the interface has no IPv4 address.
+    pub fn spawn_with_mode(iface: &str, port: u16, mode: Mode) -> Result<Arc<Self>> {
         task::block_on(async move {
             let addrs = Arc::new(AddrTable::new());
-            Arc::new(Self {
-                socket: Socket::new(iface, port, Arc::clone(&addrs)).await,
+            Ok(Arc::new(Self {
+                socket: Socket::new(iface, port, mode, Arc::clone(&addrs)).await?,
                 addrs,
-            })
+            }))
         })
     }
 
@@ -49,15 +106,21 @@ impl EndpointExt for Endpoint {
         0
     }
 
-    async fn send(&self, frame: Frame, target: Target) -> Result<()> {
+    async fn send(&self, frame: Frame, target: Target) -> netmod::Result<()> {
         let inner = bincode::serialize(&frame).unwrap();
         let env = Envelope::Data(inner);
         match target {
             /// Sending to a user,
             Target::Single(ref id) => {
-                self.socket
-                    .send(&env, self.addrs.ip(*id).await.unwrap())
+                let peer = self
+                    .addrs
+                    .ip(*id)
                     .await
+                    .ok_or(netmod::Error::ConnectionLost)?;
+                self.socket.send(&env, peer).await.map_err(|e| {
+                    debug!("Failed to send frame to {}: {}", peer, e);
+                    netmod::Error::ConnectionLost
+                })?;
             }
             Target::Flood => {
                 self.socket.multicast(&env).await;
@@ -67,7 +130,7 @@ impl EndpointExt for Endpoint {
         Ok(())
     }
 
-    async fn next(&self) -> Result<(Frame, Target)> {
+    async fn next(&self) -> netmod::Result<(Frame, Target)> {
         let fe = self.socket.next().await;
         Ok((fe.0, fe.1))
     }
@@ -80,3 +143,38 @@ pub fn default_iface() -> Option<String> {
         .find(|e| e.is_up() && !e.is_loopback() && !e.ips.is_empty())
         .map(|iface| iface.name)
 }
+
+#[test]
+fn parse_mode() {
+    for mode in [Mode::Ipv6, Mode::Ipv4Multicast, Mode::Ipv4Broadcast].iter() {
+        assert_eq!(mode.to_string().parse(), Ok(*mode));
+    }
+    assert!("ipv5".parse::<Mode>().is_err());
+}
+
+#[test]
+fn learn_ipv4_sender() {
+    use async_std::{future::timeout, net::UdpSocket};
+    use std::time::Duration;
+
+    task::block_on(async {
+        let ep = Endpoint::spawn_with_mode("lo", 19501, Mode::Ipv4Broadcast).unwrap();
+
+        // Frames from peers which never announced themselves are
+        // accepted, and their sender is added to the table
+        let frame = Frame::dummy();
+        let env = Envelope::Data(bincode::serialize(&frame).unwrap());
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sock.send_to(b"garbage", "127.0.0.1:19501").await.unwrap();
+        sock.send_to(&env.as_bytes(), "127.0.0.1:19501")
+            .await
+            .unwrap();
+
+        let (f, _) = timeout(Duration::from_secs(5), ep.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(f, frame);
+        assert_eq!(ep.peers().await, 1);
+    });
+}
@@ -1,25 +1,37 @@
 //! Socket handler module
 
-use crate::{AddrTable, Envelope, FrameExt};
+use crate::{AddrTable, Envelope, Error, FrameExt, Mode, Result};
 use async_std::{
     future::{self, Future},
-    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
     pin::Pin,
     sync::{Arc, RwLock},
     task::{self, Poll},
 };
 use netmod::{Frame, Target};
+use pnet::{datalink::interfaces, ipnetwork::IpNetwork};
 use std::collections::VecDeque;
 use std::ffi::CString;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
 use task_notify::Notify;
 
 const MULTI: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 0x1312);
-const SELF: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0);
+const MULTI_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 13, 12);
+
+/// How long to wait before receiving again after an error, at first
+const RETRY_MIN: Duration = Duration::from_millis(10);
+
+/// How long to wait before receiving again after repeated errors
+const RETRY_MAX: Duration = Duration::from_secs(5);
 
 /// Wraps around a UDP socket an the input queue
 pub(crate) struct Socket {
-    port: u16,
-    scope: u32,
+    /// Where announcements and flooded frames are sent to
+    dst: SocketAddr,
+    /// The address our own IPv4 broadcasts are received from
+    own: Option<SocketAddr>,
     sock: Arc<UdpSocket>,
     inbox: Arc<RwLock<Notify<VecDeque<FrameExt>>>>,
 }
@@ -37,45 +49,101 @@ fn if_nametoindex(name: &str) -> std::io::Result<u32> {
     }
 }
 
+/// Send IPv4 multicasts via the interface with this address
+fn set_multicast_if_v4(sock: &UdpSocket, addr: Ipv4Addr) -> io::Result<()> {
+    let addr = libc::in_addr {
+        s_addr: u32::from(addr).to_be(),
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            &addr as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&addr) as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Get the address and broadcast address of an interface
+fn ipv4_addrs(iface: &str) -> io::Result<(Ipv4Addr, Ipv4Addr)> {
+    interfaces()
+        .into_iter()
+        .filter(|i| i.name == iface)
+        .flat_map(|i| i.ips)
+        .find_map(|ip| match ip {
+            IpNetwork::V4(net) => Some((net.ip(), net.broadcast())),
+            IpNetwork::V6(_) => None,
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no IPv4 address"))
+}
+
 impl Socket {
     /// Create a new socket handler and return a management reference
-    pub(crate) async fn new(iface: &str, port: u16, table: Arc<AddrTable>) -> Arc<Self> {
-        let scope = if_nametoindex(iface).unwrap(); // FIXME: is this blocking?
-        let sock = UdpSocket::bind((SELF, port)).await.unwrap();
-        sock.join_multicast_v6(&MULTI, scope)
-            .expect("Failed to join multicast. Error");
-        sock.set_multicast_loop_v6(false)
-            .expect("Failed to set_multicast_loop_v6. Error");
+    pub(crate) async fn new(
+        iface: &str,
+        port: u16,
+        mode: Mode,
+        table: Arc<AddrTable>,
+    ) -> Result<Arc<Self>> {
+        let invalid = |e| Error::InvalidIface(iface.into(), e);
+        let (sock, dst, own) = match mode {
+            Mode::Ipv6 => {
+                // FIXME: is this blocking?
+                let scope = if_nametoindex(iface).map_err(invalid)?;
+                let sock = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).await?;
+                sock.join_multicast_v6(&MULTI, scope)?;
+                sock.set_multicast_loop_v6(false)?;
+                (sock, SocketAddrV6::new(MULTI, port, 0, scope).into(), None)
+            }
+            Mode::Ipv4Multicast => {
+                let (addr, _) = ipv4_addrs(iface).map_err(invalid)?;
+                let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+                sock.join_multicast_v4(MULTI_V4, addr)?;
+                sock.set_multicast_loop_v4(false)?;
+                set_multicast_if_v4(&sock, addr)?;
+                (sock, SocketAddrV4::new(MULTI_V4, port).into(), None)
+            }
+            Mode::Ipv4Broadcast => {
+                let (addr, broadcast) = ipv4_addrs(iface).map_err(invalid)?;
+                let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+                sock.set_broadcast(true)?;
+                let own = SocketAddrV4::new(addr, port).into();
+                (sock, SocketAddrV4::new(broadcast, port).into(), Some(own))
+            }
+        };
 
         let arc = Arc::new(Self {
-            port,
-            scope,
+            dst,
+            own,
             sock: Arc::new(sock),
             inbox: Default::default(),
         });
 
         Self::incoming_handle(Arc::clone(&arc), table);
         arc.multicast(&Envelope::Announce).await;
-        info!("Sent multicast announcement");
-        arc
+        info!("Sent {} announcement", mode);
+        Ok(arc)
     }
 
     /// Send a message to one specific client
-    pub(crate) async fn send(&self, env: &Envelope, peer: SocketAddrV6) {
-        self.sock
-            .send_to(&env.as_bytes(), peer)
-            .await
-            .unwrap();
+    pub(crate) async fn send(&self, env: &Envelope, peer: SocketAddr) -> io::Result<()> {
+        self.sock.send_to(&env.as_bytes(), peer).await.map(|_| ())
     }
 
-    /// Send a multicast with an Envelope
+    /// Send an Envelope to all peers via multicast or broadcast
+    ///
+    /// Failures are only logged: discovery is best-effort, and
+    /// flooded frames aren't acknowledged either.
     pub(crate) async fn multicast(&self, env: &Envelope) {
-        self.sock
-            .send_to(
-                &env.as_bytes(),
-                SocketAddrV6::new(MULTI.clone(), self.port, 0, self.scope),
-            )
-            .await;
+        if let Err(e) = self.sock.send_to(&env.as_bytes(), self.dst).await {
+            warn!("Failed to send to {}: {}", self.dst, e);
+        }
     }
 
     pub(crate) async fn next(&self) -> FrameExt {
@@ -99,49 +167,80 @@ impl Socket {
     #[instrument(skip(arc, table), level = "trace")]
     fn incoming_handle(arc: Arc<Self>, table: Arc<AddrTable>) {
         task::spawn(async move {
+            let mut backoff = RETRY_MIN;
             loop {
                 // This is a bad idea
                 let mut buf = vec![0; 8192];
 
-                match arc.sock.recv_from(&mut buf).await {
-                    Ok((_, peer)) => {
-                        let peer = match peer {
-                            SocketAddr::V6(v6) => v6,
-                            _ => {
-                                // ignoring IPv4 packets
+                let (len, peer) = match arc.sock.recv_from(&mut buf).await {
+                    // Broadcasts are also received by the sender
+                    Ok((_, peer)) if Some(peer) == arc.own => continue,
+                    Ok(r) => {
+                        backoff = RETRY_MIN;
+                        r
+                    }
+                    // Don't spin on a socket which keeps failing, for
+                    // example because its interface went away
+                    Err(e) => {
+                        warn!(
+                            "Failed to receive datagram: {}; retrying in {:?}",
+                            e, backoff
+                        );
+                        task::sleep(backoff).await;
+                        backoff = (backoff * 2).min(RETRY_MAX);
+                        continue;
+                    }
+                };
+
+                let env = match Envelope::from_bytes(&buf[..len]) {
+                    Some(env) => env,
+                    None => {
+                        debug!("Dropping malformed datagram from {}", peer);
+                        continue;
+                    }
+                };
+
+                match env {
+                    Envelope::Announce => {
+                        debug!("Recieving announce");
+                        if table.set(peer).await.is_none() {
+                            debug!("Peer table is full: ignoring {}", peer);
+                            continue;
+                        }
+                        arc.multicast(&Envelope::Reply).await;
+                    }
+                    Envelope::Reply => {
+                        debug!("Recieving announce reply");
+                        if table.set(peer).await.is_none() {
+                            debug!("Peer table is full: ignoring {}", peer);
+                        }
+                    }
+                    Envelope::Data(vec) => {
+                        debug!("Recieved frame");
+                        let frame = match bincode::deserialize(&vec) {
+                            Ok(frame) => frame,
+                            Err(e) => {
+                                debug!("Dropping malformed frame from {}: {}", peer, e);
                                 continue;
-                            },
-                        };
-                        let env = Envelope::from_bytes(&buf);
-                        match env {
-                            Envelope::Announce => {
-                                debug!("Recieving announce");
-                                table.set(peer).await;
-                                arc.multicast(&Envelope::Reply).await;
-                            }
-                            Envelope::Reply => {
-                                debug!("Recieving announce reply");
-                                table.set(peer).await;
                             }
-                            Envelope::Data(vec) => {
-                                debug!("Recieved frame");
-                                let frame = bincode::deserialize(&vec).unwrap();
-                                info!(frame = format!("{:#?}", frame).as_str());
-
-                                info!(peer = format!("{:#?}", peer).as_str());
-                                let id = table.id(peer.into()).await.unwrap();
-
-                                // Append to the inbox and wake
-                                let mut inbox = arc.inbox.write().await;
-                                inbox.push_back(FrameExt(frame, Target::Single(id)));
-                                Notify::wake(&mut inbox);
+                        };
+                        info!(frame = format!("{:#?}", frame).as_str());
+
+                        info!(peer = format!("{:#?}", peer).as_str());
+
+                        // Learn peers whose announcement was missed
+                        let id = match table.set(peer).await {
+                            Some(id) => id,
+                            None => {
+                                debug!("Peer table is full: dropping frame from {}", peer);
+                                continue;
                             }
-                        }
-                    }
-                    val => {
-                        // TODO: handle errors more gracefully
-                        error!("Crashed UDP thread: {:#?}", val);
-                        val.expect("Crashed UDP thread!");
+                        };
+
+                        // Append to the inbox and wake
+                        let mut inbox = arc.inbox.write().await;
+                        inbox.push_back(FrameExt(frame, Target::Single(id)));
+                        Notify::wake(&mut inbox);
                     }
                 }
             }
@@ -154,7 +253,7 @@ impl Socket {
 fn test_init() {
     task::block_on(async move {
         let table = Arc::new(AddrTable::new());
-        let sock = Socket::new("br42", 12322, table).await;
+        let sock = Socket::new("br42", 12322, Mode::Ipv6, table).await.unwrap();
         println!("Multicasting");
         sock.multicast(&Envelope::Announce);
     });
@@ -0,0 +1,14 @@
+//! UDP overlay specific error handling
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A generic initialisation error
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to initialise socket: invalid address")]
+    InvalidAddr,
+    #[error("the peer is not known to this endpoint")]
+    UnknownPeer,
+    #[error("i/o error: {0}")]
+    Io(#[from] async_std::io::Error),
+}
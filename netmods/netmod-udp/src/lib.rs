@@ -0,0 +1,466 @@
+//! A reliable UDP overlay netmod to connect routers across the internet
+//!
+//! Unlike netmod-inet, which opens TCP connections, this endpoint
+//! sends every frame in its own datagram and acknowledges frames one
+//! by one.  A lost datagram only delays the frame it carried, instead
+//! of every frame behind it, and there are no connections to set up
+//! or re-open after a peer moved or went away.
+//!
+//! Flooded frames are never held up by a slow peer: if too many
+//! frames to a peer weren't acknowledged yet, the flood skips it.
+//! Peers which introduced themselves in dynamic mode are forgotten
+//! when they haven't been heard from for a while.
+
+#[macro_use]
+extern crate tracing;
+
+mod error;
+mod peer;
+mod peers;
+mod proto;
+
+pub use error::{Error, Result};
+
+pub(crate) use peer::Peer;
+pub(crate) use peers::Peers;
+pub(crate) use proto::Packet;
+
+use async_std::{
+    channel::{bounded, Receiver, Sender},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    task,
+};
+use async_trait::async_trait;
+use netmod::{self, Endpoint as EndpointExt, Frame, Target};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// How often frames are checked for retransmission
+const TICK: Duration = Duration::from_millis(50);
+
+/// How often all peers are sent a hello
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many received frames are buffered for the router
+const INCOMING_LEN: usize = 64;
+
+/// How long to wait before receiving again after an error, doubled
+/// for each consecutive error up to `RETRY_MAX`
+const RETRY_MIN: Duration = Duration::from_millis(10);
+const RETRY_MAX: Duration = Duration::from_secs(5);
+
+/// Define the runtime mode for this endpount
+///
+/// In dynamic mode any new peer can introduce itself with a hello, up
+/// to a limit, while in static mode datagrams from unknown addresses
+/// are dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Mode {
+    Static,
+    Dynamic,
+}
+
+/// A reliable UDP overlay endpoint
+pub struct Endpoint {
+    socket: UdpSocket,
+    peers: Peers,
+    mode: Mode,
+    /// Lets peers tell frames of this run apart from earlier ones
+    session: u64,
+    alive: AtomicBool,
+    incoming: Receiver<(Frame, usize)>,
+    /// Flooded frames dropped because a peer had too many in flight
+    dropped_flood: AtomicU64,
+}
+
+impl Endpoint {
+    /// Create a new endpoint on an interface and port
+    #[tracing::instrument(level = "info")]
+    pub async fn new(bind: &str, name: &str, mode: Mode) -> Result<Arc<Self>> {
+        info!("Initialising Udp backend");
+
+        let bind: SocketAddr = bind.parse().map_err(|_| Error::InvalidAddr)?;
+        let socket = UdpSocket::bind(bind).await?;
+        let (tx, incoming) = bounded(INCOMING_LEN);
+
+        let ep = Arc::new(Self {
+            socket,
+            peers: Peers::default(),
+            mode,
+            session: rand::random(),
+            alive: AtomicBool::new(true),
+            incoming,
+            dropped_flood: AtomicU64::new(0),
+        });
+
+        task::spawn(Arc::clone(&ep).receive(tx));
+        task::spawn(Arc::clone(&ep).retransmit());
+        Ok(ep)
+    }
+
+    /// Get the current runtime mode
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Get the port this netmod is bound to
+    pub fn port(&self) -> u16 {
+        self.socket.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    /// Get the number of flooded frames which were dropped because a
+    /// peer didn't acknowledge earlier frames
+    pub fn dropped_floods(&self) -> u64 {
+        self.dropped_flood.load(Ordering::Relaxed)
+    }
+
+    /// Stop all tasks of this endpoint
+    pub async fn stop(&self) {
+        self.alive.store(false, Ordering::Relaxed);
+
+        // Wake up the receiver with an empty datagram
+        if let Ok(mut addr) = self.socket.local_addr() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            self.send_to(&[], addr).await;
+        }
+    }
+
+    /// Insert a set of peers into the routing table
+    ///
+    /// Peers are given as an address and port, or a host name and
+    /// port.  Each peer is sent a hello, so that it can learn about
+    /// this endpoint if it runs in dynamic mode.
+    pub async fn add_peers(&self, peers: Vec<String>) -> Result<()> {
+        for p in peers.into_iter() {
+            if p.is_empty() {
+                continue;
+            }
+
+            let addr = match resolve(&p).await {
+                Some(addr) => addr,
+                None => {
+                    warn!("Failed to parse peer: '{}'... skipping", p);
+                    continue;
+                }
+            };
+
+            trace!("Adding peer: {}", addr);
+            self.peers.add(addr, false).await;
+            self.send_to(&Packet::hello().serialize(), addr).await;
+        }
+
+        Ok(())
+    }
+
+    /// Remove a set of peers from the routing table
+    ///
+    /// Peers are given in the same format as for `add_peers`.
+    /// Unknown peers are skipped.
+    pub async fn remove_peers(&self, peers: Vec<String>) -> Result<()> {
+        for p in peers.into_iter() {
+            if p.is_empty() {
+                continue;
+            }
+
+            match self.remove_peer(&p).await {
+                Ok(()) => trace!("Removed peer: {}", p),
+                Err(e) => warn!("Failed to remove peer '{}': {}... skipping", p, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove a single peer from the routing table
+    ///
+    /// Frames which weren't acknowledged by the peer yet are dropped.
+    pub async fn remove_peer(&self, peer: &str) -> Result<()> {
+        let addr = resolve(peer).await.ok_or(Error::InvalidAddr)?;
+        match self.peers.remove(&addr).await {
+            true => Ok(()),
+            false => Err(Error::UnknownPeer),
+        }
+    }
+
+    fn alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    async fn send_to(&self, data: &[u8], addr: SocketAddr) {
+        if let Err(e) = self.socket.send_to(data, addr).await {
+            debug!("Failed to send datagram to {}: {}", addr, e);
+        }
+    }
+
+    /// Handle incoming datagrams until the endpoint is stopped
+    async fn receive(self: Arc<Self>, tx: Sender<(Frame, usize)>) {
+        let mut buf = vec![0; proto::MAX_DATAGRAM_LEN];
+        let mut retry = RETRY_MIN;
+        while self.alive() {
+            let (len, addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Failed to receive datagram: {}", e);
+                    task::sleep(retry).await;
+                    retry = (retry * 2).min(RETRY_MAX);
+                    continue;
+                }
+            };
+            retry = RETRY_MIN;
+
+            let packet = match Packet::decode(&buf[..len]) {
+                Some(p) => p,
+                None => {
+                    trace!("Dropping invalid datagram from {}", addr);
+                    continue;
+                }
+            };
+
+            // Only a hello introduces a new peer, so that stray data
+            // or acknowledgements don't fill up the peer table
+            let peer = match (self.peers.find(&addr).await, &packet, self.mode) {
+                (Some(peer), _, _) => peer,
+                (None, Packet::Hello { .. }, Mode::Dynamic) => match self.peers.learn(addr).await {
+                    Some(peer) => {
+                        debug!("Adding new peer {}", addr);
+                        peer
+                    }
+                    None => {
+                        debug!("Too many learned peers, ignoring {}", addr);
+                        continue;
+                    }
+                },
+                (None, _, _) => {
+                    debug!("Dropping datagram from unknown peer {}", addr);
+                    continue;
+                }
+            };
+
+            peer.seen(Instant::now());
+            match packet {
+                Packet::Hello { .. } => trace!("Received HELLO from {}", addr),
+                Packet::Data {
+                    session,
+                    seq,
+                    frame,
+                } => {
+                    // Acknowledge duplicates too, in case the first
+                    // acknowledgement was lost
+                    self.send_to(&Packet::Ack { seq }.serialize(), addr).await;
+                    if peer.receive(session, seq) && tx.send((frame, peer.id)).await.is_err() {
+                        break;
+                    }
+                }
+                Packet::Ack { seq } => peer.ack(seq),
+            }
+        }
+
+        debug!("Shutting down datagram receiver");
+    }
+
+    /// Re-send unacknowledged frames, keep peers aware of this node,
+    /// and forget learned peers which went silent
+    async fn retransmit(self: Arc<Self>) {
+        let mut last_hello = Instant::now();
+        while self.alive() {
+            task::sleep(TICK).await;
+
+            let now = Instant::now();
+            let hello = now - last_hello >= KEEPALIVE_INTERVAL;
+            if hello {
+                last_hello = now;
+            }
+
+            for peer in self.peers.all().await {
+                if peer.expired(now) {
+                    debug!("Peer {} timed out; removing", peer.addr);
+                    self.peers.remove(&peer.addr).await;
+                    continue;
+                }
+
+                if hello {
+                    self.send_to(&Packet::hello().serialize(), peer.addr).await;
+                }
+                for data in peer.due(now) {
+                    trace!("Re-sending frame to {}", peer.addr);
+                    self.send_to(&data, peer.addr).await;
+                }
+            }
+        }
+    }
+}
+
+/// Turn a peer line into a SocketAddr
+///
+/// Host names are resolved, preferring IPv6 addresses.
+async fn resolve(peer: &str) -> Option<SocketAddr> {
+    match peer.parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => ToSocketAddrs::to_socket_addrs(peer)
+            .await
+            .ok()?
+            .fold(None, |acc, addr| match (acc, addr) {
+                (None, addr) => Some(addr),
+                (_, maybe_v6) if maybe_v6.is_ipv6() => Some(maybe_v6),
+                (addr, _) => addr,
+            }),
+    }
+}
+
+#[async_trait]
+impl EndpointExt for Endpoint {
+    fn size_hint(&self) -> usize {
+        0
+    }
+
+    async fn send(&self, frame: Frame, target: Target) -> netmod::Result<()> {
+        match target {
+            // A peer which doesn't acknowledge frames must not hold
+            // up the flood to all other peers
+            Target::Flood => {
+                for peer in self.peers.all().await {
+                    match peer.try_queue(self.session, frame.clone()) {
+                        Ok(Some(data)) => self.send_to(&data, peer.addr).await,
+                        Ok(None) => {
+                            trace!("Too many frames in flight to {}; dropping flood", peer.addr);
+                            self.dropped_flood.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // The peer was removed in the meantime
+                        Err(netmod::Error::ConnectionLost) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Target::Single(id) => {
+                let peer = self
+                    .peers
+                    .get(id as usize)
+                    .await
+                    .ok_or(netmod::Error::ConnectionLost)?;
+                let data = peer.queue(self.session, frame).await?;
+                self.send_to(&data, peer.addr).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn next(&self) -> netmod::Result<(Frame, Target)> {
+        self.incoming
+            .recv()
+            .await
+            .map(|(f, id)| (f, Target::Single(id as u16)))
+            .map_err(|_| netmod::Error::ConnectionLost)
+    }
+}
+
+#[cfg(test)]
+async fn exchange_frames(a: &Endpoint, b: &Endpoint) {
+    use async_std::future::timeout;
+
+    let t = Duration::from_secs(10);
+    let frame = Frame::dummy();
+    a.send(frame.clone(), Target::Flood).await.unwrap();
+    let (f, target) = timeout(t, b.next()).await.unwrap().unwrap();
+    assert_eq!(f, frame);
+
+    b.send(frame.clone(), target).await.unwrap();
+    let (f, _) = timeout(t, a.next()).await.unwrap().unwrap();
+    assert_eq!(f, frame);
+}
+
+#[async_std::test]
+async fn dynamic_peer() {
+    let a = Endpoint::new("127.0.0.1:19401", "a", Mode::Static)
+        .await
+        .unwrap();
+    let b = Endpoint::new("127.0.0.1:19402", "b", Mode::Dynamic)
+        .await
+        .unwrap();
+    a.add_peers(vec!["127.0.0.1:19402".into()]).await.unwrap();
+
+    exchange_frames(&a, &b).await;
+    a.stop().await;
+    b.stop().await;
+}
+
+#[async_std::test]
+async fn static_peers_only() {
+    use async_std::future::timeout;
+
+    let a = Endpoint::new("127.0.0.1:19403", "a", Mode::Static)
+        .await
+        .unwrap();
+    let b = Endpoint::new("127.0.0.1:19404", "b", Mode::Static)
+        .await
+        .unwrap();
+    a.add_peers(vec!["127.0.0.1:19404".into()]).await.unwrap();
+
+    // B drops frames from A until it knows about it
+    let frame = Frame::dummy();
+    a.send(frame.clone(), Target::Flood).await.unwrap();
+    assert!(timeout(Duration::from_millis(500), b.next()).await.is_err());
+
+    // A keeps re-sending the frame until B accepts it
+    b.add_peers(vec!["127.0.0.1:19403".into()]).await.unwrap();
+    let (f, _) = timeout(Duration::from_secs(10), b.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(f, frame);
+
+    a.stop().await;
+    b.stop().await;
+}
+
+#[async_std::test]
+async fn remove_unknown_peer() {
+    let a = Endpoint::new("127.0.0.1:19405", "a", Mode::Static)
+        .await
+        .unwrap();
+    a.add_peers(vec!["127.0.0.1:19406".into()]).await.unwrap();
+    a.remove_peer("127.0.0.1:19406").await.unwrap();
+    assert!(a.remove_peer("127.0.0.1:19406").await.is_err());
+    assert!(a.remove_peer("not an address").await.is_err());
+    a.stop().await;
+}
+
+#[async_std::test]
+async fn learn_peers_from_hellos() {
+    let a = UdpSocket::bind("127.0.0.1:19407").await.unwrap();
+    let b = Endpoint::new("127.0.0.1:19408", "b", Mode::Dynamic)
+        .await
+        .unwrap();
+    let from: SocketAddr = "127.0.0.1:19407".parse().unwrap();
+
+    // Data from an unknown address doesn't introduce a peer
+    let data = Packet::Data {
+        session: 0,
+        seq: 0,
+        frame: Frame::dummy(),
+    };
+    a.send_to(&data.serialize(), "127.0.0.1:19408")
+        .await
+        .unwrap();
+    a.send_to(&Packet::Ack { seq: 0 }.serialize(), "127.0.0.1:19408")
+        .await
+        .unwrap();
+    task::sleep(Duration::from_millis(200)).await;
+    assert!(b.peers.find(&from).await.is_none());
+
+    a.send_to(&Packet::hello().serialize(), "127.0.0.1:19408")
+        .await
+        .unwrap();
+    task::sleep(Duration::from_millis(200)).await;
+    assert!(b.peers.find(&from).await.is_some());
+    b.stop().await;
+}
@@ -0,0 +1,293 @@
+//! Reliable delivery to a single peer
+//!
+//! Every frame sent to a peer gets a sequence number, and is kept
+//! until the peer acknowledges it.  Unacknowledged frames are sent
+//! again with an exponential backoff, and dropped after a few
+//! attempts.  Only a limited number of frames can be in flight at a
+//! time; sending more waits until older ones were acknowledged, or
+//! drops flooded frames right away.
+//!
+//! Frames are delivered as soon as they arrive, in any order.  The
+//! receiver remembers recently seen sequence numbers to drop frames
+//! which were sent twice because an acknowledgement was lost.
+
+use crate::{proto::MAX_DATAGRAM_LEN, Packet};
+use async_std::channel::{bounded, Receiver, Sender, TrySendError};
+use netmod::Frame;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long to wait for the first acknowledgement
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How often a frame is sent before it is dropped
+const MAX_ATTEMPTS: u32 = 6;
+
+/// How many frames may be unacknowledged at a time
+const MAX_IN_FLIGHT: usize = 128;
+
+/// How many received sequence numbers are remembered
+const WINDOW_LEN: usize = 1024;
+
+/// How long a learned peer is kept without hearing from it
+pub(crate) const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A frame that wasn't acknowledged yet
+#[derive(Debug)]
+struct Pending {
+    data: Vec<u8>,
+    attempts: u32,
+    due: Instant,
+}
+
+/// Recently received sequence numbers of a peer session
+#[derive(Debug, Default)]
+struct Window {
+    session: Option<u64>,
+    seqs: BTreeSet<u64>,
+}
+
+impl Window {
+    /// Remember a sequence number, returning `false` if it was seen
+    ///
+    /// Numbers older than the whole window are assumed to be seen.
+    fn insert(&mut self, session: u64, seq: u64) -> bool {
+        if self.session != Some(session) {
+            self.session = Some(session);
+            self.seqs.clear();
+        }
+
+        let oldest = self.seqs.iter().next().copied();
+        if self.seqs.len() == WINDOW_LEN && oldest.is_some_and(|o| seq < o) {
+            return false;
+        }
+
+        if !self.seqs.insert(seq) {
+            return false;
+        }
+        if self.seqs.len() > WINDOW_LEN {
+            self.seqs.remove(&oldest.unwrap());
+        }
+        true
+    }
+}
+
+/// A peer reached via a UDP address
+#[derive(Debug)]
+pub(crate) struct Peer {
+    /// Unique numeric Id for each peer
+    pub(crate) id: usize,
+    /// The address datagrams are sent to
+    pub(crate) addr: SocketAddr,
+    /// Whether this peer was learned from an incoming hello,
+    /// instead of being added via `add_peers`
+    pub(crate) learned: bool,
+    /// When a datagram was last received from this peer
+    last_seen: Mutex<Instant>,
+    /// The next sequence number to send
+    next_seq: AtomicU64,
+    /// Frames which were sent but not acknowledged yet
+    pending: Mutex<BTreeMap<u64, Pending>>,
+    /// One token for each frame in flight
+    in_flight: (Sender<()>, Receiver<()>),
+    /// Sequence numbers received from this peer
+    window: Mutex<Window>,
+}
+
+impl Peer {
+    pub(crate) fn new(id: usize, addr: SocketAddr, learned: bool) -> Self {
+        Self {
+            id,
+            addr,
+            learned,
+            last_seen: Mutex::new(Instant::now()),
+            next_seq: AtomicU64::new(0),
+            pending: Default::default(),
+            in_flight: bounded(MAX_IN_FLIGHT),
+            window: Default::default(),
+        }
+    }
+
+    /// Prepare a frame to be sent, and keep it until it is acknowledged
+    ///
+    /// Waits while too many frames are in flight.  Fails if the frame
+    /// is too large for a datagram, or the peer was removed.
+    pub(crate) async fn queue(&self, session: u64, frame: Frame) -> netmod::Result<Vec<u8>> {
+        let (seq, data) = self.encode(session, frame)?;
+        self.in_flight
+            .0
+            .send(())
+            .await
+            .map_err(|_| netmod::Error::ConnectionLost)?;
+        self.track(seq, &data);
+        Ok(data)
+    }
+
+    /// Prepare a frame to be sent without waiting
+    ///
+    /// Returns `None` if too many frames are in flight, in which case
+    /// the frame is dropped.
+    pub(crate) fn try_queue(&self, session: u64, frame: Frame) -> netmod::Result<Option<Vec<u8>>> {
+        let (seq, data) = self.encode(session, frame)?;
+        match self.in_flight.0.try_send(()) {
+            Ok(()) => {
+                self.track(seq, &data);
+                Ok(Some(data))
+            }
+            Err(TrySendError::Full(_)) => Ok(None),
+            Err(TrySendError::Closed(_)) => Err(netmod::Error::ConnectionLost),
+        }
+    }
+
+    /// Assign a sequence number to a frame and serialize it
+    fn encode(&self, session: u64, frame: Frame) -> netmod::Result<(u64, Vec<u8>)> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let data = Packet::Data {
+            session,
+            seq,
+            frame,
+        }
+        .serialize();
+        match data.len() > MAX_DATAGRAM_LEN {
+            true => Err(netmod::Error::FrameTooLarge),
+            false => Ok((seq, data)),
+        }
+    }
+
+    /// Keep a sent frame until it is acknowledged
+    fn track(&self, seq: u64, data: &[u8]) {
+        self.pending.lock().unwrap().insert(
+            seq,
+            Pending {
+                data: data.to_vec(),
+                attempts: 1,
+                due: Instant::now() + RETRANSMIT_TIMEOUT,
+            },
+        );
+    }
+
+    /// Handle an acknowledgement from the peer
+    pub(crate) fn ack(&self, seq: u64) {
+        if self.pending.lock().unwrap().remove(&seq).is_some() {
+            let _ = self.in_flight.1.try_recv();
+        }
+    }
+
+    /// Get all frames which have to be sent again
+    ///
+    /// Frames which were sent too often are dropped.
+    pub(crate) fn due(&self, now: Instant) -> Vec<Vec<u8>> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut resend = vec![];
+        let mut dropped = vec![];
+
+        for (seq, p) in pending.iter_mut().filter(|(_, p)| p.due <= now) {
+            if p.attempts == MAX_ATTEMPTS {
+                dropped.push(*seq);
+                continue;
+            }
+
+            p.due = now + RETRANSMIT_TIMEOUT * 2u32.pow(p.attempts);
+            p.attempts += 1;
+            resend.push(p.data.clone());
+        }
+
+        for seq in dropped {
+            warn!(
+                "Frame {} to {} was never acknowledged; dropping",
+                seq, self.addr
+            );
+            pending.remove(&seq);
+            let _ = self.in_flight.1.try_recv();
+        }
+        resend
+    }
+
+    /// Remember that a datagram was received from this peer
+    pub(crate) fn seen(&self, now: Instant) {
+        *self.last_seen.lock().unwrap() = now;
+    }
+
+    /// Check whether a learned peer wasn't heard from for too long
+    ///
+    /// Peers added via `add_peers` never expire.
+    pub(crate) fn expired(&self, now: Instant) -> bool {
+        self.learned && now.duration_since(*self.last_seen.lock().unwrap()) > PEER_TIMEOUT
+    }
+
+    /// Check whether a received frame is new, and remember it
+    pub(crate) fn receive(&self, session: u64, seq: u64) -> bool {
+        self.window.lock().unwrap().insert(session, seq)
+    }
+
+    /// Drop all frames in flight, and wake up senders
+    pub(crate) fn close(&self) {
+        self.in_flight.0.close();
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+#[test]
+fn drop_duplicates() {
+    let mut w = Window::default();
+    assert!(w.insert(1, 5));
+    assert!(w.insert(1, 3));
+    assert!(!w.insert(1, 5));
+
+    // A new session starts with an empty window
+    assert!(w.insert(2, 5));
+
+    for seq in 100..100 + WINDOW_LEN as u64 {
+        assert!(w.insert(2, seq));
+    }
+    assert!(!w.insert(2, 6));
+}
+
+#[async_std::test]
+async fn retransmit_until_acked() {
+    let peer = Peer::new(0, "127.0.0.1:9000".parse().unwrap(), false);
+    peer.queue(0, Frame::dummy()).await.unwrap();
+
+    let now = Instant::now();
+    assert!(peer.due(now).is_empty());
+    assert_eq!(peer.due(now + RETRANSMIT_TIMEOUT).len(), 1);
+
+    // Nothing is sent again once the frame was acknowledged
+    peer.ack(0);
+    assert!(peer.due(now + RETRANSMIT_TIMEOUT * 100).is_empty());
+}
+
+#[test]
+fn drop_when_window_full() {
+    let peer = Peer::new(0, "127.0.0.1:9000".parse().unwrap(), false);
+    for _ in 0..MAX_IN_FLIGHT {
+        assert!(peer.try_queue(0, Frame::dummy()).unwrap().is_some());
+    }
+    assert!(peer.try_queue(0, Frame::dummy()).unwrap().is_none());
+
+    // An acknowledgement makes room for another frame
+    peer.ack(0);
+    assert!(peer.try_queue(0, Frame::dummy()).unwrap().is_some());
+}
+
+#[test]
+fn learned_peers_expire() {
+    let now = Instant::now();
+    let later = now + PEER_TIMEOUT * 2;
+
+    let learned = Peer::new(0, "127.0.0.1:9000".parse().unwrap(), true);
+    let added = Peer::new(1, "127.0.0.1:9001".parse().unwrap(), false);
+    assert!(!learned.expired(now));
+    assert!(learned.expired(later));
+    assert!(!added.expired(later));
+
+    learned.seen(later);
+    assert!(!learned.expired(later));
+}
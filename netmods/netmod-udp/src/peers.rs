@@ -0,0 +1,117 @@
+//! The set of peers known to an endpoint
+
+use crate::Peer;
+use async_std::sync::{Arc, RwLock};
+use std::{collections::BTreeMap, net::SocketAddr};
+
+/// How many peers can introduce themselves to an endpoint
+pub(crate) const MAX_LEARNED: usize = 256;
+
+#[derive(Debug, Default)]
+struct Table {
+    /// The ID of the next new peer
+    next_id: usize,
+    /// All peers known to this endpoint
+    peers: BTreeMap<usize, Arc<Peer>>,
+    /// Map addresses to peer IDs
+    addrs: BTreeMap<SocketAddr, usize>,
+}
+
+impl Table {
+    fn insert(&mut self, addr: SocketAddr, learned: bool) -> Arc<Peer> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let peer = Arc::new(Peer::new(id, addr, learned));
+        self.peers.insert(id, Arc::clone(&peer));
+        self.addrs.insert(addr, id);
+        peer
+    }
+}
+
+/// Peers known to an endpoint, by ID and address
+#[derive(Debug, Default)]
+pub(crate) struct Peers {
+    inner: RwLock<Table>,
+}
+
+impl Peers {
+    /// Add a peer, or get the existing one for this address
+    ///
+    /// `learned` marks peers which introduced themselves, which are
+    /// removed again when they go silent.
+    pub(crate) async fn add(&self, addr: SocketAddr, learned: bool) -> Arc<Peer> {
+        let mut t = self.inner.write().await;
+        if let Some(id) = t.addrs.get(&addr) {
+            return Arc::clone(&t.peers[id]);
+        }
+
+        t.insert(addr, learned)
+    }
+
+    /// Add a peer which introduced itself
+    ///
+    /// Returns `None` if `MAX_LEARNED` peers were already learned
+    /// this way.
+    pub(crate) async fn learn(&self, addr: SocketAddr) -> Option<Arc<Peer>> {
+        let mut t = self.inner.write().await;
+        if let Some(id) = t.addrs.get(&addr) {
+            return Some(Arc::clone(&t.peers[id]));
+        }
+
+        match t.peers.values().filter(|p| p.learned).count() < MAX_LEARNED {
+            true => Some(t.insert(addr, true)),
+            false => None,
+        }
+    }
+
+    /// Remove a peer, dropping all frames still in flight
+    ///
+    /// Returns `false` if no such peer was known.
+    pub(crate) async fn remove(&self, addr: &SocketAddr) -> bool {
+        let mut t = self.inner.write().await;
+        let peer = match t.addrs.remove(addr) {
+            Some(id) => t.peers.remove(&id),
+            None => return false,
+        };
+
+        if let Some(peer) = peer {
+            peer.close();
+        }
+        true
+    }
+
+    /// Get the peer with an ID
+    pub(crate) async fn get(&self, id: usize) -> Option<Arc<Peer>> {
+        self.inner.read().await.peers.get(&id).cloned()
+    }
+
+    /// Get the peer with an address
+    pub(crate) async fn find(&self, addr: &SocketAddr) -> Option<Arc<Peer>> {
+        let t = self.inner.read().await;
+        t.addrs.get(addr).map(|id| Arc::clone(&t.peers[id]))
+    }
+
+    /// Get all peers known to this endpoint
+    pub(crate) async fn all(&self) -> Vec<Arc<Peer>> {
+        self.inner.read().await.peers.values().cloned().collect()
+    }
+}
+
+#[async_std::test]
+async fn limit_learned_peers() {
+    let peers = Peers::default();
+    let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+    peers.add(addr(1), false).await;
+    for port in 0..MAX_LEARNED as u16 {
+        assert!(peers.learn(addr(1000 + port)).await.is_some());
+    }
+
+    // Known peers are still found, but no new ones are learned
+    assert!(peers.learn(addr(1000)).await.is_some());
+    assert!(peers.learn(addr(2)).await.is_none());
+    peers.add(addr(3), false).await;
+
+    peers.remove(&addr(1000)).await;
+    assert!(peers.learn(addr(2)).await.is_some());
+}
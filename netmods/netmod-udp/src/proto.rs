@@ -0,0 +1,80 @@
+//! Datagram format of the UDP overlay
+//!
+//! Each datagram holds exactly one bincode encoded `Packet`.  Frames
+//! are acknowledged one by one, so that a lost datagram only delays
+//! the frame it carried.
+
+use bincode::{deserialize, serialize};
+use netmod::Frame;
+use serde::{Deserialize, Serialize};
+
+/// Identifies datagrams of the UDP overlay protocol
+pub(crate) const MAGIC: [u8; 4] = *b"IRDu";
+
+/// The newest protocol version spoken by this node
+pub(crate) const VERSION: u16 = 1;
+
+/// The oldest protocol version still spoken by this node
+pub(crate) const MIN_VERSION: u16 = 1;
+
+/// The largest payload of a UDP datagram
+pub(crate) const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// An internally used packet format
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Packet {
+    /// Introduces the sender, and keeps NAT mappings open
+    ///
+    /// Hellos from other protocols or unsupported versions are
+    /// ignored, so that such senders are never learned as peers.
+    Hello { magic: [u8; 4], version: u16 },
+    /// A frame that has to be acknowledged
+    ///
+    /// The session is chosen randomly whenever an endpoint starts, so
+    /// that sequence numbers of a restarted peer aren't mistaken for
+    /// duplicates.
+    Data {
+        session: u64,
+        seq: u64,
+        frame: Frame,
+    },
+    /// Acknowledges a `Data` packet
+    Ack { seq: u64 },
+}
+
+impl Packet {
+    /// Create a hello for the local protocol version
+    pub(crate) fn hello() -> Self {
+        Self::Hello {
+            magic: MAGIC,
+            version: VERSION,
+        }
+    }
+
+    /// Serialises the packet to send it in a datagram
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        serialize(self).unwrap()
+    }
+
+    /// Decode a received datagram
+    ///
+    /// Returns `None` for datagrams that can't be decoded, and for
+    /// hellos of other protocols or unsupported versions.
+    pub(crate) fn decode(buf: &[u8]) -> Option<Self> {
+        match deserialize(buf).ok()? {
+            Self::Hello { magic, version } if magic != MAGIC || version < MIN_VERSION => None,
+            p => Some(p),
+        }
+    }
+}
+
+#[test]
+fn reject_foreign_hello() {
+    let hello = Packet::Hello {
+        magic: *b"XXXX",
+        version: VERSION,
+    };
+    assert!(Packet::decode(&hello.serialize()).is_none());
+    assert!(Packet::decode(&Packet::hello().serialize()).is_some());
+    assert!(Packet::decode(b"garbage").is_none());
+}
@@ -0,0 +1,157 @@
+/* Generated by cbindgen from src/ffi.rs.  Do not edit! */
+
+#ifndef RATMAN_CLIENT_H
+#define RATMAN_CLIENT_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Length of an address in bytes
+ */
+#define RATMAN_ID_LEN 32
+
+/**
+ * An opaque connection to the daemon
+ */
+typedef struct RatmanClient RatmanClient;
+
+/**
+ * An opaque message received from the daemon
+ */
+typedef struct RatmanMessage RatmanMessage;
+
+/**
+ * Get a description of the last error on this thread
+ *
+ * Returns null if no error occured.  The string remains valid until
+ * the next failing call on this thread.
+ */
+const char *ratman_last_error(void);
+
+/**
+ * Connect to the daemon listening on `socket_addr`
+ *
+ * If `addr` is null a new address is registered for this client.
+ * Returns null on failure.  Free the client with
+ * `ratman_client_free`.
+ *
+ * # Safety
+ *
+ * `socket_addr` must be a valid C string and `addr` must either be
+ * null or point to `RATMAN_ID_LEN` bytes.
+ */
+struct RatmanClient *ratman_connect(const char *socket_addr, const uint8_t *addr);
+
+/**
+ * Disconnect from the daemon and free the client
+ *
+ * # Safety
+ *
+ * `client` must be null or a client returned by `ratman_connect`
+ * which hasn't been freed yet.
+ */
+void ratman_client_free(struct RatmanClient *client);
+
+/**
+ * Write the address of this client to `out`
+ *
+ * # Safety
+ *
+ * `client` must be a valid client and `out` must point to
+ * `RATMAN_ID_LEN` writable bytes.
+ */
+void ratman_address(const struct RatmanClient *client, uint8_t *out);
+
+/**
+ * Send a payload of `len` bytes to a remote address
+ *
+ * # Safety
+ *
+ * `client` must be a valid client, `recipient` must point to
+ * `RATMAN_ID_LEN` bytes, and `payload` to `len` bytes.
+ */
+int ratman_send_to(const struct RatmanClient *client,
+                   const uint8_t *recipient,
+                   const uint8_t *payload,
+                   uintptr_t len);
+
+/**
+ * Send a payload of `len` bytes to all reachable peers
+ *
+ * # Safety
+ *
+ * `client` must be a valid client and `payload` must point to `len`
+ * bytes.
+ */
+int ratman_flood(const struct RatmanClient *client, const uint8_t *payload, uintptr_t len);
+
+/**
+ * Wait for the next message sent to this client
+ *
+ * Returns null if the connection to the daemon was lost.  Free the
+ * message with `ratman_message_free`.
+ *
+ * # Safety
+ *
+ * `client` must be a valid client.
+ */
+struct RatmanMessage *ratman_next(const struct RatmanClient *client);
+
+/**
+ * Write the sender address of a message to `out`
+ *
+ * # Safety
+ *
+ * `msg` must be a valid message and `out` must point to
+ * `RATMAN_ID_LEN` writable bytes.
+ */
+void ratman_message_sender(const struct RatmanMessage *msg, uint8_t *out);
+
+/**
+ * Get the payload of a message and write its length to `len`
+ *
+ * The payload remains valid until the message is freed.
+ *
+ * # Safety
+ *
+ * `msg` must be a valid message and `len` must be a valid pointer
+ * to a length.
+ */
+const uint8_t *ratman_message_payload(const struct RatmanMessage *msg, uintptr_t *len);
+
+/**
+ * Free a message returned by `ratman_next`
+ *
+ * # Safety
+ *
+ * `msg` must be null or a message which hasn't been freed yet.
+ */
+void ratman_message_free(struct RatmanMessage *msg);
+
+/**
+ * Wait for the next discovered address and write it to `out`
+ *
+ * # Safety
+ *
+ * `client` must be a valid client and `out` must point to
+ * `RATMAN_ID_LEN` writable bytes.
+ */
+int ratman_discover(const struct RatmanClient *client, uint8_t *out);
+
+/**
+ * Write up to `max` known peer addresses to `out`
+ *
+ * Returns the total number of known peers, which may be larger than
+ * `max`, or `-1` on failure.
+ *
+ * # Safety
+ *
+ * `client` must be a valid client and `out` must point to
+ * `max * RATMAN_ID_LEN` writable bytes.
+ */
+intptr_t ratman_get_peers(const struct RatmanClient *client, uint8_t *out, uintptr_t max);
+
+#endif /* RATMAN_CLIENT_H */
@@ -0,0 +1,86 @@
+//! A blocking client API for applications which don't use async Rust
+
+use crate::{rt::block_on, RatmanIpc};
+use types::{api::Receive_Type, message::Message, Identity, Result};
+
+/// A blocking IPC handle for a particular address
+///
+/// This type wraps a [`RatmanIpc`] and offers the same operations
+/// without requiring an async runtime in the calling application.
+/// Every call blocks the current thread until it has completed.  It
+/// can be cloned safely, for example to receive messages on a
+/// separate thread.
+///
+/// With the `tokio` feature these calls run on an internal tokio
+/// runtime, and must not be made from within another tokio runtime.
+#[derive(Clone)]
+pub struct BlockingIpc {
+    inner: RatmanIpc,
+}
+
+impl BlockingIpc {
+    /// Connect to a Ratman IPC backend with an optional address
+    ///
+    /// See [`RatmanIpc::connect`] for details.
+    pub fn connect(socket_addr: &str, addr: Option<Identity>) -> Result<Self> {
+        block_on(RatmanIpc::connect(socket_addr, addr)).map(Into::into)
+    }
+
+    /// Connect to a Ratman IPC backend without checking its version
+    pub fn connect_any_version(socket_addr: &str, addr: Option<Identity>) -> Result<Self> {
+        block_on(RatmanIpc::connect_any_version(socket_addr, addr)).map(Into::into)
+    }
+
+    /// Connect to the daemon without providing or wanting an address
+    pub fn anonymous(socket_addr: &str) -> Result<Self> {
+        block_on(RatmanIpc::anonymous(socket_addr)).map(Into::into)
+    }
+
+    /// Return the currently assigned address
+    pub fn address(&self) -> Identity {
+        self.inner.address()
+    }
+
+    /// Send some data to a remote peer
+    pub fn send_to(&self, recipient: Identity, payload: Vec<u8>) -> Result<()> {
+        block_on(self.inner.send_to(recipient, payload))
+    }
+
+    /// Send some data to all reachable peers
+    pub fn flood(&self, payload: Vec<u8>) -> Result<()> {
+        block_on(self.inner.flood(payload))
+    }
+
+    /// Wait for the next message sent to this address
+    pub fn next(&self) -> Option<(Receive_Type, Message)> {
+        block_on(self.inner.next())
+    }
+
+    /// Wait for the next address discovery event
+    pub fn discover(&self) -> Option<Identity> {
+        block_on(self.inner.discover())
+    }
+
+    /// Get all currently known peers for this router
+    pub fn get_peers(&self) -> Result<Vec<Identity>> {
+        block_on(self.inner.get_peers())
+    }
+
+    /// Close the connection to the daemon
+    ///
+    /// See [`RatmanIpc::disconnect`] for details.
+    pub fn disconnect(&self) {
+        block_on(self.inner.disconnect())
+    }
+
+    /// Get the underlying async handle
+    pub fn as_async(&self) -> &RatmanIpc {
+        &self.inner
+    }
+}
+
+impl From<RatmanIpc> for BlockingIpc {
+    fn from(inner: RatmanIpc) -> Self {
+        Self { inner }
+    }
+}
@@ -0,0 +1,338 @@
+//! C bindings for the blocking client API
+//!
+//! These functions are only available with the `ffi` feature.  Their
+//! C declarations are in `include/ratman_client.h`, which is generated
+//! by running `cbindgen` in the crate directory.  To build a shared or
+//! static library, select the crate type explicitly:
+//!
+//! ```text
+//! cargo rustc -p ratman-client --features ffi --crate-type cdylib
+//! ```
+//!
+//! Functions returning an `int` return `0` on success and `-1` on
+//! failure.  A description of the last error on the calling thread
+//! can be retrieved with `ratman_last_error`.  Addresses are passed
+//! as buffers of `RATMAN_ID_LEN` bytes.
+
+use crate::BlockingIpc;
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int},
+    ptr, slice,
+};
+use types::{message::Message, Identity, Result};
+
+/// Length of an address in bytes
+pub const RATMAN_ID_LEN: usize = 32;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error<E: ToString>(e: E) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(e.to_string()).ok());
+}
+
+fn status(res: Result<()>) -> c_int {
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            set_error(e);
+            -1
+        }
+    }
+}
+
+unsafe fn read_id(buf: *const u8) -> Identity {
+    Identity::from_bytes(slice::from_raw_parts(buf, RATMAN_ID_LEN))
+}
+
+unsafe fn write_id(id: Identity, out: *mut u8) {
+    ptr::copy_nonoverlapping(id.as_bytes().as_ptr(), out, RATMAN_ID_LEN);
+}
+
+unsafe fn read_payload(payload: *const u8, len: usize) -> Vec<u8> {
+    match len {
+        0 => vec![],
+        _ => slice::from_raw_parts(payload, len).to_vec(),
+    }
+}
+
+/// An opaque connection to the daemon
+pub struct RatmanClient(BlockingIpc);
+
+/// An opaque message received from the daemon
+pub struct RatmanMessage(Message);
+
+/// Get a description of the last error on this thread
+///
+/// Returns null if no error occured.  The string remains valid until
+/// the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn ratman_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match *last.borrow() {
+        Some(ref e) => e.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Connect to the daemon listening on `socket_addr`
+///
+/// If `addr` is null a new address is registered for this client.
+/// Returns null on failure.  Free the client with
+/// `ratman_client_free`.
+///
+/// # Safety
+///
+/// `socket_addr` must be a valid C string and `addr` must either be
+/// null or point to `RATMAN_ID_LEN` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_connect(
+    socket_addr: *const c_char,
+    addr: *const u8,
+) -> *mut RatmanClient {
+    let socket_addr = match CStr::from_ptr(socket_addr).to_str() {
+        Ok(s) => s,
+        Err(e) => {
+            set_error(e);
+            return ptr::null_mut();
+        }
+    };
+
+    let addr = match addr.is_null() {
+        true => None,
+        false => Some(read_id(addr)),
+    };
+
+    match BlockingIpc::connect(socket_addr, addr) {
+        Ok(ipc) => Box::into_raw(Box::new(RatmanClient(ipc))),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Disconnect from the daemon and free the client
+///
+/// Messages which weren't read yet are dropped.
+///
+/// # Safety
+///
+/// `client` must be null or a client returned by `ratman_connect`
+/// which hasn't been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_client_free(client: *mut RatmanClient) {
+    if !client.is_null() {
+        let client = Box::from_raw(client);
+        client.0.disconnect();
+    }
+}
+
+/// Write the address of this client to `out`
+///
+/// # Safety
+///
+/// `client` must be a valid client and `out` must point to
+/// `RATMAN_ID_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_address(client: *const RatmanClient, out: *mut u8) {
+    write_id((*client).0.address(), out);
+}
+
+/// Send a payload of `len` bytes to a remote address
+///
+/// # Safety
+///
+/// `client` must be a valid client, `recipient` must point to
+/// `RATMAN_ID_LEN` bytes, and `payload` to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_send_to(
+    client: *const RatmanClient,
+    recipient: *const u8,
+    payload: *const u8,
+    len: usize,
+) -> c_int {
+    let payload = read_payload(payload, len);
+    status((*client).0.send_to(read_id(recipient), payload))
+}
+
+/// Send a payload of `len` bytes to all reachable peers
+///
+/// # Safety
+///
+/// `client` must be a valid client and `payload` must point to `len`
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_flood(
+    client: *const RatmanClient,
+    payload: *const u8,
+    len: usize,
+) -> c_int {
+    status((*client).0.flood(read_payload(payload, len)))
+}
+
+/// Wait for the next message sent to this client
+///
+/// Returns null if the connection to the daemon was lost.  Free the
+/// message with `ratman_message_free`.
+///
+/// # Safety
+///
+/// `client` must be a valid client.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_next(client: *const RatmanClient) -> *mut RatmanMessage {
+    match (*client).0.next() {
+        Some((_, msg)) => Box::into_raw(Box::new(RatmanMessage(msg))),
+        None => {
+            set_error("connection to the daemon was lost");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Write the sender address of a message to `out`
+///
+/// # Safety
+///
+/// `msg` must be a valid message and `out` must point to
+/// `RATMAN_ID_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_message_sender(msg: *const RatmanMessage, out: *mut u8) {
+    write_id(Identity::from_bytes((*msg).0.get_sender()), out);
+}
+
+/// Get the payload of a message and write its length to `len`
+///
+/// The payload remains valid until the message is freed.
+///
+/// # Safety
+///
+/// `msg` must be a valid message and `len` must be a valid pointer
+/// to a length.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_message_payload(
+    msg: *const RatmanMessage,
+    len: *mut usize,
+) -> *const u8 {
+    let payload = (*msg).0.get_payload();
+    *len = payload.len();
+    payload.as_ptr()
+}
+
+/// Free a message returned by `ratman_next`
+///
+/// # Safety
+///
+/// `msg` must be null or a message which hasn't been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_message_free(msg: *mut RatmanMessage) {
+    if !msg.is_null() {
+        drop(Box::from_raw(msg));
+    }
+}
+
+/// Wait for the next discovered address and write it to `out`
+///
+/// # Safety
+///
+/// `client` must be a valid client and `out` must point to
+/// `RATMAN_ID_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_discover(client: *const RatmanClient, out: *mut u8) -> c_int {
+    match (*client).0.discover() {
+        Some(id) => {
+            write_id(id, out);
+            0
+        }
+        None => {
+            set_error("connection to the daemon was lost");
+            -1
+        }
+    }
+}
+
+/// Write up to `max` known peer addresses to `out`
+///
+/// Returns the total number of known peers, which may be larger than
+/// `max`, or `-1` on failure.
+///
+/// # Safety
+///
+/// `client` must be a valid client and `out` must point to
+/// `max * RATMAN_ID_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ratman_get_peers(
+    client: *const RatmanClient,
+    out: *mut u8,
+    max: usize,
+) -> isize {
+    match (*client).0.get_peers() {
+        Ok(peers) => {
+            for (i, id) in peers.iter().take(max).enumerate() {
+                write_id(*id, out.add(i * RATMAN_ID_LEN));
+            }
+            peers.len() as isize
+        }
+        Err(e) => {
+            set_error(e);
+            -1
+        }
+    }
+}
+
+/// Make sure that a client can be driven through the C functions, and
+/// that freeing it closes the connection
+#[test]
+fn connect_send_receive_free() {
+    use crate::{accept_client, fake_daemon, write_msg};
+    use async_std::task;
+    use futures::io::AsyncReadExt;
+    use types::{api, message, parse_message};
+
+    let (listener, socket_addr) = task::block_on(fake_daemon());
+    let (addr, peer) = (Identity::random(), Identity::random());
+
+    let daemon = task::spawn(async move {
+        let (mut stream, _) = accept_client(&listener, addr, &[api::features::ERRORS]).await;
+        let req = parse_message(&mut stream).await.unwrap();
+        let send = req.get_send().get_msg();
+        assert_eq!(send.get_recipients(), &[addr.as_bytes().to_vec()]);
+        assert_eq!(send.get_payload(), &[1, 3, 1, 2]);
+        write_msg(&mut stream, api::with_id(api::api_ack(), req.id)).await;
+
+        let msg = message::new(peer, vec![addr], vec![4, 2], vec![]);
+        write_msg(&mut stream, api::api_recv(api::receive_default(msg))).await;
+
+        // The client closes the connection when it is freed
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    });
+
+    unsafe {
+        let socket_addr = CString::new(socket_addr).unwrap();
+        let client = ratman_connect(socket_addr.as_ptr(), ptr::null());
+        assert!(!client.is_null());
+
+        let mut id = [0; RATMAN_ID_LEN];
+        ratman_address(client, id.as_mut_ptr());
+        assert_eq!(&id, addr.as_bytes());
+
+        let payload = [1, 3, 1, 2];
+        let sent = ratman_send_to(client, id.as_ptr(), payload.as_ptr(), payload.len());
+        assert_eq!(sent, 0);
+
+        let msg = ratman_next(client);
+        assert!(!msg.is_null());
+        ratman_message_sender(msg, id.as_mut_ptr());
+        assert_eq!(&id, peer.as_bytes());
+        let mut len = 0;
+        let data = ratman_message_payload(msg, &mut len);
+        assert_eq!(slice::from_raw_parts(data, len), &[4, 2]);
+        ratman_message_free(msg);
+
+        ratman_client_free(client);
+    }
+    task::block_on(daemon);
+}
@@ -25,25 +25,139 @@
 //!
 //! Also: by default this library will refuse to connect to a running
 //! `ratmand` that does not match the libraries version number.  This
-//! behaviour can be disabled via the `RatmanIpc` API.
+//! behaviour can be disabled via the `RatmanIpc` API, by calling
+//! `connect_any_version` or `anonymous_any_version` instead.
+//!
+//! Applications which don't use async Rust can use the
+//! [`BlockingIpc`] wrapper instead.  With the `ffi` feature enabled,
+//! the same operations are also available to C programs via the
+//! functions in the `ffi` module.
+//!
+//! ## Runtimes
+//!
+//! By default the client uses async-std for networking and to spawn
+//! its background task.  Enable the `tokio` feature (and disable the
+//! default features) to use it inside a tokio runtime instead.
+//!
+//! ## Reconnecting
+//!
+//! When the connection to the daemon is lost the client reconnects
+//! in the background, using the same address.  Requests which were
+//! waiting for a response fail with `Error::ConnectionLost`.  Only
+//! when all reconnection attempts fail does `next()` return `None`
+//! and the [`Messages`] stream end.  Call `RatmanIpc::disconnect` to
+//! close the connection without reconnecting.
 
 #[macro_use]
 extern crate tracing;
 
-use async_std::{
-    channel::{unbounded, Receiver, Sender},
-    net::TcpStream,
-    task,
+mod blocking;
+mod rt;
+mod sink;
+mod stream;
+pub use blocking::BlockingIpc;
+pub use sink::{Discoveries, Messages, SendSink};
+pub use stream::IncomingStream;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+use async_channel::{bounded, Receiver, Sender, TrySendError};
+use futures::{
+    io::{AsyncRead as Read, AsyncReadExt, AsyncWrite, AsyncWriteExt, Cursor, ReadHalf, WriteHalf},
+    lock::Mutex,
+};
+use futures_lite::future;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
-pub use types::{api::Receive_Type, message::Message, Error, Identity, Result};
 use types::{
     api::{
-        self, ApiMessageEnum,
+        self, ApiMessage, ApiMessageEnum, Manage,
         Peers_Type::{DISCOVER, RESP},
+        Send, Setup,
         Setup_Type::ACK,
     },
     encode_message, message, parse_message, read_with_length, write_with_length,
 };
+pub use types::{
+    api::{QueuePolicy, Receive_Type, CONTROL_TOKEN_FILE},
+    message::Message,
+    Error, Identity, Result, MAX_PAYLOAD_LEN,
+};
+
+#[cfg(test)]
+use async_std::task;
+
+/// A byte stream connecting a client to the daemon
+///
+/// This is implemented for all bidirectional streams, and allows
+/// clients to talk to a daemon via something other than TCP.  See
+/// [`RatmanIpc::connect_with`].
+pub trait Transport: Read + AsyncWrite + std::marker::Send + Unpin + 'static {}
+
+impl<T> Transport for T where T: Read + AsyncWrite + std::marker::Send + Unpin + 'static {}
+
+type Socket = Box<dyn Transport>;
+
+type ConnectFuture = Pin<Box<dyn Future<Output = io::Result<Socket>> + std::marker::Send>>;
+
+/// Establish a new connection to the daemon
+type Connector = Arc<dyn Fn() -> ConnectFuture + std::marker::Send + Sync>;
+
+/// Keep track of requests that are still waiting for a response
+///
+/// Each request is assigned a unique ID which the daemon copies into
+/// its response.  The receive loop then uses this ID to hand the
+/// response to the waiting caller, instead of treating it like any
+/// other incoming message.
+#[derive(Default)]
+struct Requests {
+    ctr: AtomicU64,
+    pending: Mutex<BTreeMap<u64, Sender<ApiMessage>>>,
+}
+
+impl Requests {
+    /// Register a new request and return its ID and response channel
+    async fn register(&self) -> (u64, Receiver<ApiMessage>) {
+        // Request IDs start at 1 because 0 marks unsolicited messages
+        let id = self.ctr.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = bounded(1);
+        self.pending.lock().await.insert(id, tx);
+        (id, rx)
+    }
+
+    /// Hand a response to its waiting request
+    ///
+    /// Returns the message again if it isn't a response to a request
+    async fn respond(&self, msg: ApiMessage) -> Option<ApiMessage> {
+        if msg.id == 0 {
+            return Some(msg);
+        }
+
+        match self.pending.lock().await.remove(&msg.id) {
+            Some(tx) => {
+                let _ = tx.send(msg).await;
+            }
+            None => warn!("Received response for unknown request {}", msg.id),
+        }
+
+        None
+    }
+
+    /// Drop all pending requests, failing any waiting callers
+    async fn close(&self) {
+        self.pending.lock().await.clear();
+    }
+}
 
 /// An IPC handle for a particular address
 ///
@@ -52,10 +166,21 @@ use types::{
 /// maintain many of these connections at the same time.
 #[derive(Clone)]
 pub struct RatmanIpc {
-    socket: TcpStream,
+    /// The current connection, which is replaced when reconnecting,
+    /// or `None` after disconnecting.  Held while writing a frame to
+    /// the socket.
+    socket: Arc<Mutex<Option<WriteHalf<Socket>>>>,
     addr: Identity,
     recv: Receiver<(Receive_Type, Message)>,
     disc: Receiver<Identity>,
+    streams: Receiver<IncomingStream>,
+    reqs: Arc<Requests>,
+    /// Closed to stop the receive loop
+    stop: Sender<()>,
+    version: String,
+    features: Vec<String>,
+    queue_policy: QueuePolicy,
+    queue_size: u32,
 }
 
 impl RatmanIpc {
@@ -73,63 +198,183 @@ impl RatmanIpc {
     /// `socket_addr` refers to the local address the Ratman daemon is
     /// listening on.  `addr` refers to the Ratman cryptographic
     /// routing address associated with your application
+    ///
+    /// This function will refuse to connect to a daemon with an
+    /// incompatible version.  Use `connect_any_version` to skip this
+    /// check.
     pub async fn connect(socket_addr: &str, addr: Option<Identity>) -> Result<RatmanIpc> {
-        let mut socket = TcpStream::connect(socket_addr).await?;
+        Self::connect_inner(tcp(socket_addr), addr, true).await
+    }
+
+    /// Connect to a Ratman IPC backend without checking its version
+    ///
+    /// This behaves like `connect`, but will also connect to a
+    /// `ratmand` which doesn't match the version of this library.
+    pub async fn connect_any_version(
+        socket_addr: &str,
+        addr: Option<Identity>,
+    ) -> Result<RatmanIpc> {
+        Self::connect_inner(tcp(socket_addr), addr, false).await
+    }
+
+    /// Connect to a Ratman IPC backend via a custom transport
+    ///
+    /// `connect` is called to establish the connection, and again
+    /// whenever the client needs to reconnect.  Otherwise this
+    /// behaves like `connect`.
+    pub async fn connect_with<F, Fut, T>(connect: F, addr: Option<Identity>) -> Result<RatmanIpc>
+    where
+        F: Fn() -> Fut + std::marker::Send + Sync + 'static,
+        Fut: Future<Output = io::Result<T>> + std::marker::Send + 'static,
+        T: Transport,
+    {
+        Self::connect_inner(connector(connect), addr, true).await
+    }
 
+    async fn connect_inner(
+        connect: Connector,
+        addr: Option<Identity>,
+        check: bool,
+    ) -> Result<RatmanIpc> {
         // Introduce ourselves to the daemon
-        let online_msg = api::api_setup(match addr {
+        let online_msg = match addr {
             Some(addr) => api::online(addr, vec![]),
             None => api::online_init(),
-        });
+        };
         info!("Sending introduction message!");
-        write_with_length(&mut socket, &encode_message(online_msg)?).await?;
-
-        trace!("Waiting for ACK message!");
-        // Then wait for a response and assign the used address
-        let addr = match parse_message(&mut socket).await.map(|m| m.inner) {
-            Ok(Some(one_of)) => match one_of {
-                ApiMessageEnum::setup(s) if s.field_type == ACK => s
-                    ._id
-                    .as_ref()
-                    .map(|_| Identity::from_bytes(s.get_id()))
-                    .or(addr)
-                    .expect("failed to initialise new address!"),
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
+        let mut session = Session {
+            connect,
+            setup: online_msg,
+            check,
         };
+        let (socket, ack) = session.connect().await?;
 
-        debug!("IPC client initialisation done!");
+        // Then assign the used address
+        let addr = ack
+            ._id
+            .as_ref()
+            .map(|_| Identity::from_bytes(ack.get_id()))
+            .or(addr)
+            .expect("failed to initialise new address!");
 
-        // TODO: spawn receive daemon here
-        let (tx, recv) = unbounded();
-        let (dtx, disc) = unbounded();
-        task::spawn(run_receive(socket.clone(), tx, dtx));
+        // Reconnect with the same address and token
+        session.setup = api::online(addr, ack.get_token().to_vec());
 
-        Ok(Self {
-            socket,
-            addr,
-            recv,
-            disc,
-        })
+        debug!("IPC client initialisation done!");
+        Ok(Self::spawn(socket, addr, ack, session))
     }
 
     /// Connect to the daemon without providing or wanting an address
     pub async fn anonymous(socket_addr: &str) -> Result<Self> {
-        let mut socket = TcpStream::connect(socket_addr).await?;
+        Self::anonymous_inner(tcp(socket_addr), api::anonymous(), true).await
+    }
+
+    /// Connect to the daemon anonymously without checking its version
+    pub async fn anonymous_any_version(socket_addr: &str) -> Result<Self> {
+        Self::anonymous_inner(tcp(socket_addr), api::anonymous(), false).await
+    }
+
+    /// Connect to the daemon anonymously, in order to manage it
+    ///
+    /// `token` is the daemon's control token, which `ratmand` writes
+    /// to the file [`CONTROL_TOKEN_FILE`] in its data directory.  Only
+    /// clients connected this way may change the daemon's
+    /// configuration, for example via `add_peers`.
+    pub async fn control(socket_addr: &str, token: Vec<u8>) -> Result<Self> {
+        Self::anonymous_inner(tcp(socket_addr), api::control(token), true).await
+    }
 
-        let introduction = api::api_setup(api::anonymous());
-        write_with_length(&mut socket, &encode_message(introduction)?).await?;
+    /// Connect to the daemon via a custom transport, in order to
+    /// manage it
+    ///
+    /// See `connect_with` and `control`.
+    pub async fn control_with<F, Fut, T>(connect: F, token: Vec<u8>) -> Result<Self>
+    where
+        F: Fn() -> Fut + std::marker::Send + Sync + 'static,
+        Fut: Future<Output = io::Result<T>> + std::marker::Send + 'static,
+        T: Transport,
+    {
+        Self::anonymous_inner(connector(connect), api::control(token), true).await
+    }
+
+    async fn anonymous_inner(connect: Connector, setup: Setup, check: bool) -> Result<Self> {
+        let session = Session {
+            connect,
+            setup,
+            check,
+        };
+        let (socket, ack) = session.connect().await?;
 
         let addr = Identity::random(); // Never used
-        let (_, recv) = unbounded(); // Never used
-        let (_, disc) = unbounded(); // Never used
-        Ok(Self {
-            socket,
+        Ok(Self::spawn(socket, addr, ack, session))
+    }
+
+    /// Spawn the receive loop for an initialised connection
+    ///
+    /// Incoming messages are buffered in a bounded queue.  When it is
+    /// full the oldest message is dropped, because the receive loop
+    /// also delivers responses to requests and must never wait for
+    /// the application to poll its messages.
+    fn spawn(socket: Socket, addr: Identity, ack: Setup, session: Session) -> Self {
+        let (tx, recv) = bounded(QUEUE_SIZE);
+        let (dtx, disc) = bounded(QUEUE_SIZE);
+        let (stx, streams) = bounded(STREAM_QUEUE_SIZE);
+        let reqs = Arc::new(Requests::default());
+        let (stop, stopped) = bounded(1);
+        let (reader, writer) = socket.split();
+        let shared = Arc::new(Mutex::new(Some(writer)));
+        rt::spawn(run_receive(
+            reader,
+            Arc::clone(&shared),
+            session,
+            Channels {
+                tx,
+                rx: recv.clone(),
+                dtx,
+                stx,
+            },
+            Arc::clone(&reqs),
+            stopped,
+        ));
+
+        Self {
+            socket: shared,
             addr,
             recv,
             disc,
-        })
+            streams,
+            reqs,
+            stop,
+            queue_policy: ack.queue_policy,
+            queue_size: ack.queue_size,
+            version: ack.version,
+            features: ack.features.into_vec(),
+        }
+    }
+
+    /// Return the version of the connected daemon
+    pub fn daemon_version(&self) -> &str {
+        &self.version
+    }
+
+    /// Check whether the connected daemon supports a protocol feature
+    ///
+    /// Available feature names are listed in
+    /// [`features`](types::api::features).
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Return what the daemon does when the queue for this client is full
+    pub fn queue_policy(&self) -> QueuePolicy {
+        self.queue_policy
+    }
+
+    /// Return the number of messages the daemon buffers for this client
+    ///
+    /// Daemons that don't report their queue configuration return `0`.
+    pub fn queue_size(&self) -> u32 {
+        self.queue_size
     }
 
     /// Return the currently assigned address
@@ -138,69 +383,446 @@ impl RatmanIpc {
     }
 
     /// Send some data to a remote peer
+    ///
+    /// Payloads larger than [`MAX_PAYLOAD_LEN`] are streamed to the
+    /// daemon if it supports this.
     pub async fn send_to(&self, recipient: Identity, payload: Vec<u8>) -> Result<()> {
-        let msg = api::api_send(api::send_default(message::new(
+        self.send(api::send_default(message::new(
             self.addr,
             vec![recipient], // recipient
             payload,
             vec![], // signature
-        )));
-
-        write_with_length(&mut self.socket.clone(), &encode_message(msg)?).await?;
-        Ok(())
+        )))
+        .await
     }
 
     /// Send some data to a remote peer
     pub async fn flood(&self, payload: Vec<u8>) -> Result<()> {
-        let msg = api::api_send(api::send_flood(message::new(
+        self.send(api::send_flood(message::new(
             self.addr,
             vec![], // recipient
             payload,
             vec![], // signature
-        )));
+        )))
+        .await
+    }
 
-        write_with_length(&mut self.socket.clone(), &encode_message(msg)?).await?;
-        Ok(())
+    /// Stream a payload from a reader to a remote peer
+    ///
+    /// The payload is read and sent in chunks, so it doesn't need to
+    /// fit into memory.  The daemon needs to support the
+    /// [`STREAMS`](types::api::features::STREAMS) feature.
+    pub async fn send_stream<R: Read + Unpin>(&self, recipient: Identity, reader: R) -> Result<()> {
+        let send = api::send_default(message::new(
+            self.addr,
+            vec![recipient], // recipient
+            vec![],          // payload
+            vec![],          // signature
+        ));
+        self.stream(send, reader).await
     }
 
     /// Receive a message sent to this address
+    ///
+    /// Messages with payloads larger than [`MAX_PAYLOAD_LEN`] are
+    /// returned by [`next_stream`](Self::next_stream) instead.
     pub async fn next(&self) -> Option<(Receive_Type, Message)> {
         self.recv.recv().await.ok()
     }
 
+    /// Receive a message with a streamed payload
+    pub async fn next_stream(&self) -> Option<IncomingStream> {
+        self.streams.recv().await.ok()
+    }
+
     /// Listen for the next address discovery event
     pub async fn discover(&self) -> Option<Identity> {
         self.disc.recv().await.ok()
     }
 
+    /// Get a [`Stream`](futures::Stream) of messages sent to this address
+    ///
+    /// Messages are shared between all streams and calls to
+    /// [`next`](Self::next) of the same connection.
+    pub fn messages(&self) -> Messages {
+        Messages(self.recv.clone())
+    }
+
+    /// Get a [`Stream`](futures::Stream) of address discovery events
+    pub fn discoveries(&self) -> Discoveries {
+        Discoveries(self.disc.clone())
+    }
+
+    /// Get a [`Sink`](futures::Sink) to send messages to remote peers
+    pub fn sink(&self) -> SendSink {
+        SendSink::new(self.clone())
+    }
+
     /// Get all currently known peers for this router
     pub async fn get_peers(&self) -> Result<Vec<Identity>> {
         let msg = api::api_peers(api::peers_req());
-        write_with_length(&mut self.socket.clone(), &encode_message(msg)?).await?;
-        
-        match parse_message(&mut self.socket.clone())
-            .await
-            .map(|m| m.inner)
-        {
-            Ok(Some(one_of)) => match one_of {
-                ApiMessageEnum::peers(s) if s.field_type == RESP => {
-                    Ok(s.peers.iter().map(|p| Identity::from_bytes(p)).collect())
-                }
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
+
+        match self.request(msg).await?.inner {
+            Some(ApiMessageEnum::peers(s)) if s.field_type == RESP => {
+                Ok(s.peers.iter().map(|p| Identity::from_bytes(p)).collect())
+            }
+            Some(ApiMessageEnum::error(e)) => Err(e.into()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Add peers to the daemon's drivers
+    ///
+    /// Peers are written in the same syntax as for `ratmand --peers`,
+    /// for example `inet#10.0.0.1:9000`.  Like all other management
+    /// commands this requires a connection made with `control`.
+    pub async fn add_peers(&self, peers: Vec<String>) -> Result<()> {
+        self.manage(api::add_peers(peers)).await
+    }
+
+    /// Remove peers from the daemon's drivers
+    pub async fn remove_peers(&self, peers: Vec<String>) -> Result<()> {
+        self.manage(api::remove_peers(peers)).await
+    }
+
+    /// Enable or disable one of the daemon's endpoints
+    ///
+    /// Endpoints are numbered in the order the daemon added them.
+    pub async fn set_endpoint_enabled(&self, id: u64, enabled: bool) -> Result<()> {
+        self.manage(api::set_endpoint(id, enabled)).await
+    }
+
+    /// Change the log level of the daemon
+    ///
+    /// Valid levels are `trace`, `debug`, `info`, `warn`, and `error`.
+    pub async fn set_log_level(&self, level: &str) -> Result<()> {
+        self.manage(api::log_level(level)).await
+    }
+
+    /// Close the connection to the daemon
+    ///
+    /// The receive loop stops without reconnecting, and requests which
+    /// are still waiting for a response fail.  Messages that were
+    /// already received can still be read, after which `next()`
+    /// returns `None`.  This affects all clones of this handle.
+    pub async fn disconnect(&self) {
+        self.stop.close();
+
+        // The connection is closed once the receive loop has dropped
+        // its half as well
+        if let Some(mut socket) = self.socket.lock().await.take() {
+            if let Err(e) = socket.close().await {
+                debug!("Failed to close connection: {}", e);
+            }
+        }
+    }
+
+    /// Send a management command and wait for it to be acknowledged
+    ///
+    /// The daemon only accepts these from clients connected via
+    /// `control`.
+    async fn manage(&self, m: Manage) -> Result<()> {
+        if !self.supports(api::features::MANAGE) {
+            return Err(Error::Unsupported(api::features::MANAGE));
+        }
+
+        into_ack(self.request(api::api_manage(m)).await?)
+    }
+
+    /// Send a message, streaming its payload if it is too large
+    async fn send(&self, mut send: Send) -> Result<()> {
+        let len = send.get_msg().get_payload().len();
+        if len > MAX_PAYLOAD_LEN && self.supports(api::features::STREAMS) {
+            let payload = send.mut_msg().take_payload();
+            return self.stream(send, Cursor::new(payload)).await;
+        }
+
+        self.send_request(api::api_send(send)).await
+    }
+
+    /// Send a message and stream its payload from a reader
+    async fn stream<R: Read + Unpin>(&self, mut send: Send, mut reader: R) -> Result<()> {
+        if !self.supports(api::features::STREAMS) {
+            return Err(Error::Unsupported(api::features::STREAMS));
+        }
+
+        // The request ID is unique for this connection, so it can
+        // double as the stream ID
+        let (id, rx) = self.reqs.register().await;
+        send.set_stream(id);
+        self.write(api::with_id(api::api_send(send), id)).await?;
+
+        let mut seq = 0;
+        loop {
+            let data = stream::read_chunk(&mut reader).await?;
+            let last = data.len() < MAX_PAYLOAD_LEN;
+            self.write(api::api_chunk(api::chunk(id, seq, data, last)))
+                .await?;
+
+            if last {
+                break;
+            }
+            seq += 1;
         }
+
+        into_ack(rx.recv().await.map_err(|_| Error::ConnectionLost)?)
+    }
+
+    /// Send a message to the daemon
+    ///
+    /// If the daemon reports errors for failed requests, wait for it
+    /// to acknowledge the message.  Older daemons don't respond at
+    /// all, so the message is only written to the socket.
+    async fn send_request(&self, msg: ApiMessage) -> Result<()> {
+        if !self.supports(api::features::ERRORS) {
+            return self.write(msg).await;
+        }
+
+        into_ack(self.request(msg).await?)
+    }
+
+    /// Write a single message to the daemon
+    async fn write(&self, msg: ApiMessage) -> Result<()> {
+        let buf = encode_message(msg)?;
+        let mut socket = self.socket.lock().await;
+        let socket = socket.as_mut().ok_or(Error::ConnectionLost)?;
+        write_with_length(socket, &buf).await?;
+        Ok(())
+    }
+
+    /// Send a request to the daemon and wait for its response
+    ///
+    /// Responses are matched to this request via its request ID, so
+    /// it's safe to call this function while messages are being
+    /// received on the same connection.
+    async fn request(&self, msg: ApiMessage) -> Result<ApiMessage> {
+        let (id, rx) = self.reqs.register().await;
+        self.write(api::with_id(msg, id)).await?;
+
+        rx.recv().await.map_err(|_| Error::ConnectionLost)
     }
 }
 
-async fn run_receive(
-    mut socket: TcpStream,
+/// Turn a response into the result of the request
+fn into_ack(msg: ApiMessage) -> Result<()> {
+    match msg.inner {
+        Some(ApiMessageEnum::ack(_)) => Ok(()),
+        Some(ApiMessageEnum::error(e)) => Err(e.into()),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+/// The number of incoming messages and discovery events buffered
+const QUEUE_SIZE: usize = 256;
+
+/// The number of incoming streams buffered
+const STREAM_QUEUE_SIZE: usize = 4;
+
+/// The number of chunks buffered for each incoming stream
+///
+/// Once this many chunks are waiting, the client stops reading from
+/// the connection until the stream's reader catches up.
+const STREAM_CHUNKS: usize = 4;
+
+/// The version of this library, sent to the daemon during the handshake
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Check whether a daemon version is compatible with this library
+///
+/// Because the MAJOR and MINOR version of this library follow a
+/// particular Ratman release, only the PATCH version may differ.
+fn compatible(daemon: &str) -> bool {
+    fn minor(v: &str) -> Vec<&str> {
+        v.splitn(3, '.').take(2).collect()
+    }
+
+    let theirs = minor(daemon);
+    theirs.len() == 2 && theirs == minor(VERSION)
+}
+
+/// Send a setup message and wait for the daemon to acknowledge it
+async fn handshake(socket: &mut Socket, setup: Setup, check: bool) -> Result<Setup> {
+    let setup = api::with_version(setup, VERSION, &[api::features::STREAMS]);
+    write_with_length(socket, &encode_message(api::api_setup(setup))?).await?;
+
+    trace!("Waiting for ACK message!");
+    let ack = match parse_message(socket).await.map(|m| m.inner) {
+        Ok(Some(one_of)) => match one_of {
+            ApiMessageEnum::setup(s) if s.field_type == ACK => s,
+            ApiMessageEnum::error(e) => return Err(e.into()),
+            _ => return Err(Error::UnexpectedResponse),
+        },
+        Ok(None) => return Err(Error::UnexpectedResponse),
+        Err(e) => return Err(e),
+    };
+
+    debug!("Connected to ratmand version {}", ack.get_version());
+    if check && !compatible(ack.get_version()) {
+        return Err(Error::IncompatibleVersion(
+            ack.get_version().to_owned(),
+            VERSION.to_owned(),
+        ));
+    }
+
+    Ok(ack)
+}
+
+/// Connect to a daemon listening on a TCP socket
+fn tcp(socket_addr: &str) -> Connector {
+    let socket_addr = socket_addr.to_owned();
+    Arc::new(move || {
+        let socket_addr = socket_addr.clone();
+        Box::pin(async move { Ok(Box::new(rt::connect(&socket_addr).await?) as Socket) })
+    })
+}
+
+/// Connect to a daemon via a custom transport
+fn connector<F, Fut, T>(connect: F) -> Connector
+where
+    F: Fn() -> Fut + std::marker::Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + std::marker::Send + 'static,
+    T: Transport,
+{
+    Arc::new(move || {
+        let fut = connect();
+        Box::pin(async move { Ok(Box::new(fut.await?) as Socket) })
+    })
+}
+
+/// Everything needed to (re-)establish a connection to the daemon
+struct Session {
+    connect: Connector,
+    /// The setup message sent during the handshake
+    setup: Setup,
+    check: bool,
+}
+
+impl Session {
+    async fn connect(&self) -> Result<(Socket, Setup)> {
+        let mut socket = (self.connect)().await?;
+        let ack = handshake(&mut socket, self.setup.clone(), self.check).await?;
+        Ok((socket, ack))
+    }
+
+    /// Try to reconnect to the daemon, backing off between attempts
+    async fn reconnect(&self) -> Option<Socket> {
+        let mut delay = RECONNECT_DELAY;
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            rt::sleep(delay).await;
+            info!("Reconnecting to ratmand (attempt {})", attempt);
+            match self.connect().await {
+                Ok((socket, _)) => return Some(socket),
+                Err(e) => warn!("Failed to reconnect: {}", e),
+            }
+            delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+        }
+
+        None
+    }
+}
+
+/// The number of times the client tries to reconnect to the daemon
+const RECONNECT_ATTEMPTS: u32 = 10;
+
+/// The delay before the first reconnection attempt, which is doubled
+/// after every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(5);
+
+/// Channels to hand incoming messages to the `RatmanIpc` handles
+struct Channels {
     tx: Sender<(Receive_Type, Message)>,
+    /// Used to drop the oldest message when the queue is full
+    rx: Receiver<(Receive_Type, Message)>,
     dtx: Sender<Identity>,
+    stx: Sender<IncomingStream>,
+}
+
+impl Channels {
+    /// Queue an incoming message, dropping the oldest one if the
+    /// queue is full
+    fn deliver(&self, mut item: (Receive_Type, Message)) {
+        loop {
+            match self.tx.try_send(item) {
+                Ok(_) => break,
+                Err(TrySendError::Full(i)) => {
+                    warn!("Receive queue is full; dropping oldest message");
+                    let _ = self.rx.try_recv();
+                    item = i;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!("Failed to forward received message: queue closed");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Receive messages from the daemon, reconnecting when the
+/// connection is lost
+///
+/// Stops when `stopped` is closed, which happens when the client
+/// disconnects or all of its handles were dropped.
+async fn run_receive(
+    mut reader: ReadHalf<Socket>,
+    shared: Arc<Mutex<Option<WriteHalf<Socket>>>>,
+    session: Session,
+    channels: Channels,
+    reqs: Arc<Requests>,
+    stopped: Receiver<()>,
 ) {
+    loop {
+        future::or(receive(&mut reader, &channels, &reqs), async {
+            let _ = stopped.recv().await;
+        })
+        .await;
+
+        // Responses for the old connection will never arrive
+        reqs.close().await;
+
+        // Nobody is listening anymore if all handles were dropped,
+        // leaving only the receiver held by `channels`
+        if stopped.is_closed() || channels.tx.receiver_count() == 1 {
+            break;
+        }
+
+        let reconnect = future::or(session.reconnect(), async {
+            let _ = stopped.recv().await;
+            None
+        });
+        match reconnect.await {
+            Some(new) => {
+                // Don't replace a connection the client just closed
+                let mut socket = shared.lock().await;
+                if stopped.is_closed() {
+                    break;
+                }
+
+                info!("Reconnected to ratmand");
+                let (r, w) = new.split();
+                *socket = Some(w);
+                reader = r;
+            }
+            None if stopped.is_closed() => break,
+            None => {
+                error!("Failed to reconnect to ratmand; giving up");
+                break;
+            }
+        }
+    }
+}
+
+/// Receive messages until the connection fails
+async fn receive(socket: &mut ReadHalf<Socket>, channels: &Channels, reqs: &Requests) {
+    let Channels { dtx, stx, .. } = channels;
+
+    // Incoming streams which are still waiting for chunks
+    let mut streams = BTreeMap::new();
+
     loop {
         trace!("Reading message from stream...");
-        let msg = match read_with_length(&mut socket).await {
+        let msg = match read_with_length(socket).await {
             Ok(msg) => msg,
             Err(e) => {
                 error!("Failed to read from socket: {:?}", e);
@@ -209,21 +831,71 @@ async fn run_receive(
         };
 
         trace!("Parsing message from stream...");
-        match types::decode_message(&msg).map(|m| m.inner) {
-            Ok(Some(one_of)) => match one_of {
+        let msg = match types::decode_message(&msg) {
+            // Responses are handed to the request waiting for them
+            Ok(msg) => match reqs.respond(msg).await {
+                Some(msg) => msg,
+                None => continue,
+            },
+            Err(_) => {
+                warn!("Invalid payload received; skipping...");
+                continue;
+            }
+        };
+
+        match msg.inner {
+            Some(one_of) => match one_of {
+                ApiMessageEnum::recv(mut msg) if msg.stream != 0 => {
+                    debug!("Receiving streamed message {}", msg.stream);
+                    let (ctx, chunks) = bounded(STREAM_CHUNKS);
+
+                    let stream = IncomingStream {
+                        tt: msg.field_type,
+                        msg: msg.take_msg(),
+                        chunks,
+                        done: false,
+                    };
+                    match stx.try_send(stream) {
+                        Ok(_) => {
+                            streams.insert(msg.stream, ctx);
+                        }
+                        // Chunks for unknown streams are discarded
+                        Err(TrySendError::Full(_)) => {
+                            warn!("Stream queue is full; dropping stream {}", msg.stream);
+                        }
+                        Err(e) => error!("Failed to forward received stream: {}", e),
+                    }
+                }
+                ApiMessageEnum::chunk(mut c) => match streams.get(&c.stream) {
+                    // Wait for the reader to catch up, which also
+                    // makes the daemon wait before sending more
+                    Some(ctx) => {
+                        let sent = ctx.send((c.take_data(), c.last)).await;
+                        if sent.is_err() {
+                            debug!("Stream {} was dropped; discarding its chunks", c.stream);
+                        }
+                        if sent.is_err() || c.last {
+                            streams.remove(&c.stream);
+                        }
+                    }
+                    None => trace!("Discarding chunk for unknown stream {}", c.stream),
+                },
                 ApiMessageEnum::recv(mut msg) => {
                     let tt = msg.field_type;
                     let msg = msg.take_msg();
 
                     debug!("Forwarding message to IPC wrapper");
-                    if let Err(e) = tx.send((tt, msg)).await {
-                        error!("Failed to forward received message: {}", e);
-                    }
+                    channels.deliver((tt, msg));
                 }
                 ApiMessageEnum::peers(peers) if peers.get_field_type() == DISCOVER => {
+                    // Discovery events are only hints, so don't
+                    // block incoming messages if nobody polls them
                     match peers.peers.get(0) {
-                        Some(p) => match dtx.send(Identity::from_bytes(p)).await {
+                        Some(p) => match dtx.try_send(Identity::from_bytes(p)) {
                             Ok(_) => {}
+                            Err(TrySendError::Full(_)) => {
+                                debug!("Discovery queue is full; dropping event");
+                            }
                             _ => {
                                 error!("Failed to send discovery to client poller...");
                                 continue;
@@ -232,9 +904,16 @@ async fn run_receive(
                         None => continue,
                     }
                 }
+                ApiMessageEnum::error(e) => {
+                    warn!(
+                        "Daemon reported an error: {} ({:?})",
+                        e.get_message(),
+                        e.get_code()
+                    );
+                }
                 _ => {} // This might be a problem idk
             },
-            _ => {
+            None => {
                 warn!("Invalid payload received; skipping...");
                 continue;
             }
@@ -243,6 +922,7 @@ async fn run_receive(
 }
 
 /// This test is horrible and a bad idea but whatever
+#[cfg(test)]
 #[async_std::test]
 #[ignore]
 async fn send_message() {
@@ -292,3 +972,359 @@ async fn send_message() {
     // Exorcise the deamons!
     daemon.kill().unwrap();
 }
+
+/// Start a fake daemon, returning its listener and socket address
+#[cfg(test)]
+async fn fake_daemon() -> (async_std::net::TcpListener, String) {
+    let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let socket_addr = listener.local_addr().unwrap().to_string();
+    (listener, socket_addr)
+}
+
+/// Accept a client on a fake daemon and acknowledge its handshake
+///
+/// The client is assigned `addr`, and told that the daemon supports
+/// `features`.  Returns the connection and the client's setup.
+#[cfg(test)]
+async fn accept_client(
+    listener: &async_std::net::TcpListener,
+    addr: Identity,
+    features: &[&str],
+) -> (async_std::net::TcpStream, Setup) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let setup = parse_message(&mut stream).await.unwrap().take_setup();
+    let ack = api::with_version(api::online_ack(addr), VERSION, features);
+    write_msg(&mut stream, api::api_setup(ack)).await;
+    (stream, setup)
+}
+
+/// Write a message from a fake daemon
+#[cfg(test)]
+async fn write_msg(stream: &mut async_std::net::TcpStream, msg: ApiMessage) {
+    write_with_length(stream, &encode_message(msg).unwrap())
+        .await
+        .unwrap();
+}
+
+/// Make sure that request responses are routed to the caller, even
+/// when other messages arrive on the same stream first
+#[cfg(test)]
+#[async_std::test]
+async fn request_response_routing() {
+    let (listener, socket_addr) = fake_daemon().await;
+    let (addr, peer) = (Identity::random(), Identity::random());
+
+    // A fake daemon which delivers a message before answering
+    let daemon = task::spawn(async move {
+        let (mut stream, _) = accept_client(&listener, addr, &[]).await;
+        let req = parse_message(&mut stream).await.unwrap();
+        assert_ne!(req.id, 0);
+
+        let msg = message::new(peer, vec![addr], vec![1, 3, 1, 2], vec![]);
+        write_msg(&mut stream, api::api_recv(api::receive_default(msg))).await;
+
+        let resp = api::with_id(api::api_peers(api::all_peers(vec![peer])), req.id);
+        write_msg(&mut stream, resp).await;
+        stream
+    });
+
+    let client = RatmanIpc::connect(&socket_addr, None).await.unwrap();
+    assert_eq!(client.address(), addr);
+    assert_eq!(client.get_peers().await.unwrap(), vec![peer]);
+
+    let (_, msg) = client.next().await.unwrap();
+    assert_eq!(msg.get_payload(), &[1, 3, 1, 2]);
+    drop(daemon.await);
+}
+
+#[cfg(test)]
+#[test]
+fn version_compatibility() {
+    let (major, minor) = {
+        let mut v = VERSION.split('.');
+        (v.next().unwrap(), v.next().unwrap())
+    };
+
+    assert!(compatible(VERSION));
+    assert!(compatible(&format!("{}.{}.1312", major, minor)));
+    assert!(!compatible(&format!("{}.{}1.0", major, minor)));
+    assert!(!compatible(""));
+}
+
+/// Make sure that errors reported by the daemon are returned to the
+/// caller of the failed request
+#[cfg(test)]
+#[async_std::test]
+async fn remote_errors() {
+    let (listener, socket_addr) = fake_daemon().await;
+    let addr = Identity::random();
+
+    let daemon = task::spawn(async move {
+        let (mut stream, _) = accept_client(&listener, addr, &[api::features::ERRORS]).await;
+        let req = parse_message(&mut stream).await.unwrap();
+        let err = api::error(api::ErrorCode::NO_USER, "the provided address is unknown");
+        write_msg(&mut stream, api::with_id(api::api_error(err), req.id)).await;
+        stream
+    });
+
+    let client = RatmanIpc::connect(&socket_addr, None).await.unwrap();
+    match client.send_to(Identity::random(), vec![1, 3, 1, 2]).await {
+        Err(Error::Remote(code, _)) => assert_eq!(code, api::ErrorCode::NO_USER),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    drop(daemon.await);
+}
+
+/// Make sure that responses are still delivered while the client
+/// doesn't poll its incoming messages, and that the oldest messages
+/// are dropped when the queue is full
+#[cfg(test)]
+#[async_std::test]
+async fn full_receive_queue() {
+    use async_std::future::timeout;
+
+    let (listener, socket_addr) = fake_daemon().await;
+    let (addr, peer) = (Identity::random(), Identity::random());
+    let num = QUEUE_SIZE + 44;
+
+    let daemon = task::spawn(async move {
+        let (mut stream, _) = accept_client(&listener, addr, &[api::features::ERRORS]).await;
+
+        // Deliver more messages than the client queues before the ACK
+        let req = parse_message(&mut stream).await.unwrap();
+        for i in 0..num {
+            let payload = (i as u16).to_be_bytes().to_vec();
+            let msg = message::new(peer, vec![addr], payload, vec![]);
+            write_msg(&mut stream, api::api_recv(api::receive_default(msg))).await;
+        }
+
+        write_msg(&mut stream, api::with_id(api::api_ack(), req.id)).await;
+        stream
+    });
+
+    let client = RatmanIpc::connect(&socket_addr, None).await.unwrap();
+    timeout(Duration::from_secs(5), client.send_to(peer, vec![1, 2]))
+        .await
+        .expect("send_to was blocked by the full receive queue")
+        .unwrap();
+
+    let (_, msg) = client.next().await.unwrap();
+    assert_eq!(
+        msg.get_payload(),
+        &((num - QUEUE_SIZE) as u16).to_be_bytes()
+    );
+    drop(daemon.await);
+}
+
+/// Make sure that large payloads are streamed in both directions
+#[cfg(test)]
+#[async_std::test]
+async fn stream_payloads() {
+    let (listener, socket_addr) = fake_daemon().await;
+    let (addr, peer) = (Identity::random(), Identity::random());
+    let payload: Vec<u8> = (0..MAX_PAYLOAD_LEN * 2 + 3).map(|i| i as u8).collect();
+
+    let expected = payload.clone();
+    let daemon = task::spawn(async move {
+        let features = &[api::features::ERRORS, api::features::STREAMS];
+        let (mut stream, _) = accept_client(&listener, addr, features).await;
+
+        // Receive the streamed payload and acknowledge it
+        let req = parse_message(&mut stream).await.unwrap();
+        let stream_id = req.get_send().stream;
+        assert_ne!(stream_id, 0);
+
+        let mut received = vec![];
+        for seq in 0..3 {
+            let mut c = parse_message(&mut stream).await.unwrap().take_chunk();
+            assert_eq!((c.stream, c.seq, c.last), (stream_id, seq, seq == 2));
+            received.append(&mut c.take_data());
+        }
+        assert_eq!(received, expected);
+        write_msg(&mut stream, api::with_id(api::api_ack(), req.id)).await;
+
+        // Then stream it back in two chunks
+        let msg = message::new(peer, vec![addr], vec![], vec![]);
+        let mut recv = api::receive_default(msg);
+        recv.set_stream(7);
+        write_msg(&mut stream, api::api_recv(recv)).await;
+
+        for (seq, data) in received.chunks(MAX_PAYLOAD_LEN + 2).enumerate() {
+            let c = api::chunk(7, seq as u64, data.to_vec(), seq == 1);
+            write_msg(&mut stream, api::api_chunk(c)).await;
+        }
+        stream
+    });
+
+    let client = RatmanIpc::connect(&socket_addr, None).await.unwrap();
+    client.send_to(peer, payload.clone()).await.unwrap();
+
+    let incoming = client.next_stream().await.unwrap();
+    assert_eq!(incoming.message().get_sender(), peer.as_bytes());
+    assert_eq!(incoming.read_to_end().await.unwrap(), payload);
+    drop(daemon.await);
+}
+
+/// Make sure that a slow stream reader holds up the connection
+/// instead of losing chunks
+#[cfg(test)]
+#[async_std::test]
+async fn slow_stream_reader() {
+    let (listener, socket_addr) = fake_daemon().await;
+    let (addr, peer) = (Identity::random(), Identity::random());
+    let num = STREAM_CHUNKS * 2 + 1;
+
+    let daemon = task::spawn(async move {
+        let features = &[api::features::ERRORS, api::features::STREAMS];
+        let (mut stream, _) = accept_client(&listener, addr, features).await;
+
+        let msg = message::new(peer, vec![addr], vec![], vec![]);
+        let mut recv = api::receive_default(msg.clone());
+        recv.set_stream(7);
+        write_msg(&mut stream, api::api_recv(recv)).await;
+        for seq in 0..num {
+            let c = api::chunk(7, seq as u64, vec![seq as u8], seq + 1 == num);
+            write_msg(&mut stream, api::api_chunk(c)).await;
+        }
+
+        // Messages after the stream arrive once it was read
+        write_msg(&mut stream, api::api_recv(api::receive_default(msg))).await;
+        stream
+    });
+
+    let client = RatmanIpc::connect(&socket_addr, None).await.unwrap();
+    let incoming = client.next_stream().await.unwrap();
+    task::sleep(Duration::from_millis(200)).await;
+
+    let expected: Vec<u8> = (0..num as u8).collect();
+    assert_eq!(incoming.read_to_end().await.unwrap(), expected);
+    assert!(client.next().await.is_some());
+    drop(daemon.await);
+}
+
+/// Make sure that the client reconnects with the same address after
+/// the daemon drops the connection, and that the stream and sink
+/// interfaces keep working across reconnects
+#[cfg(test)]
+#[async_std::test]
+async fn reconnect_stream_sink() {
+    use futures::{SinkExt, StreamExt};
+
+    let (listener, socket_addr) = fake_daemon().await;
+    let (addr, peer) = (Identity::random(), Identity::random());
+
+    let daemon = task::spawn(async move {
+        let features = &[api::features::ERRORS];
+
+        // Accept the first connection and drop it right away
+        drop(accept_client(&listener, addr, features).await);
+
+        let (mut stream, setup) = accept_client(&listener, addr, features).await;
+        assert_eq!(setup.get_id(), addr.as_bytes());
+
+        let msg = message::new(peer, vec![addr], vec![1, 3, 1, 2], vec![]);
+        write_msg(&mut stream, api::api_recv(api::receive_default(msg))).await;
+
+        let req = parse_message(&mut stream).await.unwrap();
+        assert_eq!(req.get_send().get_msg().get_payload(), &[1, 2]);
+        write_msg(&mut stream, api::with_id(api::api_ack(), req.id)).await;
+        stream
+    });
+
+    let client = RatmanIpc::connect(&socket_addr, None).await.unwrap();
+    let (_, msg) = client.messages().next().await.unwrap();
+    assert_eq!(msg.get_payload(), &[1, 3, 1, 2]);
+
+    client.sink().send((peer, vec![1, 2])).await.unwrap();
+    drop(daemon.await);
+}
+
+/// Make sure that disconnecting closes the connection, fails pending
+/// requests and doesn't reconnect
+#[cfg(test)]
+#[async_std::test]
+async fn disconnect() {
+    use async_std::future::timeout;
+
+    let (listener, socket_addr) = fake_daemon().await;
+    let addr = Identity::random();
+
+    let daemon = task::spawn(async move {
+        let (mut stream, _) = accept_client(&listener, addr, &[api::features::ERRORS]).await;
+        let _ = parse_message(&mut stream).await.unwrap();
+
+        // The request is never answered, and the client doesn't
+        // reconnect after closing the connection
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        let reconnect = timeout(RECONNECT_DELAY * 4, listener.accept()).await;
+        assert!(reconnect.is_err());
+    });
+
+    let client = RatmanIpc::connect(&socket_addr, None).await.unwrap();
+    let request = task::spawn({
+        let client = client.clone();
+        async move { client.get_peers().await }
+    });
+    task::sleep(Duration::from_millis(100)).await;
+
+    client.disconnect().await;
+    assert!(matches!(request.await, Err(Error::ConnectionLost)));
+    assert!(client.next().await.is_none());
+    assert!(matches!(
+        client.send_to(addr, vec![1, 2]).await,
+        Err(Error::ConnectionLost)
+    ));
+    daemon.await;
+}
+
+/// Make sure that the blocking API works without an async runtime in
+/// the calling thread
+#[cfg(test)]
+#[test]
+fn blocking_get_peers() {
+    let (listener, socket_addr) = task::block_on(fake_daemon());
+    let (addr, peer) = (Identity::random(), Identity::random());
+
+    let daemon = task::spawn(async move {
+        let (mut stream, _) = accept_client(&listener, addr, &[api::features::ERRORS]).await;
+        let req = parse_message(&mut stream).await.unwrap();
+        let resp = api::with_id(api::api_peers(api::all_peers(vec![peer])), req.id);
+        write_msg(&mut stream, resp).await;
+        stream
+    });
+
+    let client = BlockingIpc::connect(&socket_addr, None).unwrap();
+    assert_eq!(client.address(), addr);
+    assert_eq!(client.get_peers().unwrap(), vec![peer]);
+    drop(task::block_on(daemon));
+}
+
+/// Make sure that the client works inside a tokio runtime
+#[cfg(all(test, feature = "tokio"))]
+#[test]
+fn tokio_runtime() {
+    let (listener, socket_addr) = task::block_on(fake_daemon());
+    let (addr, peer) = (Identity::random(), Identity::random());
+
+    let daemon = task::spawn(async move {
+        let (mut stream, _) = accept_client(&listener, addr, &[api::features::ERRORS]).await;
+        let req = parse_message(&mut stream).await.unwrap();
+        let resp = api::with_id(api::api_peers(api::all_peers(vec![peer])), req.id);
+        write_msg(&mut stream, resp).await;
+        stream
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let client = RatmanIpc::connect(&socket_addr, None).await.unwrap();
+        assert_eq!(client.address(), addr);
+        assert_eq!(client.get_peers().await.unwrap(), vec![peer]);
+    });
+    drop(task::block_on(daemon));
+}
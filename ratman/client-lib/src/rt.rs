@@ -0,0 +1,94 @@
+//! Runtime specific parts of the client
+//!
+//! By default the client uses async-std to connect to the daemon and
+//! to spawn its receive task.  With the `tokio` feature it uses tokio
+//! instead.  If both features are enabled, tokio is used.
+//!
+//! Outside of a tokio runtime the `tokio` backend starts its own, so
+//! the client can still be driven by other executors.
+
+#[cfg(not(any(feature = "async-std", feature = "tokio")))]
+compile_error!("ratman-client needs either the `async-std` or the `tokio` feature");
+
+pub(crate) use imp::*;
+
+#[cfg(not(feature = "tokio"))]
+mod imp {
+    use std::{future::Future, io::Result, time::Duration};
+
+    pub(crate) type TcpStream = async_std::net::TcpStream;
+
+    pub(crate) async fn connect(socket_addr: &str) -> Result<TcpStream> {
+        TcpStream::connect(socket_addr).await
+    }
+
+    pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(fut: F) {
+        async_std::task::spawn(fut);
+    }
+
+    pub(crate) async fn sleep(dur: Duration) {
+        async_std::task::sleep(dur).await
+    }
+
+    pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+        async_std::task::block_on(fut)
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod imp {
+    use std::{
+        future::Future,
+        io::{Error, Result},
+        sync::OnceLock,
+        time::Duration,
+    };
+    use tokio::runtime::{Builder, Handle, Runtime};
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    pub(crate) type TcpStream = Compat<tokio::net::TcpStream>;
+
+    /// The runtime used outside of a tokio runtime
+    ///
+    /// This is used by the blocking API, and allows the client to be
+    /// driven by other executors.
+    fn runtime() -> &'static Runtime {
+        static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+        RUNTIME.get_or_init(|| {
+            Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("failed to start tokio runtime")
+        })
+    }
+
+    /// Get the current tokio runtime, or the internal one
+    fn handle() -> Handle {
+        Handle::try_current().unwrap_or_else(|_| runtime().handle().clone())
+    }
+
+    pub(crate) async fn connect(socket_addr: &str) -> Result<TcpStream> {
+        // Sockets need to be registered with a runtime's reactor
+        let socket_addr = socket_addr.to_owned();
+        let stream = handle()
+            .spawn(async move { tokio::net::TcpStream::connect(socket_addr).await })
+            .await
+            .map_err(Error::other)??;
+        Ok(stream.compat())
+    }
+
+    pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(fut: F) {
+        drop(handle().spawn(fut));
+    }
+
+    pub(crate) async fn sleep(dur: Duration) {
+        let _ = handle().spawn(tokio::time::sleep(dur)).await;
+    }
+
+    /// Block on a future
+    ///
+    /// This panics when called from within a tokio runtime.
+    pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+        runtime().block_on(fut)
+    }
+}
@@ -0,0 +1,96 @@
+//! `futures` adapters for a `RatmanIpc` connection
+
+use crate::RatmanIpc;
+use async_channel::Receiver;
+use futures::{ready, Sink, Stream, StreamExt};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use types::{api::Receive_Type, message::Message, Error, Identity, Result};
+
+/// A stream of messages sent to an address
+///
+/// Created by [`RatmanIpc::messages`].  The stream ends when the
+/// connection to the daemon is lost and can't be re-established.
+pub struct Messages(pub(crate) Receiver<(Receive_Type, Message)>);
+
+impl Stream for Messages {
+    type Item = (Receive_Type, Message);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx)
+    }
+}
+
+/// A stream of address discovery events
+///
+/// Created by [`RatmanIpc::discoveries`].
+pub struct Discoveries(pub(crate) Receiver<Identity>);
+
+impl Stream for Discoveries {
+    type Item = Identity;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx)
+    }
+}
+
+type SendFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// A sink for messages to remote peers
+///
+/// Created by [`RatmanIpc::sink`].  Each item is a recipient and a
+/// payload, and is sent like a call to
+/// [`send_to`](RatmanIpc::send_to).  Only one message is in flight
+/// at a time, so errors are reported for the message which caused
+/// them.
+pub struct SendSink {
+    ipc: RatmanIpc,
+    pending: Option<SendFuture>,
+}
+
+impl SendSink {
+    pub(crate) fn new(ipc: RatmanIpc) -> Self {
+        Self { ipc, pending: None }
+    }
+
+    /// Drive the message which is currently being sent
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let res = match self.pending.as_mut() {
+            Some(fut) => ready!(fut.as_mut().poll(cx)),
+            None => Ok(()),
+        };
+
+        self.pending = None;
+        Poll::Ready(res)
+    }
+}
+
+impl Sink<(Identity, Vec<u8>)> for SendSink {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_pending(cx)
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        (recipient, payload): (Identity, Vec<u8>),
+    ) -> Result<()> {
+        let ipc = self.ipc.clone();
+        self.pending = Some(Box::pin(
+            async move { ipc.send_to(recipient, payload).await },
+        ));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_pending(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_pending(cx)
+    }
+}
@@ -0,0 +1,76 @@
+//! Streamed message payloads
+//!
+//! Payloads larger than [`MAX_PAYLOAD_LEN`] don't fit into a single
+//! API frame.  Instead the message is sent without a payload, followed
+//! by a stream of `Chunk` messages.
+
+use async_channel::Receiver;
+use futures::io::{AsyncRead as Read, AsyncReadExt};
+use types::{api::Receive_Type, message::Message, Error, Result, MAX_PAYLOAD_LEN};
+
+/// A received message with a streamed payload
+///
+/// The payload of [`message`](Self::message) is empty.  Instead it
+/// can be read chunk by chunk via [`next_chunk`](Self::next_chunk).
+/// Only a few chunks are buffered for each stream.  While they
+/// aren't read, nothing else is received on the connection, including
+/// responses to requests, so read streams as they come in, for
+/// example on their own task.  Dropping a stream discards the rest of
+/// its payload.
+pub struct IncomingStream {
+    pub(crate) tt: Receive_Type,
+    pub(crate) msg: Message,
+    pub(crate) chunks: Receiver<(Vec<u8>, bool)>,
+    pub(crate) done: bool,
+}
+
+impl IncomingStream {
+    /// Get the type of the received message
+    pub fn receive_type(&self) -> Receive_Type {
+        self.tt
+    }
+
+    /// Get the message metadata
+    pub fn message(&self) -> &Message {
+        &self.msg
+    }
+
+    /// Get the next chunk of the payload
+    ///
+    /// Returns `None` once the whole payload has been read, or an
+    /// error if the connection was lost before its end.
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let (data, last) = self
+            .chunks
+            .recv()
+            .await
+            .map_err(|_| Error::ConnectionLost)?;
+        self.done = last;
+        Ok(Some(data))
+    }
+
+    /// Read the whole payload into memory
+    pub async fn read_to_end(mut self) -> Result<Vec<u8>> {
+        let mut payload = vec![];
+        while let Some(mut data) = self.next_chunk().await? {
+            payload.append(&mut data);
+        }
+        Ok(payload)
+    }
+}
+
+/// Read the next chunk of a payload from a reader
+///
+/// Returns less than `MAX_PAYLOAD_LEN` bytes only at the end of the
+/// reader.
+pub(crate) async fn read_chunk<R: Read + Unpin>(r: &mut R) -> Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(MAX_PAYLOAD_LEN);
+    r.take(MAX_PAYLOAD_LEN as u64)
+        .read_to_end(&mut chunk)
+        .await?;
+    Ok(chunk)
+}
@@ -89,7 +89,7 @@ impl Network {
                     // FIXME: Figure out what the udp module actually needs
                     Params::LocalUpd { iface, port } => {
                         use netmod_lan::Endpoint;
-                        let ep = Endpoint::spawn(&iface, port);
+                        let ep = Endpoint::spawn(&iface, port).unwrap();
                         block_on(async { router.add_endpoint(ep).await });
                     }
                     Params::Tcp {
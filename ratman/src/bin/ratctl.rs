@@ -1,5 +1,7 @@
-use clap::{App, Arg};
-use ratman_client::{Identity, RatmanIpc};
+use clap::{App, Arg, ArgGroup, ArgMatches};
+use directories::ProjectDirs;
+use ratman_client::{Identity, RatmanIpc, CONTROL_TOKEN_FILE};
+use std::path::PathBuf;
 
 const ASCII: &str = r#"      ,     .             
       (\,;,/)                    (\,/)
@@ -25,11 +27,16 @@ fn setup_cli() -> App<'static, 'static> {
                 .help("Specify the API socket bind address")
                 .default_value("127.0.0.1:9020"),
         )
+        .arg(
+            Arg::with_name("CONTROL_TOKEN")
+                .takes_value(true)
+                .long("control-token")
+                .value_name("FILE")
+                .help("Read the token which allows changing the router's configuration from FILE.  Defaults to the file ratmand creates in its data directory")
+        )
         .arg(
             Arg::with_name("GET_PEERS")
                 .long("get-peers")
-                .required_unless("SUBSCRIBE_PEERS")
-                .conflicts_with("SUBSCRIBE_PEERS")
                 .help("Request the currently known list of peers from the router")
         )
         .arg(
@@ -37,28 +44,121 @@ fn setup_cli() -> App<'static, 'static> {
             Arg::with_name("SUBSCRIBE_PEERS")
                 .hidden(true)
                 .long("subscribe-peers")
-                .required_unless("GET_PEERS")
-                .conflicts_with("GET_PEERS")
                 .help("Remain running and be notified about new peers as they are discovered")
         )
+        .arg(
+            Arg::with_name("ADD_PEERS")
+                .long("add-peer")
+                .takes_value(true)
+                .multiple(true)
+                .value_name("PEER")
+                .help("Add peers to the running router, using the same syntax as `ratmand --peers`")
+        )
+        .arg(
+            Arg::with_name("REMOVE_PEERS")
+                .long("remove-peer")
+                .takes_value(true)
+                .multiple(true)
+                .value_name("PEER")
+                .help("Remove peers from the running router")
+        )
+        .arg(
+            Arg::with_name("ENABLE_ENDPOINT")
+                .long("enable-endpoint")
+                .takes_value(true)
+                .value_name("ID")
+                .help("Enable a previously disabled endpoint")
+        )
+        .arg(
+            Arg::with_name("DISABLE_ENDPOINT")
+                .long("disable-endpoint")
+                .takes_value(true)
+                .value_name("ID")
+                .help("Stop sending and receiving frames via an endpoint.  Endpoints are numbered in the order ratmand initialised them")
+        )
+        .arg(
+            Arg::with_name("LOG_LEVEL")
+                .long("log-level")
+                .takes_value(true)
+                .possible_values(&["trace", "debug", "info", "warn", "error"])
+                .help("Change the log level of the running router")
+        )
+        .group(
+            ArgGroup::with_name("COMMAND")
+                .args(&[
+                    "GET_PEERS",
+                    "SUBSCRIBE_PEERS",
+                    "ADD_PEERS",
+                    "REMOVE_PEERS",
+                    "ENABLE_ENDPOINT",
+                    "DISABLE_ENDPOINT",
+                    "LOG_LEVEL",
+                ])
+                .required(true)
+        )
 }
 
-async fn connect_ipc(bind: &str) -> Result<RatmanIpc, Box<dyn std::error::Error>> {
+/// The control token file of a ratmand running as the current user
+fn default_token_file() -> Option<PathBuf> {
+    let dirs = ProjectDirs::from("org", "irdest", "ratmand")?;
+    Some(dirs.data_dir().join(CONTROL_TOKEN_FILE))
+}
+
+/// Connect to the daemon, with its control token if `token` is set
+async fn connect_ipc(
+    bind: &str,
+    token: Option<PathBuf>,
+) -> Result<RatmanIpc, Box<dyn std::error::Error>> {
     eprintln!("Connecting to IPC backend...");
-    Ok(RatmanIpc::anonymous(bind).await?)
+    match token {
+        Some(path) => {
+            let token = std::fs::read(&path)
+                .map_err(|e| format!("failed to read control token {:?}: {}", path, e))?;
+            Ok(RatmanIpc::control(bind, token).await?)
+        }
+        None => Ok(RatmanIpc::anonymous(bind).await?),
+    }
 }
 
 async fn get_peers(ipc: &RatmanIpc) -> Result<Vec<Identity>, Box<dyn std::error::Error>> {
     Ok(ipc.get_peers().await?)
 }
 
+/// Send the management command selected on the command line
+async fn manage(ipc: &RatmanIpc, m: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let values = |name| m.values_of(name).unwrap().map(Into::into).collect();
+    let endpoint = |name| m.value_of(name).map(str::parse).transpose();
+
+    if m.is_present("ADD_PEERS") {
+        ipc.add_peers(values("ADD_PEERS")).await?;
+    } else if m.is_present("REMOVE_PEERS") {
+        ipc.remove_peers(values("REMOVE_PEERS")).await?;
+    } else if let Some(id) = endpoint("ENABLE_ENDPOINT")? {
+        ipc.set_endpoint_enabled(id, true).await?;
+    } else if let Some(id) = endpoint("DISABLE_ENDPOINT")? {
+        ipc.set_endpoint_enabled(id, false).await?;
+    } else if let Some(level) = m.value_of("LOG_LEVEL") {
+        ipc.set_log_level(level).await?;
+    }
+
+    Ok(())
+}
+
 #[async_std::main]
 async fn main() {
     let cli = setup_cli();
     let m = cli.get_matches();
 
+    // Only management commands need the control token
     let bind = m.value_of("API_BIND").unwrap();
-    let ipc = match connect_ipc(bind).await {
+    let token = match m.is_present("GET_PEERS") || m.is_present("SUBSCRIBE_PEERS") {
+        true => None,
+        false => m
+            .value_of("CONTROL_TOKEN")
+            .map(PathBuf::from)
+            .or_else(default_token_file),
+    };
+    let ipc = match connect_ipc(bind, token).await {
         Ok(ipc) => ipc,
         Err(e) => {
             eprintln!("Failed to connect to daemon: {}", e);
@@ -83,5 +183,8 @@ async fn main() {
         while let Some(peer) = ipc.discover().await {
             println!("Discovered {}", peer);
         }
+    } else if let Err(e) = manage(&ipc, &m).await {
+        eprintln!("Failed to reconfigure router: {}", e);
+        std::process::exit(1);
     }
 }
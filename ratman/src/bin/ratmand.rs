@@ -6,9 +6,11 @@ extern crate tracing;
 pub(crate) use ratman::*;
 
 use clap::{App, Arg, ArgMatches};
+use daemon::config::{Config, LEVELS};
 use netmod_inet::{Endpoint as Inet, Mode};
-use netmod_lan::{default_iface, Endpoint as LanDiscovery};
-use std::{fs::File, io::Read};
+use netmod_lan::{default_iface, Endpoint as LanDiscovery, Mode as LanMode};
+use netmod_udp::{Endpoint as Udp, Mode as UdpMode};
+use std::{fs::File, io::Read, path::Path, sync::Arc};
 
 pub fn build_cli() -> ArgMatches<'static> {
     App::new("ratmand")
@@ -16,12 +18,19 @@ pub fn build_cli() -> ArgMatches<'static> {
         .version(env!("CARGO_PKG_VERSION"))
         .after_help("This is ALPHA level software and will include bugs and cause crashes.  If you encounter a reproducible issue, please report it in our issue tracker (https://git.irde.st/we/irdest) or our mailing list: https://lists.irde.st/archives/list/community@lists.irde.st")
         .max_term_width(120)
+        .arg(
+            Arg::with_name("CONFIG")
+                .takes_value(true)
+                .short("c")
+                .long("config")
+                .help("Load settings from a TOML configuration file.  Options passed on the command line take precedence over values from this file")
+        )
         .arg(
             Arg::with_name("VERBOSITY")
                 .takes_value(true)
                 .short("v")
                 .long("verbosity")
-                .possible_values(&["trace", "debug", "info", "warn", "error", "fatal"])
+                .possible_values(LEVELS)
                 .default_value("info")
                 .help("Specify the verbosity level at which ratmand logs interactions"),
         )
@@ -29,7 +38,7 @@ pub fn build_cli() -> ArgMatches<'static> {
             Arg::with_name("ACCEPT_UNKNOWN_PEERS")
                 .long("accept-unknown-peers")
                 .short("d")
-                .required_unless_one(&["PEERS", "PEER_FILE", "NO_INET"])
+                .required_unless_one(&["PEERS", "PEER_FILE", "NO_INET", "CONFIG"])
                 .help("Configure ratmand to peer with any incoming connection it may encounter")
         )
         .arg(
@@ -47,6 +56,35 @@ pub fn build_cli() -> ArgMatches<'static> {
                 .help("Specify the inet-driver socket bind address.  Make sure this port is open in your firewall")
                 .default_value("[::]:9000"),
         )
+        .arg(
+            Arg::with_name("INET_TRUST")
+                .long("inet-trust")
+                .takes_value(true)
+                .multiple(true)
+                .value_name("KEY")
+                .help("Only accept inet peers with one of these public keys.  Trusted peers are accepted even if their address isn't known")
+        )
+        .arg(
+            Arg::with_name("PEER_EXCHANGE")
+                .long("peer-exchange")
+                .takes_value(true)
+                .value_name("MAX")
+                .help("Connect to inet peers shared by other peers, until MAX peers are known.  Defaults to 8 with --accept-unknown-peers, and 0 (disabled) otherwise")
+        )
+        .arg(
+            Arg::with_name("INET_RATE_LIMIT")
+                .long("inet-rate-limit")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Send at most BYTES per second to each inet peer.  Frames beyond this limit are queued, and flooded frames are dropped first.  Unlimited by default")
+        )
+        .arg(
+            Arg::with_name("UDP_BIND")
+                .long("udp")
+                .takes_value(true)
+                .value_name("ADDR")
+                .help("Bind the reliable UDP overlay driver to this address, to connect to peers given as quic#<address>:<port>.  Disabled by default")
+        )
         .arg(
             Arg::with_name("NO_INET")
                 .long("no-inet")
@@ -65,6 +103,14 @@ pub fn build_cli() -> ArgMatches<'static> {
                 .long("discovery-iface")
                 .help("Specify the interface on which to bind for local peer discovery.  If none is provided the default interface will be attempted to be determined")
         )
+        .arg(
+            Arg::with_name("DISCOVERY_MODE")
+                .takes_value(true)
+                .long("discovery-mode")
+                .default_value("ipv6")
+                .possible_values(&["ipv6", "ipv4-multicast", "ipv4-broadcast"])
+                .help("Specify how local peers are discovered: via IPv6 multicast, or IPv4 multicast or broadcast for networks without IPv6.  All local peers must use the same mode")
+        )
         .arg(
             Arg::with_name("NO_DISCOVERY")
                 .long("no-discovery")
@@ -74,7 +120,7 @@ pub fn build_cli() -> ArgMatches<'static> {
             Arg::with_name("PEERS")
                 .long("peers")
                 .short("p")
-                .help("Specify a set of peers via the PEER SYNTAX: <netmod-id>#<address>:<port>[L].  Incompatible with `-f`. Valid netmod-ids are tcp. Example: tcp#10.0.0.10:9000L")
+                .help("Specify a set of peers via the PEER SYNTAX: <netmod-id>#<address>:<port>[L].  Incompatible with `-f`. Valid netmod-ids are inet and quic. Example: inet#10.0.0.10:9000L")
                 .takes_value(true)
                 .multiple(true),
         )
@@ -85,6 +131,39 @@ pub fn build_cli() -> ArgMatches<'static> {
                 .help("Provide a set of initial peers to connect to.  Incompatible with `-p`")
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("QUEUE_SIZE")
+                .long("queue-size")
+                .takes_value(true)
+                .default_value("256")
+                .help("Specify the number of received messages buffered for each connected client")
+        )
+        .arg(
+            Arg::with_name("QUEUE_POLICY")
+                .long("queue-policy")
+                .takes_value(true)
+                .possible_values(&["drop-oldest", "block", "spill"])
+                .default_value("block")
+                .help("Specify what happens when a client queue is full: drop the oldest message, block the router until the client catches up, or spill new messages to disk, up to the inbox quota")
+        )
+        .arg(
+            Arg::with_name("INBOX_QUOTA")
+                .long("inbox-quota")
+                .takes_value(true)
+                .help("Specify the maximum size (in bytes) of stored messages for each disconnected client.  Set to 0 to disable the inbox.  Defaults to 16MiB")
+        )
+        .arg(
+            Arg::with_name("MAX_STREAM_LEN")
+                .long("max-stream-len")
+                .takes_value(true)
+                .help("Specify the maximum length (in bytes) of a payload streamed by a client.  Streamed payloads are written to the state directory until they are complete.  Defaults to 4GiB")
+        )
+        .arg(
+            Arg::with_name("METRICS_BIND")
+                .long("metrics-bind")
+                .takes_value(true)
+                .help("Serve router metrics for Prometheus via HTTP on this address.  Requires the `metrics` feature")
+        )
         .arg(
             Arg::with_name("USE_UPNP")
                 .long("upnp")
@@ -94,89 +173,195 @@ pub fn build_cli() -> ArgMatches<'static> {
         .get_matches()
 }
 
+/// Get an option passed on the command line, or from the config file
+///
+/// Falls back to the default value of the option if neither set it.
+fn value<T: ToString>(m: &ArgMatches<'_>, name: &str, file: Option<T>) -> Option<String> {
+    match m.occurrences_of(name) {
+        0 => file
+            .map(|v| v.to_string())
+            .or_else(|| m.value_of(name).map(Into::into)),
+        _ => m.value_of(name).map(Into::into),
+    }
+}
+
+/// Check whether a flag was passed, or enabled in the config file
+fn flag(m: &ArgMatches<'_>, name: &str, file: Option<bool>) -> bool {
+    m.is_present(name) || file.unwrap_or(false)
+}
+
+fn read_peer_file<P: AsRef<Path>>(path: P) -> Option<String> {
+    let mut f = File::open(path).ok()?;
+    let mut buf = String::new();
+    f.read_to_string(&mut buf).ok()?;
+    Some(buf)
+}
+
+/// Load the initial set of peers
+///
+/// Peers passed on the command line replace the peers from the
+/// config file.
+fn load_peers(m: &ArgMatches<'_>, cfg: &Config) -> Option<String> {
+    if let Some(peers) = m.values_of("PEERS") {
+        return Some(peers.collect::<Vec<_>>().join("\n").replace(" ", "\n"));
+    }
+
+    match (m.value_of("PEER_FILE"), &cfg.peers, &cfg.peer_file) {
+        (Some(path), _, _) => read_peer_file(path),
+        (None, Some(peers), _) => Some(peers.join("\n")),
+        (None, None, Some(path)) => read_peer_file(path),
+        (None, None, None) => None,
+    }
+}
+
 // Ok(()) -> all good
 // Err(_) -> emit warning but keep going
 async fn setup_local_discovery(
     r: &Router,
     m: &ArgMatches<'_>,
+    cfg: &Config,
 ) -> std::result::Result<(String, u16), String> {
-    let iface = m.value_of("DISCOVERY_IFACE")
-        .map(Into::into)
+    let iface = value(m, "DISCOVERY_IFACE", cfg.discovery.iface.as_ref())
         .or_else(|| default_iface().map(|iface| {
             info!("Auto-selected interface '{}' for local peer discovery.  Is this wrong?  Pass --discovery-iface to ratmand instead!", iface);
             iface
         })).ok_or("failed to determine interface to bind on".to_string())?;
 
-    let port = m
-        .value_of("DISCOVERY_PORT")
+    let port = value(m, "DISCOVERY_PORT", cfg.discovery.port)
         .unwrap()
         .parse()
         .map_err(|e| format!("failed to parse discovery port: {}", e))?;
 
-    r.add_endpoint(LanDiscovery::spawn(&iface, port)).await;
+    let mode: LanMode = value(m, "DISCOVERY_MODE", cfg.discovery.mode.as_ref())
+        .unwrap()
+        .parse()?;
+
+    let lan = LanDiscovery::spawn_with_mode(&iface, port, mode)
+        .map_err(|e| format!("failed to initialise discovery endpoint: {}", e))?;
+    r.add_endpoint(lan).await;
     Ok((iface, port))
 }
 
 #[async_std::main]
 async fn main() {
     let m = build_cli();
-    let dynamic = m.is_present("ACCEPT_UNKNOWN_PEERS");
+
+    let cfg = match m.value_of("CONFIG").map(Config::load) {
+        Some(Ok(cfg)) => cfg,
+        Some(Err(e)) => {
+            daemon::setup_logging("error");
+            daemon::elog(format!("Failed to load configuration: {}", e), 2)
+        }
+        None => Config::default(),
+    };
+    let dynamic = flag(&m, "ACCEPT_UNKNOWN_PEERS", cfg.accept_unknown_peers);
 
     // Setup logging
-    daemon::setup_logging(m.value_of("VERBOSITY").unwrap());
+    daemon::setup_logging(&value(&m, "VERBOSITY", cfg.verbosity.as_ref()).unwrap());
 
     // Load peers or throw an error about missing cli data!
-    let peers: Vec<_> = match m
-        .value_of("PEERS")
-        .map(|s| s.replace(" ", "\n").to_owned())
-        .or(m.value_of("PEER_FILE").and_then(|path| {
-            let mut f = File::open(path).ok()?;
-            let mut buf = String::new();
-            f.read_to_string(&mut buf).ok()?;
-            Some(buf)
-        }))
-        .or(if m.is_present("NO_PEERING") {
-            Some("".into())
-        } else {
-            None
-        }) {
+    let peers: Vec<_> = match load_peers(&m, &cfg).or(if m.is_present("NO_PEERING") {
+        Some("".into())
+    } else {
+        None
+    }) {
         Some(peer_str) => peer_str.split("\n").map(|s| s.trim().to_owned()).collect(),
         None if !dynamic => daemon::elog("Failed to initialise ratmand: missing peers data!", 2),
         None => vec![],
     };
 
     let r = Router::new();
-    if !m.is_present("NO_INET") {
-        let tcp = match Inet::new(
-            m.value_of("INET_BIND").unwrap(),
+    let inet = if !m.is_present("NO_INET") && cfg.inet.enable != Some(false) {
+        let keypair = match daemon::load_keypair() {
+            Ok(keypair) => keypair,
+            Err(e) => daemon::elog(format!("Failed to load inet keypair: {}", e), 2),
+        };
+        let trusted = match m.values_of("INET_TRUST") {
+            Some(keys) => keys.map(String::from).collect(),
+            None => cfg.inet.trusted_keys.clone().unwrap_or_default(),
+        };
+        let trusted = match trusted.iter().map(|k| k.parse()).collect() {
+            Ok(trusted) => trusted,
+            Err(e) => daemon::elog(format!("Failed to parse INET_TRUST: {}", e), 2),
+        };
+
+        let tcp = match Inet::with_keys(
+            &value(&m, "INET_BIND", cfg.inet.bind).unwrap(),
             "ratmand",
             if dynamic { Mode::Dynamic } else { Mode::Static },
+            keypair,
+            trusted,
         )
         .await
         {
             Ok(tcp) => {
+                info!("Public key of the inet driver: {}", tcp.public_key());
+
+                match value(&m, "PEER_EXCHANGE", cfg.inet.peer_exchange).map(|n| n.parse()) {
+                    Some(Ok(max)) => tcp.peer_exchange(max),
+                    Some(Err(e)) => {
+                        daemon::elog(format!("Failed to parse PEER_EXCHANGE: {}", e), 2)
+                    }
+                    None => {}
+                }
+
+                match value(&m, "INET_RATE_LIMIT", cfg.inet.rate_limit).map(|n| n.parse()) {
+                    Some(Ok(rate)) => tcp.rate_limit(rate),
+                    Some(Err(e)) => {
+                        daemon::elog(format!("Failed to parse INET_RATE_LIMIT: {}", e), 2)
+                    }
+                    None => {}
+                }
+
                 // Open the UPNP port if the user enabled this feature
-                if m.is_present("USE_UPNP") {
+                if flag(&m, "USE_UPNP", cfg.inet.upnp) {
                     if let Err(e) = daemon::upnp::open_port(tcp.port()) {
                         error!("UPNP setup failed: {}", e);
                     }
                 }
 
-                let peers: Vec<_> = peers.iter().map(|s| s.as_str()).collect();
-                match daemon::attach_peers(&tcp, peers).await {
-                    Ok(()) => tcp,
-                    Err(e) => daemon::elog(format!("failed to parse peer data: {}", e), 1),
-                }
+                tcp
             }
             Err(e) => daemon::elog(format!("failed to initialise TCP endpoint: {}", e), 1),
         };
 
-        r.add_endpoint(tcp).await;
+        r.add_endpoint(Arc::clone(&tcp)).await;
+        Some(tcp)
+    } else {
+        None
+    };
+
+    let udp = match value(&m, "UDP_BIND", cfg.udp.bind) {
+        Some(bind) => {
+            let mode = if dynamic {
+                UdpMode::Dynamic
+            } else {
+                UdpMode::Static
+            };
+            match Udp::new(&bind, "ratmand", mode).await {
+                Ok(udp) => {
+                    r.add_endpoint(Arc::clone(&udp)).await;
+                    Some(udp)
+                }
+                Err(e) => daemon::elog(format!("failed to initialise UDP endpoint: {}", e), 1),
+            }
+        }
+        None => None,
+    };
+
+    let drivers = daemon::Drivers { inet, udp };
+    let peers: Vec<_> = peers.iter().map(|s| s.as_str()).collect();
+    match daemon::attach_peers(&drivers, peers).await {
+        Ok(()) => {}
+        Err(daemon::PeerError::NotRunning(d)) => {
+            warn!("Ignoring {}# peers: their driver is not running", d)
+        }
+        Err(e) => daemon::elog(format!("failed to parse peer data: {}", e), 1),
     }
 
     // If local-discovery is enabled
-    if !m.is_present("NO_DISCOVERY") {
-        match setup_local_discovery(&r, &m).await {
+    if !m.is_present("NO_DISCOVERY") && cfg.discovery.enable != Some(false) {
+        match setup_local_discovery(&r, &m, &cfg).await {
             Ok((iface, port)) => debug!(
                 "Local peer discovery running on interface {}, port {}",
                 iface, port
@@ -185,11 +370,49 @@ async fn main() {
         }
     }
 
-    let api_bind = match m.value_of("API_BIND").unwrap().parse() {
+    let api_bind = match value(&m, "API_BIND", cfg.api_bind).unwrap().parse() {
         Ok(addr) => addr,
         Err(e) => daemon::elog(format!("Failed to parse API_BIND address: {}", e), 2),
     };
-    if let Err(e) = daemon::run(r, api_bind).await {
+    let queue = daemon::QueueConfig {
+        size: match value(&m, "QUEUE_SIZE", cfg.queue.size).unwrap().parse() {
+            Ok(size) if size > 0 => size,
+            Ok(_) => daemon::elog("Failed to parse QUEUE_SIZE: must be at least 1", 2),
+            Err(e) => daemon::elog(format!("Failed to parse QUEUE_SIZE: {}", e), 2),
+        },
+        policy: match value(&m, "QUEUE_POLICY", cfg.queue.policy.as_ref())
+            .unwrap()
+            .parse()
+        {
+            Ok(policy) => policy,
+            Err(e) => daemon::elog(format!("Failed to parse QUEUE_POLICY: {}", e), 2),
+        },
+    };
+    let inbox_quota = match value(&m, "INBOX_QUOTA", cfg.queue.inbox_quota).map(|q| q.parse()) {
+        Some(Ok(quota)) => quota,
+        Some(Err(e)) => daemon::elog(format!("Failed to parse INBOX_QUOTA: {}", e), 2),
+        None => daemon::DEFAULT_INBOX_QUOTA,
+    };
+    let max_stream_len = match value(&m, "MAX_STREAM_LEN", cfg.max_stream_len).map(|l| l.parse()) {
+        Some(Ok(len)) => len,
+        Some(Err(e)) => daemon::elog(format!("Failed to parse MAX_STREAM_LEN: {}", e), 2),
+        None => daemon::DEFAULT_MAX_STREAM_LEN,
+    };
+    let metrics_bind = match value(&m, "METRICS_BIND", cfg.metrics_bind).map(|a| a.parse()) {
+        Some(Ok(addr)) => Some(addr),
+        Some(Err(e)) => daemon::elog(format!("Failed to parse METRICS_BIND address: {}", e), 2),
+        None => None,
+    };
+    let res = daemon::run(
+        r,
+        api_bind,
+        queue,
+        inbox_quota,
+        max_stream_len,
+        drivers,
+        metrics_bind,
+    );
+    if let Err(e) = res.await {
         error!("Ratmand suffered fatal error: {}", e);
     }
 }
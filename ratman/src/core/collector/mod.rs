@@ -79,6 +79,11 @@ impl Collector {
         }
     }
 
+    /// Get the number of workers that are collecting frames
+    pub(crate) async fn num_workers(&self) -> usize {
+        self.workers.lock().await.len()
+    }
+
     /// Get any message that has been completed
     pub(crate) async fn completed(&self) -> Message {
         self.state.completed().await
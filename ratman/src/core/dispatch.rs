@@ -2,7 +2,7 @@
 
 use crate::{
     core::{Collector, DriverMap, EpTargetPair, RouteTable},
-    Message, Result, Slicer,
+    Error, Message, Result, Slicer,
 };
 use async_std::{sync::Arc, task};
 use netmod::{Frame, Recipient, Target};
@@ -67,14 +67,21 @@ impl Dispatch {
             }
         };
 
+        if !self.drivers.enabled(epid as usize).await {
+            return Err(Error::DispatchFailed);
+        }
+
         let ep = self.drivers.get(epid as usize).await;
-        Ok(ep.send(frame, trgt).await?)
+        ep.send(frame, trgt).await?;
+        self.drivers.count_out(epid as usize).await;
+        Ok(())
     }
 
     pub(crate) async fn flood(&self, frame: Frame) -> Result<()> {
-        for ep in self.drivers.get_all().await.into_iter() {
+        for (id, ep) in self.drivers.get_all().await.into_iter() {
             let f = frame.clone();
             ep.send(f, Target::Flood).await.unwrap();
+            self.drivers.count_out(id).await;
         }
 
         Ok(())
@@ -82,9 +89,13 @@ impl Dispatch {
 
     /// Reflood a message to the network, except the previous interface
     pub(crate) async fn reflood(&self, frame: Frame, ep: usize) {
-        for ep in self.drivers.get_without(ep).await.into_iter() {
+        for (id, ep) in self.drivers.get_without(ep).await.into_iter() {
             let f = frame.clone();
-            task::spawn(async move { ep.send(f, Target::Flood).await.unwrap() });
+            let drivers = Arc::clone(&self.drivers);
+            task::spawn(async move {
+                ep.send(f, Target::Flood).await.unwrap();
+                drivers.count_out(id).await;
+            });
         }
     }
 }
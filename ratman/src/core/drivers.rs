@@ -1,6 +1,7 @@
+use crate::EndpointMetrics;
 use async_std::sync::{Arc, RwLock};
 use netmod::Endpoint;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 type Ep = dyn Endpoint + 'static + Send + Sync;
 type EpVec = Vec<EpWrap>;
@@ -9,11 +10,22 @@ type EpVec = Vec<EpWrap>;
 ///
 /// This way, when remove an interface, the ID's of other interfaces
 /// don't have have to be updated or mapped, because their place in the list doesn't change.
+///
+/// Disabled endpoints keep their place too, but aren't used to send
+/// frames until they are enabled again.
 enum EpWrap {
     Used(Arc<Ep>),
+    Disabled(Arc<Ep>),
     Void,
 }
 
+/// Frame counters for a single endpoint
+#[derive(Default)]
+struct EpStats {
+    frames_in: AtomicU64,
+    frames_out: AtomicU64,
+}
+
 /// A map of available endpoint drivers
 ///
 /// Currently the removing of drivers isn't supported, but it's
@@ -23,6 +35,8 @@ enum EpWrap {
 pub(crate) struct DriverMap {
     curr: AtomicUsize,
     map: RwLock<EpVec>,
+    /// Frame counters, indexed by endpoint ID
+    stats: RwLock<Vec<EpStats>>,
 }
 
 impl DriverMap {
@@ -38,6 +52,7 @@ impl DriverMap {
         let mut map = self.map.write().await;
         let curr = self.curr.fetch_add(1, Ordering::Relaxed);
         map.push(EpWrap::Used(ep));
+        self.stats.write().await.push(EpStats::default());
         curr
     }
 
@@ -47,35 +62,92 @@ impl DriverMap {
         std::mem::swap(&mut map[id], &mut EpWrap::Void);
     }
 
+    /// Enable or disable an endpoint
+    ///
+    /// Returns `false` if no endpoint with this ID exists.
+    pub(crate) async fn set_enabled(&self, id: usize, enabled: bool) -> bool {
+        let mut map = self.map.write().await;
+        let ep = match map.get_mut(id) {
+            Some(EpWrap::Used(ep)) | Some(EpWrap::Disabled(ep)) => Arc::clone(ep),
+            _ => return false,
+        };
+
+        map[id] = match enabled {
+            true => EpWrap::Used(ep),
+            false => EpWrap::Disabled(ep),
+        };
+        true
+    }
+
+    /// Check whether an endpoint is currently enabled
+    pub(crate) async fn enabled(&self, id: usize) -> bool {
+        let map = self.map.read().await;
+        matches!(map.get(id), Some(EpWrap::Used(_)))
+    }
+
     /// Get access to an endpoint via an Arc wrapper
+    ///
+    /// This also returns disabled endpoints.
     pub(crate) async fn get(&self, id: usize) -> Arc<Ep> {
         let map = self.map.read().await;
         Arc::clone(match map[id] {
-            EpWrap::Used(ref ep) => ep,
+            EpWrap::Used(ref ep) | EpWrap::Disabled(ref ep) => ep,
             EpWrap::Void => panic!("Trying to use a removed endpoint!"),
         })
     }
 
-    /// Get access to all endpoints wrapped in Arc
-    pub(crate) async fn get_all(&self) -> Vec<Arc<Ep>> {
+    /// Get access to all enabled endpoints wrapped in Arc, with their IDs
+    pub(crate) async fn get_all(&self) -> Vec<(usize, Arc<Ep>)> {
         let map = self.map.read().await;
         map.iter()
-            .filter_map(|ep| match ep {
-                EpWrap::Used(ref ep) => Some(Arc::clone(ep)),
+            .enumerate()
+            .filter_map(|(i, ep)| match ep {
+                EpWrap::Used(ref ep) => Some((i, Arc::clone(ep))),
                 _ => None,
             })
             .collect()
     }
 
-    /// Get all endpoints, except for the one provided via the ID
-    pub(crate) async fn get_without(&self, not: usize) -> Vec<Arc<Ep>> {
+    /// Get all enabled endpoints, except for the one provided via the ID
+    pub(crate) async fn get_without(&self, not: usize) -> Vec<(usize, Arc<Ep>)> {
         let map = self.map.read().await;
         map.iter()
             .enumerate()
             .filter_map(|(i, ep)| match ep {
-                EpWrap::Used(ref ep) if i != not => Some(Arc::clone(ep)),
+                EpWrap::Used(ref ep) if i != not => Some((i, Arc::clone(ep))),
                 _ => None,
             })
             .collect()
     }
+
+    /// Count a frame received via an endpoint
+    pub(crate) async fn count_in(&self, id: usize) {
+        if let Some(stats) = self.stats.read().await.get(id) {
+            stats.frames_in.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a frame sent via an endpoint
+    pub(crate) async fn count_out(&self, id: usize) {
+        if let Some(stats) = self.stats.read().await.get(id) {
+            stats.frames_out.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the frame counters of all endpoints that weren't removed
+    pub(crate) async fn metrics(&self) -> Vec<EndpointMetrics> {
+        let map = self.map.read().await;
+        let stats = self.stats.read().await;
+        map.iter()
+            .zip(stats.iter())
+            .enumerate()
+            .filter(|(_, (ep, _))| !matches!(ep, EpWrap::Void))
+            .map(|(id, (ep, stats))| EndpointMetrics {
+                id,
+                enabled: matches!(ep, EpWrap::Used(_)),
+                frames_in: stats.frames_in.load(Ordering::Relaxed),
+                frames_out: stats.frames_out.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
 }
@@ -1,36 +1,325 @@
-use async_std::sync::{Arc, RwLock};
-use netmod::{Frame, SeqId};
-use std::collections::BTreeSet;
+//! Remote frame journal
+//!
+//! The journal holds frames for addresses that aren't reachable yet,
+//! and replays them once a route appears.  It also remembers which
+//! flood frames were already seen, so that they are only handled and
+//! reflooded once.  Both are limited in size and age.
+
+use crate::core::{Collector, Dispatch, RouteTable, RouteType};
+use async_std::{
+    channel::{bounded, Receiver, Sender},
+    future,
+    sync::{Arc, RwLock},
+    task,
+};
+use chrono::{DateTime, Duration, Utc};
+use netmod::{Frame, Recipient, SeqId};
+use std::collections::{BTreeSet, VecDeque};
+
+/// The maximum number of frames held in the journal
+const MAX_FRAMES: usize = 1024;
+
+/// The maximum number of seen flood frames remembered
+const MAX_KNOWN: usize = 16 * 1024;
+
+/// How long frames are held, and seen frames are remembered, in seconds
+const MAX_AGE: i64 = 60 * 60;
+
+/// How often queued frames are retried if no new route appears
+const REPLAY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Identifies a single frame: its sequence ID and number in the sequence
+pub(crate) type FrameId = (SeqId, u32);
+
+fn expired(at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+    now - at > Duration::seconds(MAX_AGE)
+}
+
+/// Seen flood frames, oldest first
+#[derive(Default)]
+struct Known {
+    order: VecDeque<(FrameId, DateTime<Utc>)>,
+    set: BTreeSet<FrameId>,
+}
+
+impl Known {
+    /// Insert a frame ID, returning `false` if it was already known
+    fn insert(&mut self, fid: FrameId, at: DateTime<Utc>) -> bool {
+        if !self.set.insert(fid) {
+            return false;
+        }
+
+        self.order.push_back((fid, at));
+        while self.order.len() > MAX_KNOWN {
+            self.pop();
+        }
+        true
+    }
+
+    /// Forget frame IDs which are too old
+    fn expire(&mut self, now: DateTime<Utc>) {
+        while matches!(self.order.front(), Some((_, at)) if expired(*at, now)) {
+            self.pop();
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some((fid, _)) = self.order.pop_front() {
+            self.set.remove(&fid);
+        }
+    }
+}
 
 /// Remote frame journal
 pub(crate) struct Journal {
+    /// Frames that couldn't be delivered yet, oldest first
+    frames: RwLock<VecDeque<(Frame, DateTime<Utc>)>>,
     /// Keeps track of known frames to do reflood
-    known: RwLock<BTreeSet<SeqId>>,
+    known: RwLock<Known>,
+    routes: Arc<RouteTable>,
+    dispatch: Arc<Dispatch>,
+    collector: Arc<Collector>,
+    /// Wakes up the replay task when a new route appears
+    wake: (Sender<()>, Receiver<()>),
 }
 
 impl Journal {
-    pub(crate) fn new() -> Arc<Self> {
+    pub(crate) fn new(
+        routes: Arc<RouteTable>,
+        dispatch: Arc<Dispatch>,
+        collector: Arc<Collector>,
+    ) -> Arc<Self> {
         Arc::new(Self {
+            frames: Default::default(),
             known: Default::default(),
+            routes,
+            dispatch,
+            collector,
+            wake: bounded(1),
         })
     }
 
     /// Dispatches a long-running task to run the journal logic
+    ///
+    /// Queued frames are replayed whenever a new route appears, and
+    /// otherwise every `REPLAY_INTERVAL`.
     pub(crate) fn run(self: Arc<Self>) {
-        // task::spawn(async move { loop {} });
+        task::spawn(async move {
+            loop {
+                let _ = future::timeout(REPLAY_INTERVAL, self.wake.1.recv()).await;
+                self.replay().await;
+            }
+        });
     }
 
-    /// Add a new frame to the known set
-    pub(crate) async fn queue(&self, _: Frame) {}
+    /// Tell the journal that a route may have appeared
+    pub(crate) fn wake(&self) {
+        let _ = self.wake.0.try_send(());
+    }
 
-    /// Save a FrameID in the known journal page
-    #[allow(unused)]
-    pub(crate) async fn save(&self, fid: &SeqId) {
-        self.known.write().await.insert(fid.clone());
+    /// Queue a frame until its recipient becomes reachable
+    ///
+    /// If the journal is full the oldest frame is dropped.
+    pub(crate) async fn queue(&self, f: Frame) {
+        let mut frames = self.frames.write().await;
+        frames.push_back((f, Utc::now()));
+        if frames.len() > MAX_FRAMES {
+            debug!("Journal is full; dropping oldest frame");
+            frames.pop_front();
+        }
     }
 
-    /// Checks if a frame ID has not been seen before
-    pub(crate) async fn unknown(&self, fid: &SeqId) -> bool {
-        !self.known.read().await.contains(fid)
+    /// Save a frame in the known journal page
+    ///
+    /// Returns `false` if the frame was seen before.
+    pub(crate) async fn save(&self, f: &Frame) -> bool {
+        self.known
+            .write()
+            .await
+            .insert((f.seqid(), f.seq.num), Utc::now())
     }
+
+    /// Deliver all queued frames whose recipient is now reachable
+    ///
+    /// Frames that are too old are dropped.
+    pub(crate) async fn replay(&self) {
+        let now = Utc::now();
+        self.known.write().await.expire(now);
+
+        let queued: Vec<_> = self.frames.write().await.drain(..).collect();
+        let mut keep = VecDeque::new();
+        for (f, at) in queued {
+            let id = match f.recipient {
+                Recipient::User(id) if !expired(at, now) => id,
+                _ => {
+                    trace!("Dropping expired frame from journal");
+                    continue;
+                }
+            };
+
+            match self.routes.reachable(id).await {
+                Some(RouteType::Local) => self.collector.queue_and_spawn(f.seqid(), f).await,
+                Some(RouteType::Remote(_)) => {
+                    if let Err(e) = self.dispatch.send_one(f).await {
+                        warn!("Failed to forward journaled frame: {}", e);
+                    }
+                }
+                None => keep.push_back((f, at)),
+            }
+        }
+
+        // Frames queued in the meantime are newer than the kept ones
+        let mut frames = self.frames.write().await;
+        keep.append(&mut frames);
+        while keep.len() > MAX_FRAMES {
+            keep.pop_front();
+        }
+        *frames = keep;
+    }
+
+    /// Get the number of frames that couldn't be delivered yet
+    pub(crate) async fn len(&self) -> usize {
+        self.frames.read().await.len()
+    }
+
+    /// Get a copy of all queued frames and known frame IDs
+    pub(crate) async fn snapshot(
+        &self,
+    ) -> (Vec<(Frame, DateTime<Utc>)>, Vec<(FrameId, DateTime<Utc>)>) {
+        let frames = self.frames.read().await.iter().cloned().collect();
+        let known = self.known.read().await.order.iter().cloned().collect();
+        (frames, known)
+    }
+
+    /// Add frames and frame IDs from a previous snapshot
+    pub(crate) async fn restore(
+        &self,
+        frames: Vec<(Frame, DateTime<Utc>)>,
+        mut known: Vec<(FrameId, DateTime<Utc>)>,
+    ) {
+        let mut queued = self.frames.write().await;
+        let newer = std::mem::take(&mut *queued);
+        queued.extend(frames);
+        queued.extend(newer);
+        while queued.len() > MAX_FRAMES {
+            queued.pop_front();
+        }
+        drop(queued);
+
+        known.sort_by_key(|(_, at)| *at);
+        let mut k = self.known.write().await;
+        for (fid, at) in known {
+            k.insert(fid, at);
+        }
+        drop(k);
+
+        // Drop old entries and deliver what is reachable already
+        self.replay().await;
+    }
+}
+
+#[cfg(test)]
+fn journal() -> Arc<Journal> {
+    use crate::core::DriverMap;
+
+    let routes = RouteTable::new();
+    let collector = Collector::new();
+    let dispatch = Dispatch::new(
+        Arc::clone(&routes),
+        DriverMap::new(),
+        Arc::clone(&collector),
+    );
+    Journal::new(routes, dispatch, collector)
+}
+
+#[async_std::test]
+async fn seen_frames_survive_snapshot() {
+    use crate::core::Snapshot;
+    use identity::Identity;
+
+    let f = Frame::inline_flood(Identity::random(), vec![1, 3, 1, 2]);
+    let j = journal();
+    assert!(j.save(&f).await);
+    assert!(!j.save(&f).await);
+
+    let dir = std::env::temp_dir().join(format!("ratman-journal-{}", Identity::random()));
+    let (frames, known) = j.snapshot().await;
+    Snapshot {
+        frames,
+        known,
+        ..Default::default()
+    }
+    .save(&dir)
+    .unwrap();
+
+    let s = Snapshot::load(&dir).unwrap().unwrap();
+    let restored = journal();
+    restored.restore(s.frames, s.known).await;
+    assert!(!restored.save(&f).await);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[async_std::test]
+async fn replay_when_route_appears() {
+    use crate::{Message, Slicer, TimePair};
+    use identity::Identity;
+
+    let j = journal();
+    let id = Identity::random();
+    let msg = Message {
+        id: Identity::random(),
+        sender: Identity::random(),
+        recipient: Recipient::User(id),
+        payload: vec![1, 3, 1, 2],
+        timesig: TimePair::sending(),
+        sign: vec![],
+    };
+    let f = Slicer::slice(1312, msg).remove(0);
+
+    j.queue(f).await;
+    j.replay().await;
+    assert_eq!(j.len().await, 1);
+
+    j.routes.add_local(id).await.unwrap();
+    j.replay().await;
+    assert_eq!(j.len().await, 0);
+
+    // The collector can miss a wakeup, so poll it again on timeout
+    let timeout = std::time::Duration::from_millis(100);
+    let msg = loop {
+        if let Ok(msg) = future::timeout(timeout, j.collector.completed()).await {
+            break msg;
+        }
+    };
+    assert_eq!(msg.payload, vec![1, 3, 1, 2]);
+}
+
+#[async_std::test]
+async fn journal_is_bounded() {
+    use identity::Identity;
+    use netmod::SeqBuilder;
+
+    let j = journal();
+    for _ in 0..=MAX_FRAMES {
+        let id = Identity::random();
+        j.queue(
+            SeqBuilder::new(id, Recipient::User(id), id)
+                .add(vec![])
+                .build()
+                .remove(0),
+        )
+        .await;
+    }
+    assert_eq!(j.len().await, MAX_FRAMES);
+
+    // Frames which are too old are dropped on replay
+    let old = Utc::now() - Duration::seconds(MAX_AGE + 1);
+    j.frames
+        .write()
+        .await
+        .iter_mut()
+        .for_each(|(_, at)| *at = old);
+    j.replay().await;
+    assert_eq!(j.len().await, 0);
 }
@@ -9,6 +9,7 @@ mod dispatch;
 mod drivers;
 mod journal;
 mod routes;
+mod snapshot;
 mod switch;
 
 pub(self) use collector::Collector;
@@ -18,7 +19,9 @@ pub(self) use journal::Journal;
 pub(self) use routes::{EpTargetPair, RouteTable, RouteType};
 pub(self) use switch::Switch;
 
-use crate::{Endpoint, Error, Identity, Message, Result};
+pub(crate) use snapshot::Snapshot;
+
+use crate::{Endpoint, Error, Identity, Message, Metrics, Result};
 use async_std::sync::Arc;
 use netmod::Frame;
 
@@ -29,7 +32,7 @@ use netmod::Frame;
 pub(crate) struct Core {
     collector: Arc<Collector>,
     dispatch: Arc<Dispatch>,
-    _journal: Arc<Journal>,
+    journal: Arc<Journal>,
     routes: Arc<RouteTable>,
     switch: Arc<Switch>,
     drivers: Arc<DriverMap>,
@@ -40,18 +43,21 @@ impl Core {
     pub(crate) fn init() -> Self {
         let drivers = DriverMap::new();
         let routes = RouteTable::new();
-        let _journal = Journal::new();
-
         let collector = Collector::new();
         let dispatch = Dispatch::new(
             Arc::clone(&routes),
             Arc::clone(&drivers),
             Arc::clone(&collector),
         );
+        let journal = Journal::new(
+            Arc::clone(&routes),
+            Arc::clone(&dispatch),
+            Arc::clone(&collector),
+        );
 
         let switch = Switch::new(
             Arc::clone(&routes),
-            Arc::clone(&_journal),
+            Arc::clone(&journal),
             Arc::clone(&dispatch),
             Arc::clone(&collector),
             Arc::clone(&drivers),
@@ -59,13 +65,13 @@ impl Core {
 
         // Dispatch the runners
         Arc::clone(&switch).run();
-        Arc::clone(&_journal).run();
+        Arc::clone(&journal).run();
 
         Self {
             dispatch,
             routes,
             collector,
-            _journal,
+            journal,
             switch,
             drivers,
         }
@@ -107,6 +113,35 @@ impl Core {
         self.routes.discover().await
     }
 
+    /// Read the current values of all internal counters
+    pub(crate) async fn metrics(&self) -> Metrics {
+        let (remote_routes, local_users) = self.routes.count().await;
+        Metrics {
+            endpoints: self.drivers.metrics().await,
+            journal_frames: self.journal.len().await,
+            collector_workers: self.collector.num_workers().await,
+            remote_routes,
+            local_users,
+        }
+    }
+
+    /// Take a snapshot of the routing table and journal
+    pub(crate) async fn snapshot(&self) -> Snapshot {
+        let (frames, known) = self.journal.snapshot().await;
+        Snapshot {
+            routes: self.routes.snapshot().await,
+            frames,
+            known,
+            ..Default::default()
+        }
+    }
+
+    /// Restore the routing table and journal from a snapshot
+    pub(crate) async fn restore(&self, s: Snapshot) {
+        self.routes.restore(s.routes).await;
+        self.journal.restore(s.frames, s.known).await;
+    }
+
     /// Insert a new endpoint
     pub(crate) async fn add_ep(&self, ep: Arc<impl Endpoint + 'static + Send + Sync>) -> usize {
         let id = self.drivers.add(ep).await;
@@ -124,6 +159,14 @@ impl Core {
         self.drivers.remove(id).await;
     }
 
+    /// Enable or disable an endpoint
+    pub(crate) async fn set_ep_enabled(&self, id: usize, enabled: bool) -> Result<()> {
+        match self.drivers.set_enabled(id, enabled).await {
+            true => Ok(()),
+            false => Err(Error::NoEndpoint),
+        }
+    }
+
     /// Add a local user endpoint
     pub(crate) async fn add_local(&self, id: Identity) -> Result<()> {
         self.routes.add_local(id).await
@@ -6,27 +6,46 @@ use async_std::{
     sync::{Arc, Mutex},
     task,
 };
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use {identity::Identity, netmod::Target};
 
 /// A netmod endpoint ID and an endpoint target ID
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct EpTargetPair(pub(crate) u8, pub(crate) Target);
 
 /// Describes the reachability of a route
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum RouteType {
     Remote(EpTargetPair),
     Local,
 }
 
-/// An ephemeral routing table
+/// An entry in the routing table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Route {
+    pub(crate) tt: RouteType,
+    /// When this route was added or last updated
+    pub(crate) updated: DateTime<Utc>,
+}
+
+impl Route {
+    fn new(tt: RouteType) -> Self {
+        Self {
+            tt,
+            updated: Utc::now(),
+        }
+    }
+}
+
+/// An in-memory routing table
 ///
-/// It only captures the current state of best routes and has no
-/// persistence relationships.  It can update entries for topology
-/// changes, but these are not carried between sessions.
+/// It only captures the current state of best routes.  It can update
+/// entries for topology changes, which are carried between sessions
+/// by taking a snapshot of the table and restoring it later.
 pub(crate) struct RouteTable {
-    routes: Arc<Mutex<BTreeMap<Identity, RouteType>>>,
+    routes: Arc<Mutex<BTreeMap<Identity, Route>>>,
     new: IoPair<Identity>,
 }
 
@@ -44,7 +63,7 @@ impl RouteTable {
     /// to the `new` set which can be polled by calling `discovered().await`.
     pub(crate) async fn update(self: &Arc<Self>, if_: u8, t: Target, id: Identity) {
         let mut tbl = self.routes.lock().await;
-        let route = RouteType::Remote(EpTargetPair(if_, t));
+        let route = Route::new(RouteType::Remote(EpTargetPair(if_, t)));
 
         // Only "announce" a new user if it was not known before
         if tbl.insert(id, route).is_none() {
@@ -60,7 +79,8 @@ impl RouteTable {
 
     /// Track a local ID in the routes table
     pub(crate) async fn add_local(&self, id: Identity) -> Result<()> {
-        match self.routes.lock().await.insert(id, RouteType::Local) {
+        let route = Route::new(RouteType::Local);
+        match self.routes.lock().await.insert(id, route) {
             Some(_) => Err(Error::DuplicateUser),
             None => Ok(()),
         }
@@ -93,7 +113,7 @@ impl RouteTable {
     /// returns `None` if the specified ID isn't remote.  To get more
     /// control over how the table is queried, use `reachable` instead
     pub(crate) async fn resolve(&self, id: Identity) -> Option<EpTargetPair> {
-        match self.routes.lock().await.get(&id).cloned()? {
+        match self.routes.lock().await.get(&id)?.tt {
             RouteType::Remote(ep) => Some(ep),
             RouteType::Local => None,
         }
@@ -101,6 +121,33 @@ impl RouteTable {
 
     /// Check if an ID is reachable via currently known routes
     pub(crate) async fn reachable(&self, id: Identity) -> Option<RouteType> {
-        self.routes.lock().await.get(&id).cloned()
+        self.routes.lock().await.get(&id).map(|r| r.tt)
+    }
+
+    /// Count remote routes and local users in the routing table
+    pub(crate) async fn count(&self) -> (usize, usize) {
+        let tbl = self.routes.lock().await;
+        let local = tbl.values().filter(|r| r.tt == RouteType::Local).count();
+        (tbl.len() - local, local)
+    }
+
+    /// Get a copy of all entries in the routing table
+    pub(crate) async fn snapshot(&self) -> Vec<(Identity, Route)> {
+        self.routes
+            .lock()
+            .await
+            .iter()
+            .map(|(id, r)| (*id, r.clone()))
+            .collect()
+    }
+
+    /// Insert entries from a previous snapshot
+    ///
+    /// Entries which are already present in the table are kept.
+    pub(crate) async fn restore(&self, routes: Vec<(Identity, Route)>) {
+        let mut tbl = self.routes.lock().await;
+        for (id, route) in routes {
+            tbl.entry(id).or_insert(route);
+        }
     }
 }
@@ -0,0 +1,59 @@
+//! Router state snapshots
+//!
+//! A snapshot captures everything a router learned about the network
+//! and its local users, so that a restarted router doesn't have to
+//! rediscover the whole network.  It is stored in a single file,
+//! which is replaced atomically when a new snapshot is taken.
+
+use crate::{
+    core::{journal::FrameId, routes::Route},
+    Error, Identity, Result,
+};
+use chrono::{DateTime, Utc};
+use netmod::Frame;
+use serde::{Deserialize, Serialize};
+use std::{fs, io::ErrorKind, path::Path};
+
+/// The name of the snapshot file in the state directory
+const SNAPSHOT_FILE: &str = "router.snapshot";
+
+/// The stored state of a router
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    /// All routes and local users, with the time they were updated
+    pub(crate) routes: Vec<(Identity, Route)>,
+    /// Local users which were marked as online
+    pub(crate) online: Vec<Identity>,
+    /// Frames in the journal that haven't been delivered yet, with
+    /// the time they were queued
+    pub(crate) frames: Vec<(Frame, DateTime<Utc>)>,
+    /// IDs of flood frames that were already seen, with the time they
+    /// were first seen
+    pub(crate) known: Vec<(FrameId, DateTime<Utc>)>,
+}
+
+impl Snapshot {
+    /// Write the snapshot to a state directory
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let data = bincode::serialize(self).map_err(|_| Error::EncodeFailed)?;
+
+        fs::create_dir_all(dir)?;
+        let tmp = dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, dir.join(SNAPSHOT_FILE))?;
+        Ok(())
+    }
+
+    /// Load a snapshot from a state directory, if one exists
+    pub(crate) fn load(dir: &Path) -> Result<Option<Self>> {
+        let data = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        bincode::deserialize(&data)
+            .map(Some)
+            .map_err(|_| Error::DecodeFailed)
+    }
+}
@@ -68,16 +68,23 @@ impl Switch {
                 _ => continue,
             };
 
+            // Frames from disabled endpoints are dropped
+            if !self.drivers.enabled(id).await {
+                trace!("Dropping frame from disabled endpoint {}", id);
+                continue;
+            }
+
             trace!("Receiving frame...");
+            self.drivers.count_in(id).await;
 
             // Switch the traffic to the appropriate place
             use {Recipient::*, RouteType::*};
             match f.recipient {
                 Flood => {
-                    let seqid = f.seq.seqid;
-                    if self.journal.unknown(&seqid).await {
+                    if self.journal.save(&f).await {
                         if let Some(sender) = Protocol::is_announce(&f) {
                             self.routes.update(id as u8, t, sender).await;
+                            self.journal.wake();
                         } else {
                             self.collector.queue_and_spawn(f.seqid(), f.clone()).await;
                         }
@@ -87,7 +94,11 @@ impl Switch {
                 }
                 User(id) => match self.routes.reachable(id).await {
                     Some(Local) => self.collector.queue_and_spawn(f.seqid(), f).await,
-                    Some(Remote(_)) => self.dispatch.send_one(f).await.unwrap(),
+                    Some(Remote(_)) => {
+                        if let Err(e) = self.dispatch.send_one(f).await {
+                            warn!("Failed to forward frame: {}", e);
+                        }
+                    }
                     None => self.journal.queue(f).await,
                 },
             }
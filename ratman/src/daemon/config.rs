@@ -0,0 +1,234 @@
+//! Configuration file support for ratmand
+//!
+//! All options of ratmand can also be set in a TOML file, which is
+//! passed via `--config`.  Options given on the command line take
+//! precedence over values from the file.  A complete configuration
+//! looks like this:
+//!
+//! ```toml
+//! verbosity = "debug"
+//! api_bind = "127.0.0.1:9020"
+//! metrics_bind = "127.0.0.1:9021"
+//! max_stream_len = 4294967296
+//! accept_unknown_peers = false
+//! peers = ["inet#10.0.0.10:9000"]
+//!
+//! [inet]
+//! enable = true
+//! bind = "[::]:9000"
+//! upnp = false
+//! trusted_keys = ["<64 hexadecimal characters>"]
+//! peer_exchange = 8
+//! rate_limit = 1048576
+//!
+//! [udp]
+//! bind = "[::]:9002"
+//!
+//! [discovery]
+//! enable = true
+//! port = 9001
+//! iface = "eth0"
+//! mode = "ipv6"
+//!
+//! [queue]
+//! size = 256
+//! policy = "block"
+//! inbox_quota = 16777216
+//! ```
+//!
+//! Instead of listing `peers`, a `peer_file` can be provided.
+
+use crate::daemon::{peers, QueuePolicy};
+use netmod_inet::PublicKey;
+use netmod_lan::Mode as LanMode;
+use serde::Deserialize;
+use std::{fs, io, net::SocketAddr, path::PathBuf};
+
+/// Log levels accepted by `verbosity` and `--verbosity`
+pub const LEVELS: &[&str] = &["trace", "debug", "info", "warn", "error"];
+
+/// An error in the configuration file
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {0:?}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("{0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("invalid value for `{0}`: {1}")]
+    Invalid(&'static str, String),
+}
+
+/// Settings for the inet overlay driver
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InetConfig {
+    pub enable: Option<bool>,
+    pub bind: Option<SocketAddr>,
+    pub upnp: Option<bool>,
+    pub trusted_keys: Option<Vec<String>>,
+    pub peer_exchange: Option<usize>,
+    pub rate_limit: Option<u64>,
+}
+
+/// Settings for the reliable UDP overlay driver
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpConfig {
+    pub bind: Option<SocketAddr>,
+}
+
+/// Settings for local peer discovery
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    pub enable: Option<bool>,
+    pub port: Option<u16>,
+    pub iface: Option<String>,
+    pub mode: Option<String>,
+}
+
+/// Settings for client queues and inboxes
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueSection {
+    pub size: Option<usize>,
+    pub policy: Option<String>,
+    pub inbox_quota: Option<u64>,
+}
+
+/// The contents of a ratmand configuration file
+///
+/// Every value is optional, so that unset values can fall back to
+/// their command line defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub verbosity: Option<String>,
+    pub api_bind: Option<SocketAddr>,
+    pub metrics_bind: Option<SocketAddr>,
+    pub max_stream_len: Option<u64>,
+    pub accept_unknown_peers: Option<bool>,
+    pub peers: Option<Vec<String>>,
+    pub peer_file: Option<PathBuf>,
+    pub inet: InetConfig,
+    pub udp: UdpConfig,
+    pub discovery: DiscoveryConfig,
+    pub queue: QueueSection,
+}
+
+impl Config {
+    /// Load and validate a configuration file
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, ConfigError> {
+        let path = path.into();
+        match fs::read_to_string(&path) {
+            Ok(s) => Self::parse(&s),
+            Err(e) => Err(ConfigError::Io(path, e)),
+        }
+    }
+
+    /// Parse and validate a configuration
+    pub fn parse(s: &str) -> Result<Self, ConfigError> {
+        let cfg: Self = toml::from_str(s)?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Check values which the parser can't check on its own
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(ref lvl) = self.verbosity {
+            if !LEVELS.contains(&lvl.as_str()) {
+                let msg = format!("'{}' is not one of {}", lvl, LEVELS.join(", "));
+                return Err(ConfigError::Invalid("verbosity", msg));
+            }
+        }
+
+        if self.peers.is_some() && self.peer_file.is_some() {
+            let msg = "can't be combined with `peer_file`".into();
+            return Err(ConfigError::Invalid("peers", msg));
+        }
+
+        for peer in self.peers.iter().flatten() {
+            peers::check_peer(peer).map_err(|e| ConfigError::Invalid("peers", e))?;
+        }
+
+        for key in self.inet.trusted_keys.iter().flatten() {
+            key.parse::<PublicKey>()
+                .map_err(|e| ConfigError::Invalid("inet.trusted_keys", e.to_string()))?;
+        }
+
+        if let Some(ref mode) = self.discovery.mode {
+            mode.parse::<LanMode>()
+                .map_err(|e| ConfigError::Invalid("discovery.mode", e))?;
+        }
+
+        if self.queue.size == Some(0) {
+            let msg = "must be at least 1".into();
+            return Err(ConfigError::Invalid("queue.size", msg));
+        }
+
+        if let Some(ref policy) = self.queue.policy {
+            policy
+                .parse::<QueuePolicy>()
+                .map_err(|e| ConfigError::Invalid("queue.policy", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn parse_config() {
+    let cfg = Config::parse(
+        r#"
+verbosity = "debug"
+peers = ["inet#10.0.0.10:9000", "inet#[fe80::1]:9000L"]
+
+[inet]
+bind = "[::]:9000"
+trusted_keys = ["7f1b06c1a8a7d6bd2a19cbc35f8e7b1c9e0f5a4b3c2d1e0f9a8b7c6d5e4f3a2b"]
+peer_exchange = 4
+rate_limit = 65536
+
+[udp]
+bind = "127.0.0.1:9002"
+
+[discovery]
+mode = "ipv4-broadcast"
+
+[queue]
+policy = "spill"
+"#,
+    )
+    .unwrap();
+
+    assert_eq!(cfg.verbosity.as_deref(), Some("debug"));
+    assert_eq!(cfg.peers.map(|p| p.len()), Some(2));
+    assert_eq!(cfg.inet.bind, Some("[::]:9000".parse().unwrap()));
+    assert_eq!(cfg.inet.trusted_keys.map(|k| k.len()), Some(1));
+    assert_eq!(cfg.inet.peer_exchange, Some(4));
+    assert_eq!(cfg.inet.rate_limit, Some(65536));
+    assert_eq!(cfg.udp.bind, Some("127.0.0.1:9002".parse().unwrap()));
+    assert_eq!(cfg.discovery.port, None);
+    assert_eq!(cfg.discovery.mode.as_deref(), Some("ipv4-broadcast"));
+    assert_eq!(cfg.queue.policy.as_deref(), Some("spill"));
+}
+
+#[test]
+fn reject_invalid_config() {
+    let invalid = [
+        "verbosity = \"loud\"",
+        "api_bind = \"localhost\"",
+        "peers = [\"carrier-pigeon#home\"]",
+        "peers = []\npeer_file = \"peers.txt\"",
+        "[discovery]\nport = 90010",
+        "[discovery]\nmode = \"ipx\"",
+        "[inet]\ntrusted_keys = [\"abcd\"]",
+        "[queue]\nsize = 0",
+        "[queue]\npolicy = \"ignore\"",
+        "unknown = true",
+    ];
+
+    for cfg in invalid.iter() {
+        assert!(Config::parse(cfg).is_err(), "accepted: {}", cfg);
+    }
+}
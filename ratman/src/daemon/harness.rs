@@ -0,0 +1,154 @@
+//! An in-process Ratman network for testing client applications
+//!
+//! A [`TestNetwork`] runs several routers which are linked via
+//! `netmod-mem`.  Each of them serves the client API in memory, so
+//! tests can connect any number of [`RatmanIpc`] clients to them
+//! without opening sockets or starting `ratmand`.
+//!
+//! ```no_run
+//! # async fn test() -> ratman_client::Result<()> {
+//! use ratman::daemon::harness::TestNetwork;
+//!
+//! let net = TestNetwork::new(2).await;
+//! let alice = net.node(0).client().await?;
+//! let bob = net.node(1).client().await?;
+//!
+//! alice.send_to(bob.address(), b"Hello Bob!".to_vec()).await?;
+//! let (_, msg) = bob.next().await.unwrap();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! This module is only available with the `harness` feature.
+
+use crate::{
+    daemon::{
+        pipe::MemStream, serve, state, DaemonState, Drivers, QueueConfig, DEFAULT_INBOX_QUOTA,
+        DEFAULT_MAX_STREAM_LEN,
+    },
+    Router,
+};
+use async_std::{
+    channel::{unbounded, Sender},
+    future, io,
+    stream::StreamExt,
+    task,
+};
+use netmod_mem::MemMod;
+use ratman_client::{Identity, RatmanIpc, Result, CONTROL_TOKEN_FILE};
+use std::path::PathBuf;
+
+/// A single router serving the client API in memory
+///
+/// Its state is kept in a temporary directory, which is removed
+/// again when the node is dropped.
+pub struct TestNode {
+    router: Router,
+    conns: Sender<MemStream>,
+    data_dir: PathBuf,
+}
+
+impl TestNode {
+    /// Serve the client API for an existing router
+    pub fn start(router: Router) -> Self {
+        let data_dir = std::env::temp_dir().join(format!("ratman-harness-{}", Identity::random()));
+        let (conns, rx) = unbounded();
+        let listen = Box::pin(rx.map(|s| Ok(state::Io::mem(s))));
+
+        let mut state = DaemonState::new(
+            listen,
+            router.clone(),
+            QueueConfig::default(),
+            DEFAULT_INBOX_QUOTA,
+            DEFAULT_MAX_STREAM_LEN,
+            data_dir.clone(),
+        );
+        let r = router.clone();
+        task::spawn(async move {
+            if let Err(e) = serve(r, &mut state, Drivers::default(), future::pending()).await {
+                error!("Test node stopped: {}", e);
+            }
+        });
+
+        Self {
+            router,
+            conns,
+            data_dir,
+        }
+    }
+
+    /// Get the router of this node
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    /// Connect a client with a new address
+    pub async fn client(&self) -> Result<RatmanIpc> {
+        self.connect(None).await
+    }
+
+    /// Connect a client, optionally re-using an existing address
+    pub async fn connect(&self, addr: Option<Identity>) -> Result<RatmanIpc> {
+        let conns = self.conns.clone();
+        RatmanIpc::connect_with(move || open(conns.clone()), addr).await
+    }
+
+    /// Connect a client which may change the node's configuration
+    pub async fn control(&self) -> Result<RatmanIpc> {
+        let token = std::fs::read(self.data_dir.join(CONTROL_TOKEN_FILE))?;
+        let conns = self.conns.clone();
+        RatmanIpc::control_with(move || open(conns.clone()), token).await
+    }
+}
+
+/// Open a new in-memory connection to a node
+async fn open(conns: Sender<MemStream>) -> io::Result<MemStream> {
+    let (client, daemon) = MemStream::pair();
+    conns
+        .send(daemon)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+    Ok(client)
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        self.conns.close();
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+/// A virtual network of [`TestNode`]s
+pub struct TestNetwork {
+    nodes: Vec<TestNode>,
+}
+
+impl TestNetwork {
+    /// Create a network of `n` nodes, each linked to the next one
+    pub async fn new(n: usize) -> Self {
+        let routers: Vec<_> = (0..n).map(|_| Router::new()).collect();
+        for pair in routers.windows(2) {
+            let (a, b) = MemMod::make_pair();
+            pair[0].add_endpoint(a).await;
+            pair[1].add_endpoint(b).await;
+        }
+
+        Self {
+            nodes: routers.into_iter().map(TestNode::start).collect(),
+        }
+    }
+
+    /// Get the node at a position in the network
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` is out of bounds.
+    pub fn node(&self, idx: usize) -> &TestNode {
+        &self.nodes[idx]
+    }
+
+    /// Get all nodes of the network, in order
+    pub fn nodes(&self) -> &[TestNode] {
+        &self.nodes
+    }
+}
@@ -0,0 +1,204 @@
+//! Persistent inboxes for registered addresses
+//!
+//! When a message arrives for an address whose client is currently
+//! disconnected it is written to an inbox file in the daemon's data
+//! directory.  The next time the client completes the `Setup`
+//! handshake all stored messages are forwarded to it.
+//!
+//! Inbox files are only accessed while holding the lock on the
+//! [`OnlineMap`](super::state::OnlineMap), which keeps storing and
+//! draining from racing each other.
+
+use async_std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, ErrorKind, WriteExt},
+};
+use identity::Identity;
+use std::path::PathBuf;
+use types::{
+    api::{self, ApiMessageEnum, Receive},
+    decode_message, encode_message, read_with_limit, write_with_length, Error, Result,
+};
+
+/// The default maximum inbox size per address in bytes
+pub const DEFAULT_QUOTA: u64 = 16 * 1024 * 1024;
+
+/// Encode a message for storage, returning the encoded length
+pub(crate) fn encode(recv: Receive) -> Result<(Vec<u8>, u64)> {
+    let buf = encode_message(api::api_recv(recv))?;
+    let len = buf.len() as u64 + 8; // length prefix
+    Ok((buf, len))
+}
+
+/// Append an encoded message to a file of length-prefixed API messages
+pub(crate) async fn write_to(path: &PathBuf, buf: &Vec<u8>) -> Result<()> {
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    write_with_length(&mut f, buf).await?;
+    Ok(())
+}
+
+/// Decode a message encoded by [`encode`]
+pub(crate) fn decode(buf: &Vec<u8>) -> Option<Receive> {
+    match decode_message(buf).ok()?.inner {
+        Some(ApiMessageEnum::recv(recv)) => Some(recv),
+        _ => None,
+    }
+}
+
+/// Replace a file with a list of encoded messages
+pub(crate) async fn write_all(path: &PathBuf, bufs: &[Vec<u8>]) -> Result<()> {
+    let mut f = File::create(path).await?;
+    for buf in bufs {
+        write_with_length(&mut f, buf).await?;
+    }
+    f.flush().await?;
+    Ok(())
+}
+
+/// Read all messages from a file written by [`write_to`]
+///
+/// No message can be larger than the quota `max` of the file it was
+/// stored in.  Reading stops at the first message which is too large
+/// or truncated, because everything after it can't be trusted.
+pub(crate) async fn read_all(path: &PathBuf, max: u64) -> Result<Vec<Receive>> {
+    let mut f = match File::open(path).await {
+        Ok(f) => BufReader::new(f),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    // These files are written by the daemon itself, and may contain
+    // messages which are larger than a single API frame
+    let mut msgs = vec![];
+    loop {
+        let buf = match read_with_limit(&mut f, max).await {
+            Ok(buf) => buf,
+            Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                warn!("Corrupt message in {:?}: {}; skipping the rest", path, e);
+                break;
+            }
+        };
+
+        match decode(&buf) {
+            Some(recv) => msgs.push(recv),
+            None => warn!("Invalid message in {:?}; skipping...", path),
+        }
+    }
+
+    Ok(msgs)
+}
+
+/// Store messages for addresses whose client is offline
+#[derive(Clone, Debug)]
+pub(crate) struct Inbox {
+    dir: PathBuf,
+    /// Maximum size of a single inbox in bytes
+    quota: u64,
+}
+
+impl Inbox {
+    pub(crate) fn new(dir: PathBuf, quota: u64) -> Self {
+        trace!("Ensure inbox directory exists: {:?}", dir);
+        let _ = std::fs::create_dir_all(&dir);
+        Self { dir, quota }
+    }
+
+    /// Get the maximum size of a single inbox in bytes
+    pub(crate) fn quota(&self) -> u64 {
+        self.quota
+    }
+
+    fn path(&self, id: Identity) -> PathBuf {
+        self.dir.join(format!("{}.inbox", id))
+    }
+
+    /// Store a message for an offline address
+    ///
+    /// Messages that would grow the inbox beyond its quota are
+    /// dropped.
+    pub(crate) async fn store(&self, id: Identity, recv: Receive) -> Result<()> {
+        let path = self.path(id);
+        let size = fs::metadata(&path).await.map(|m| m.len()).unwrap_or(0);
+
+        let (buf, len) = encode(recv)?;
+        if size + len > self.quota {
+            warn!("Inbox for {} is over quota: dropping message", id);
+            return Ok(());
+        }
+
+        write_to(&path, &buf).await
+    }
+
+    /// Take all stored messages for an address out of its inbox
+    pub(crate) async fn take(&self, id: Identity) -> Result<Vec<Receive>> {
+        let path = self.path(id);
+        let msgs = read_all(&path, self.quota).await?;
+        if !msgs.is_empty() {
+            fs::remove_file(&path).await?;
+        }
+        Ok(msgs)
+    }
+}
+
+#[async_std::test]
+async fn store_and_take() {
+    let dir = std::env::temp_dir().join(format!("ratmand-inbox-{}", Identity::random()));
+    let (id, sender) = (Identity::random(), Identity::random());
+    let recv = |payload| {
+        let msg = types::message::received(
+            Identity::random(),
+            sender,
+            Some(id),
+            payload,
+            String::new(),
+            vec![],
+        );
+        api::receive_default(msg)
+    };
+
+    // Large enough to hold exactly two messages
+    let quota = 2 * encode(recv(vec![0; 32])).unwrap().1;
+    let inbox = Inbox::new(dir.clone(), quota);
+    for i in 0..3 {
+        inbox.store(id, recv(vec![i; 32])).await.unwrap();
+    }
+
+    let msgs = inbox.take(id).await.unwrap();
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(msgs[1].get_msg().get_payload(), &[1; 32]);
+    assert!(inbox.take(id).await.unwrap().is_empty());
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[async_std::test]
+async fn skip_corrupt_messages() {
+    let dir = std::env::temp_dir().join(format!("ratmand-inbox-{}", Identity::random()));
+    let id = Identity::random();
+    let msg = types::message::received(id, id, Some(id), vec![1; 32], String::new(), vec![]);
+    let (buf, len) = encode(api::receive_default(msg)).unwrap();
+
+    // A valid message, followed by a length prefix claiming a huge
+    // message, which must not be allocated
+    let inbox = Inbox::new(dir.clone(), 2 * len);
+    write_to(&inbox.path(id), &buf).await.unwrap();
+    OpenOptions::new()
+        .append(true)
+        .open(inbox.path(id))
+        .await
+        .unwrap()
+        .write_all(&(u64::MAX / 2).to_be_bytes())
+        .await
+        .unwrap();
+
+    let msgs = inbox.take(id).await.unwrap();
+    assert_eq!(msgs.len(), 1);
+    assert!(inbox.take(id).await.unwrap().is_empty());
+
+    let _ = std::fs::remove_dir_all(dir);
+}
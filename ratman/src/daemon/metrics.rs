@@ -0,0 +1,258 @@
+//! An HTTP endpoint for monitoring systems
+//!
+//! When ratmand is started with `--metrics-bind`, the router's
+//! internal counters are served on `/metrics`, in the Prometheus text
+//! exposition format.  This includes the number of frames each inet
+//! peer dropped because its queue was full.  This module is only
+//! available with the `metrics` feature.
+
+use crate::{daemon::Drivers, Metrics, Router};
+use async_std::{
+    io::{self, ReadExt, WriteExt},
+    net::{TcpListener, TcpStream},
+    stream::StreamExt,
+    sync::Arc,
+    task,
+};
+use netmod_inet::PeerInfo;
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// The longest request header that is accepted
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve metrics on an address until the listener fails
+pub async fn serve(
+    r: Router,
+    drivers: Drivers,
+    clients: Arc<AtomicUsize>,
+    bind: SocketAddr,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    info!("Serving metrics on http://{}/metrics", bind);
+    listen(listener, r, drivers, clients).await
+}
+
+async fn listen(
+    listener: TcpListener,
+    r: Router,
+    drivers: Drivers,
+    clients: Arc<AtomicUsize>,
+) -> io::Result<()> {
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let (r, drivers, clients) = (r.clone(), drivers.clone(), Arc::clone(&clients));
+        task::spawn(async move {
+            if let Err(e) = handle(stream?, r, drivers, clients).await {
+                debug!("Failed to answer metrics request: {}", e);
+            }
+            io::Result::Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+/// Answer a single HTTP request, then close the connection
+async fn handle(
+    mut stream: TcpStream,
+    r: Router,
+    drivers: Drivers,
+    clients: Arc<AtomicUsize>,
+) -> io::Result<()> {
+    let head = io::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await?;
+    let mut request = head.split_whitespace();
+
+    let (status, body) = match (request.next(), request.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let metrics = r.metrics().await;
+            let peers = match drivers.inet {
+                Some(ref inet) => inet.peers().await,
+                None => vec![],
+            };
+            let clients = clients.load(Ordering::Relaxed);
+            ("200 OK", render(&metrics, &peers, clients))
+        }
+        (Some("GET"), _) => ("404 Not Found", "not found\n".into()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".into()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Read the request line and headers
+async fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = vec![];
+    let mut buf = [0; 1024];
+    while !head.ends_with(b"\r\n\r\n") {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+
+        head.extend_from_slice(&buf[..len]);
+        if head.len() > MAX_REQUEST_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too long",
+            ));
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Write a metric family header
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Render metrics in the Prometheus text format
+fn render(m: &Metrics, peers: &[PeerInfo], clients: usize) -> String {
+    let mut out = String::new();
+
+    family(
+        &mut out,
+        "ratman_frames_in_total",
+        "counter",
+        "Frames received via an endpoint",
+    );
+    for ep in &m.endpoints {
+        let _ = writeln!(
+            out,
+            "ratman_frames_in_total{{endpoint=\"{}\"}} {}",
+            ep.id, ep.frames_in
+        );
+    }
+
+    family(
+        &mut out,
+        "ratman_frames_out_total",
+        "counter",
+        "Frames sent via an endpoint",
+    );
+    for ep in &m.endpoints {
+        let _ = writeln!(
+            out,
+            "ratman_frames_out_total{{endpoint=\"{}\"}} {}",
+            ep.id, ep.frames_out
+        );
+    }
+
+    family(
+        &mut out,
+        "ratman_endpoint_enabled",
+        "gauge",
+        "Whether an endpoint is enabled",
+    );
+    for ep in &m.endpoints {
+        let _ = writeln!(
+            out,
+            "ratman_endpoint_enabled{{endpoint=\"{}\"}} {}",
+            ep.id, ep.enabled as u8
+        );
+    }
+
+    family(
+        &mut out,
+        "ratman_inet_dropped_frames_total",
+        "counter",
+        "Frames dropped because the queue of an inet peer was full",
+    );
+    for p in peers {
+        let counts = [("unicast", p.dropped_unicast), ("flood", p.dropped_flood)];
+        for (class, dropped) in counts.iter() {
+            let _ = writeln!(
+                out,
+                "ratman_inet_dropped_frames_total{{peer=\"{}\",class=\"{}\"}} {}",
+                p.addr, class, dropped
+            );
+        }
+    }
+
+    let gauges = [
+        (
+            "ratman_journal_frames",
+            "Frames waiting in the journal",
+            m.journal_frames,
+        ),
+        (
+            "ratman_collector_workers",
+            "Messages being collected",
+            m.collector_workers,
+        ),
+        (
+            "ratman_routes",
+            "Remote addresses with a known route",
+            m.remote_routes,
+        ),
+        ("ratman_local_users", "Local addresses", m.local_users),
+        ("ratman_clients", "Connected IPC clients", clients),
+    ];
+    for (name, help, value) in gauges.iter() {
+        family(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    out
+}
+
+#[async_std::test]
+async fn serve_metrics() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let clients = Arc::new(AtomicUsize::new(2));
+    task::spawn(listen(listener, Router::new(), Drivers::default(), clients));
+
+    let get = |path: &'static str| async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        resp
+    };
+
+    let resp = get("/metrics").await;
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.contains("\nratman_routes 0\n"));
+    assert!(resp.contains("\nratman_clients 2\n"));
+
+    assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn render_dropped_frames() {
+    use netmod_inet::{LinkType, PeerState};
+
+    let peer = PeerInfo {
+        addr: "10.0.0.10:9000".parse().unwrap(),
+        state: PeerState::Duplex,
+        link: LinkType::Bidirect,
+        bytes_sent: 0,
+        bytes_received: 0,
+        last_seen: None,
+        dropped_unicast: 1,
+        dropped_flood: 7,
+    };
+    let out = render(&Metrics::default(), &[peer], 0);
+    assert!(out.contains(
+        "\nratman_inet_dropped_frames_total{peer=\"10.0.0.10:9000\",class=\"flood\"} 7\n"
+    ));
+}
@@ -1,10 +1,42 @@
 //! Module only loaded when Ratman is running as a daemon
 
+pub mod config;
+mod inbox;
 mod parse;
 mod peers;
+mod queue;
+mod service;
 mod state;
 mod transform;
 
+#[cfg(feature = "harness")]
+pub mod harness;
+#[cfg(feature = "harness")]
+mod pipe;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(not(feature = "metrics"))]
+pub mod metrics {
+    use super::Drivers;
+    use crate::Router;
+    use async_std::{io, sync::Arc};
+    use std::{net::SocketAddr, sync::atomic::AtomicUsize};
+
+    pub async fn serve(
+        _: Router,
+        _: Drivers,
+        _: Arc<AtomicUsize>,
+        _: SocketAddr,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "ratmand was built without the `metrics` feature",
+        ))
+    }
+}
+
 #[cfg(feature = "upnp")]
 pub mod upnp;
 
@@ -15,44 +47,88 @@ pub mod upnp {
     }
 }
 
-use std::net::SocketAddr;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{atomic::Ordering, OnceLock},
+    time::Duration,
+};
 
 use crate::{Message, Recipient, Router};
-use async_std::{net::TcpListener, task::spawn};
-use state::{DaemonState, OnlineMap};
-use tracing_subscriber::{filter::LevelFilter, fmt, EnvFilter};
+use async_std::{
+    future,
+    net::TcpListener,
+    prelude::FutureExt,
+    stream::StreamExt,
+    sync::Arc,
+    task::{self, spawn},
+};
+use inbox::Inbox;
+use state::{DaemonState, Io, OnlineMap};
+use tracing_subscriber::{filter::LevelFilter, fmt, reload, EnvFilter};
 use types::Result;
 
-pub use peers::attach_peers;
+pub use inbox::DEFAULT_QUOTA as DEFAULT_INBOX_QUOTA;
+pub use parse::DEFAULT_MAX_STREAM_LEN;
+pub use peers::{attach_peers, detach_peers, load_keypair, Drivers, PeerError};
+pub use queue::{QueueConfig, QueuePolicy};
 
 pub fn elog<S: Into<String>>(msg: S, code: u16) -> ! {
     error!("{}", msg.into());
     std::process::exit(code.into());
 }
 
+/// Handle to change the log filter while the daemon is running
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, fmt::Formatter>> = OnceLock::new();
+
+/// Build the log filter for a verbosity level
+fn log_filter(lvl: &str) -> Option<EnvFilter> {
+    let level = match lvl {
+        "trace" => LevelFilter::TRACE,
+        "debug" => LevelFilter::DEBUG,
+        "info" => LevelFilter::INFO,
+        "warn" => LevelFilter::WARN,
+        "error" => LevelFilter::ERROR,
+        _ => return None,
+    };
+
+    Some(
+        EnvFilter::default()
+            .add_directive(level.into())
+            .add_directive("async_std=error".parse().unwrap())
+            .add_directive("async_io=error".parse().unwrap())
+            .add_directive("polling=error".parse().unwrap())
+            .add_directive("trust_dns_proto=error".parse().unwrap())
+            .add_directive("trust_dns_resolver=warn".parse().unwrap())
+            .add_directive("mio=error".parse().unwrap()),
+    )
+}
+
 pub fn setup_logging(lvl: &str) {
-    let filter = EnvFilter::default()
-        .add_directive(match lvl {
-            "trace" => LevelFilter::TRACE.into(),
-            "debug" => LevelFilter::DEBUG.into(),
-            "info" => LevelFilter::INFO.into(),
-            "warn" => LevelFilter::WARN.into(),
-            "error" => LevelFilter::ERROR.into(),
-            _ => unreachable!(),
-        })
-        .add_directive("async_std=error".parse().unwrap())
-        .add_directive("async_io=error".parse().unwrap())
-        .add_directive("polling=error".parse().unwrap())
-        .add_directive("trust_dns_proto=error".parse().unwrap())
-        .add_directive("trust_dns_resolver=warn".parse().unwrap())
-        .add_directive("mio=error".parse().unwrap());
+    let filter = log_filter(lvl).unwrap_or_else(|| unreachable!());
 
     // Initialise the logger
-    fmt().with_env_filter(filter).init();
+    let builder = fmt().with_env_filter(filter).with_filter_reloading();
+    let _ = LOG_FILTER.set(builder.reload_handle());
+    builder.init();
     info!("Initialised logger: welcome to ratmand!");
 }
 
-async fn run_relay(r: Router, online: OnlineMap) {
+/// Change the verbosity of a running daemon
+pub fn set_log_level(lvl: &str) -> std::result::Result<(), String> {
+    let filter = log_filter(lvl).ok_or_else(|| format!("unknown log level '{}'", lvl))?;
+    LOG_FILTER
+        .get()
+        .ok_or("logging has not been initialised")?
+        .reload(filter)
+        .map_err(|e| e.to_string())?;
+
+    info!("Changed log level to '{}'", lvl);
+    Ok(())
+}
+
+async fn run_relay(r: Router, online: OnlineMap, inbox: Inbox) {
     loop {
         let Message {
             id,
@@ -76,22 +152,13 @@ async fn run_relay(r: Router, online: OnlineMap) {
         ));
 
         match recipient {
-            Recipient::User(ref id) => {
-                if let Some(Some(ref mut io)) = online.lock().await.get(id).map(Clone::clone) {
-                    info!("Forwarding message to online client!");
-                    if let Err(e) = parse::forward_recv(io.as_io(), recv).await {
-                        error!("Failed to forward received message: {}", e);
-                    }
-                }
-            }
+            Recipient::User(id) => state::deliver(&online, &inbox, id, recv).await,
             Recipient::Flood => {
-                for (_, ref mut io) in online.lock().await.iter_mut() {
-                    if io.is_none() && continue {}
-                    if let Err(e) =
-                        parse::forward_recv(io.as_mut().unwrap().as_io(), recv.clone()).await
-                    {
-                        error!("Failed to forward received message: {}", e);
-                    }
+                // Don't hold the lock while pushing because this may
+                // block until the client has caught up
+                let queues: Vec<_> = online.lock().await.values().flatten().cloned().collect();
+                for queue in queues {
+                    let _ = queue.push(recv.clone()).await;
                 }
             }
         }
@@ -99,22 +166,142 @@ async fn run_relay(r: Router, online: OnlineMap) {
 }
 
 /// Run the daemon!
-pub async fn run(r: Router, addr: SocketAddr) -> Result<()> {
+///
+/// Clients can add and remove peers of the running overlay drivers
+/// at runtime.  If a metrics address is given, the router's
+/// counters are served there via HTTP, along with the frames each
+/// inet peer dropped.
+///
+/// The daemon runs until it receives `SIGTERM` or `SIGINT`, after
+/// which it saves its state and returns.
+pub async fn run(
+    r: Router,
+    addr: SocketAddr,
+    queue: QueueConfig,
+    inbox_quota: u64,
+    max_stream_len: u64,
+    drivers: Drivers,
+    metrics_bind: Option<SocketAddr>,
+) -> Result<()> {
     info!("Listening for API connections on socket {:?}", addr);
+    info!(
+        "Client queues hold {} messages with policy '{}'",
+        queue.size, queue.policy
+    );
     let listener = TcpListener::bind(addr).await?;
-    let mut state = DaemonState::new(&listener, r.clone());
+    let conns = Box::pin(listener.incoming().map(|s| s.map(Io::tcp)));
+    let data_dir = state::default_data_dir();
+
+    // Pick up where the last session left off
+    if let Err(e) = r.restore(&data_dir).await {
+        warn!("Failed to restore router state: {}", e);
+    }
+    spawn(take_snapshots(r.clone(), data_dir.clone()));
+
+    let mut state = DaemonState::new(
+        conns,
+        r.clone(),
+        queue,
+        inbox_quota,
+        max_stream_len,
+        data_dir.clone(),
+    );
+    if let Some(bind) = metrics_bind {
+        let (r, drivers, clients) = (r.clone(), drivers.clone(), state.get_clients());
+        spawn(async move {
+            if let Err(e) = metrics::serve(r, drivers, clients, bind).await {
+                error!("Failed to serve metrics: {}", e);
+            }
+        });
+    }
+
+    // Install the signal handlers first, so that a signal right after
+    // reporting readiness still shuts the daemon down gracefully
+    let signals = service::install_signal_handlers();
+    if let Err(e) = service::notify("READY=1") {
+        warn!("Failed to notify service manager: {}", e);
+    }
+
+    let stop = async {
+        match signals {
+            Ok(signals) => service::shutdown_signal(signals).await,
+            Err(e) => {
+                warn!("Failed to install signal handlers: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    serve(r.clone(), &mut state, drivers.clone(), stop).await?;
+    shutdown(r, state, drivers, data_dir).await;
+    Ok(())
+}
+
+/// Save all state and stop the overlay drivers
+async fn shutdown(r: Router, state: DaemonState<'_>, drivers: Drivers, data_dir: PathBuf) {
+    let _ = service::notify("STOPPING=1");
+
+    state.offline_all().await;
+    if let Err(e) = r.snapshot(&data_dir).await {
+        error!("Failed to save router state: {}", e);
+    }
+    if let Err(e) = state.sync_users().await {
+        error!("Failed to sync known addresses: {}", e);
+    }
+
+    drivers.stop().await;
+    info!("Shutdown complete");
+}
+
+/// How often the router state is saved while the daemon is running
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Periodically save the router state to the data directory
+async fn take_snapshots(r: Router, data_dir: PathBuf) {
+    loop {
+        task::sleep(SNAPSHOT_INTERVAL).await;
+        if let Err(e) = r.snapshot(&data_dir).await {
+            warn!("Failed to save router state: {}", e);
+        }
+    }
+}
+
+/// Handle client connections until no more can be accepted, or
+/// until `stop` completes
+async fn serve(
+    r: Router,
+    state: &mut DaemonState<'_>,
+    drivers: Drivers,
+    stop: impl Future<Output = ()>,
+) -> Result<()> {
     let online = state.get_online().await;
+    let inbox = state.get_inbox();
+    let clients = state.get_clients();
 
-    let relay = spawn(run_relay(r.clone(), online));
+    let relay = spawn(run_relay(r.clone(), Arc::clone(&online), inbox.clone()));
 
-    while let Ok(io) = state.listen_for_connections().await {
-        let io = match io {
-            Some(io) => io,
-            None => continue,
+    let mut stop = Box::pin(stop);
+    loop {
+        let next = async { Some(state.listen_for_connections().await) };
+        let stopped = async {
+            (&mut stop).await;
+            None
+        };
+        let conn = match next.race(stopped).await {
+            Some(Ok(Some(conn))) => conn,
+            _ => break,
         };
 
         info!("Established new client connection");
-        spawn(parse::parse_stream(r.clone(), io.clone()));
+        let (r, online, inbox) = (r.clone(), Arc::clone(&online), inbox.clone());
+        let (drivers, clients) = (drivers.clone(), Arc::clone(&clients));
+        clients.fetch_add(1, Ordering::Relaxed);
+        spawn(async move {
+            parse::parse_stream(r, conn.io, drivers, conn.privileged, conn.streams).await;
+            if let Some((id, queue)) = conn.client {
+                state::set_offline(&online, &inbox, id, &queue).await;
+            }
+            clients.fetch_sub(1, Ordering::Relaxed);
+        });
     }
 
     relay.cancel().await;
@@ -1,48 +1,331 @@
 use crate::{
-    daemon::{state::Io, transform},
+    daemon::{
+        self,
+        peers::{self, Drivers, PeerError},
+        queue::QueueConfig,
+        state::Io,
+        transform,
+    },
     Result, Router,
 };
 
-use async_std::io::{Read, Write};
+use async_std::{
+    fs::{self, File},
+    io::{prelude::WriteExt, Read, Write},
+};
 use identity::Identity;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
 use types::{
     api::{
-        all_peers, api_peers, api_setup, online_ack, ApiMessageEnum, Peers, Peers_Type, Receive,
-        Send, Setup, Setup_Type, Setup_oneof__id,
+        all_peers, anonymous_ack, api_ack, api_chunk, api_error, api_peers, api_recv, api_setup,
+        chunk, error, features, online_ack, with_id, with_queue, with_version, ApiError,
+        ApiMessage, ApiMessageEnum, Chunk, ErrorCode, Manage, Manage_Type, Peers, Peers_Type,
+        Receive, Send, Setup, Setup_Type, Setup_oneof__id,
     },
     encode_message, parse_message, write_with_length, Error as ParseError, Result as ParseResult,
+    MAX_PAYLOAD_LEN,
 };
 
-async fn handle_send(r: &Router, send: Send) -> Result<()> {
+/// The result of handling a request
+type Response = std::result::Result<Option<ApiMessage>, ApiError>;
+
+/// Turn a router error into an error response
+fn respond(res: Result<Option<ApiMessage>>) -> Response {
+    res.map_err(|e| {
+        error!("Failed to execute command: {}", e);
+        transform::error_to_api(&e)
+    })
+}
+
+async fn handle_send(r: &Router, send: Send) -> Result<Option<ApiMessage>> {
     debug!("Queuing message to send");
     for msg in transform::send_to_message(send) {
         r.send(msg).await?;
     }
-    Ok(())
+    Ok(Some(api_ack()))
 }
 
-async fn handle_setup(_io: &mut Io, _r: &Router, s: Setup) -> Result<()> {
+async fn handle_setup(_io: &mut Io, _r: &Router, s: Setup) -> Result<Option<ApiMessage>> {
     trace!("Handle setup message: {:?}", s);
-    Ok(())
+    Ok(None)
 }
 
-async fn handle_peers(io: &mut Io, r: &Router, peers: Peers) -> Result<()> {
+async fn handle_peers(r: &Router, peers: Peers) -> Result<Option<ApiMessage>> {
     if peers.field_type != Peers_Type::REQ {
-        return Ok(()); // Ignore all other messages
+        return Ok(None); // Ignore all other messages
     }
 
     let all = r.known_addresses().await;
-    let response = encode_message(api_peers(all_peers(all))).unwrap();
-    write_with_length(io.as_io(), &response).await.unwrap(); // baaaaad
+    Ok(Some(api_peers(all_peers(all))))
+}
+
+/// Change the configuration of the running daemon
+async fn handle_manage(r: &Router, drivers: &Drivers, m: Manage) -> Response {
+    let invalid = |msg: String| error(ErrorCode::INVALID_REQUEST, msg);
+
+    match m.field_type {
+        Manage_Type::ADD_PEERS | Manage_Type::REMOVE_PEERS => {
+            for peer in m.peers.iter() {
+                peers::check_peer(peer).map_err(invalid)?;
+            }
+
+            let list = m.peers.iter().map(|p| p.as_str()).collect();
+            let res = match m.field_type {
+                Manage_Type::ADD_PEERS => peers::attach_peers(drivers, list).await,
+                _ => peers::detach_peers(drivers, list).await,
+            };
+            res.map_err(|e| match e {
+                PeerError::NotRunning(_) => error(ErrorCode::NOT_SUPPORTED, e.to_string()),
+                e => invalid(e.to_string()),
+            })?;
+        }
+        Manage_Type::ENABLE_ENDPOINT | Manage_Type::DISABLE_ENDPOINT => {
+            let enabled = m.field_type == Manage_Type::ENABLE_ENDPOINT;
+            info!(
+                "{} endpoint {}",
+                if enabled { "Enabling" } else { "Disabling" },
+                m.endpoint
+            );
+            respond(
+                r.set_endpoint_enabled(m.endpoint as usize, enabled)
+                    .await
+                    .map(|_| None),
+            )?;
+        }
+        Manage_Type::LOG_LEVEL => daemon::set_log_level(&m.level).map_err(invalid)?,
+    }
+
+    Ok(Some(api_ack()))
+}
+
+/// The default maximum total payload length of a streamed message
+pub const DEFAULT_MAX_STREAM_LEN: u64 = 4 * 1024 * 1024 * 1024;
+
+/// The maximum number of streams a client can have open at once
+const MAX_STREAMS: usize = 4;
+
+/// Used to give every spooled payload its own file
+static NEXT_SPOOL: AtomicU64 = AtomicU64::new(1);
+
+/// A payload which is currently being streamed by a client
+struct Pending {
+    /// The request which started the stream
+    id: u64,
+    send: Send,
+    /// The next expected sequence number
+    seq: u64,
+    /// The number of bytes spooled so far
+    len: u64,
+    path: PathBuf,
+    file: File,
+}
+
+/// Payloads which are currently being streamed by a client
+///
+/// Chunks are spooled to a file instead of being kept in memory.  A
+/// streamed `Send` is only passed to the router once its last chunk
+/// has arrived, and is then answered with the ID of the `Send`.
+pub(crate) struct Streams {
+    /// The directory payloads are spooled to
+    dir: PathBuf,
+    /// The maximum total payload length of a streamed message
+    max_len: u64,
+    pending: BTreeMap<u64, Pending>,
+}
+
+impl Streams {
+    pub(crate) fn new(dir: PathBuf, max_len: u64) -> Self {
+        Self {
+            dir,
+            max_len,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    async fn start(&mut self, id: u64, send: Send) -> Response {
+        if self.pending.contains_key(&send.stream) {
+            return Err(error(
+                ErrorCode::INVALID_REQUEST,
+                format!("stream {} already exists", send.stream),
+            ));
+        }
+
+        if self.pending.len() >= MAX_STREAMS {
+            return Err(error(
+                ErrorCode::INVALID_REQUEST,
+                format!("too many open streams (at most {})", MAX_STREAMS),
+            ));
+        }
+
+        let path = self.dir.join(format!(
+            "{}.{}",
+            std::process::id(),
+            NEXT_SPOOL.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::create(&path)
+            .await
+            .map_err(|e| spool_error(send.stream, e))?;
+
+        debug!("Starting payload stream {}", send.stream);
+        let stream = send.stream;
+        let pending = Pending {
+            id,
+            send,
+            seq: 0,
+            len: 0,
+            path,
+            file,
+        };
+        self.pending.insert(stream, pending);
+        Ok(None)
+    }
+
+    /// Append a chunk to its stream, returning the message when done
+    ///
+    /// Errors carry the ID of the request which should be answered,
+    /// which is `0` for chunks of unknown streams.
+    async fn push(
+        &mut self,
+        c: Chunk,
+    ) -> std::result::Result<Option<(u64, Send)>, (u64, ApiError)> {
+        let p = self.pending.get_mut(&c.stream).ok_or_else(|| {
+            let msg = format!("unknown stream {}", c.stream);
+            (0, error(ErrorCode::INVALID_REQUEST, msg))
+        })?;
+        let id = p.id;
+
+        if c.seq != p.seq {
+            let msg = format!(
+                "stream {}: expected chunk {}, got {}",
+                c.stream, p.seq, c.seq
+            );
+            self.remove(c.stream);
+            return Err((id, error(ErrorCode::INVALID_REQUEST, msg)));
+        }
+
+        p.len += c.get_data().len() as u64;
+        if p.len > self.max_len {
+            let msg = format!(
+                "stream {} exceeds the maximum length of {} bytes",
+                c.stream, self.max_len
+            );
+            self.remove(c.stream);
+            return Err((id, error(ErrorCode::PAYLOAD_TOO_LARGE, msg)));
+        }
+
+        if let Err(e) = p.file.write_all(c.get_data()).await {
+            self.remove(c.stream);
+            return Err((id, spool_error(c.stream, e)));
+        }
+        p.seq += 1;
+
+        if !c.last {
+            return Ok(None);
+        }
+
+        // The router needs the whole payload to sign and slice it
+        let mut p = self.pending.remove(&c.stream).unwrap();
+        let payload = match p.file.flush().await {
+            Ok(()) => fs::read(&p.path).await,
+            Err(e) => Err(e),
+        };
+        let _ = std::fs::remove_file(&p.path);
+
+        p.send.stream = 0;
+        p.send
+            .mut_msg()
+            .set_payload(payload.map_err(|e| (id, spool_error(c.stream, e)))?);
+        Ok(Some((id, p.send)))
+    }
+
+    /// Drop a stream and its spooled payload
+    fn remove(&mut self, stream: u64) {
+        if let Some(p) = self.pending.remove(&stream) {
+            let _ = std::fs::remove_file(&p.path);
+        }
+    }
+}
+
+impl Drop for Streams {
+    fn drop(&mut self) {
+        for p in self.pending.values() {
+            let _ = std::fs::remove_file(&p.path);
+        }
+    }
+}
+
+fn spool_error(stream: u64, e: std::io::Error) -> ApiError {
+    error!("Failed to spool stream {}: {}", stream, e);
+    error(
+        ErrorCode::UNKNOWN,
+        format!("failed to spool stream {}: {}", stream, e),
+    )
+}
+
+/// Write a response to the client, carrying the ID of its request
+async fn send_response(io: &mut Io, id: u64, msg: ApiMessage) -> ParseResult<()> {
+    let response = encode_message(with_id(msg, id))?;
+    let lock = io.write_lock();
+    let _lock = lock.lock().await;
+    write_with_length(io, &response).await?;
     Ok(())
 }
 
-async fn send_online_ack<Io: Write + Unpin>(io: &mut Io, id: Identity) -> ParseResult<()> {
-    let ack = encode_message(api_setup(online_ack(id)))?;
+/// The version reported to clients during the handshake
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The set of protocol features supported by this daemon
+const FEATURES: &[&str] = &[
+    features::REQUEST_ID,
+    features::ERRORS,
+    features::STREAMS,
+    features::MANAGE,
+];
+
+/// Send an ACK for a setup message, with an optional assigned address
+///
+/// The ACK also tells the client how the daemon queues messages for it.
+async fn send_ack<Io: Write + Unpin>(
+    io: &mut Io,
+    id: Option<Identity>,
+    queue: &QueueConfig,
+) -> ParseResult<()> {
+    let setup = match id {
+        Some(id) => online_ack(id),
+        None => anonymous_ack(),
+    };
+
+    let setup = with_queue(setup, queue.policy.into(), queue.size as u32);
+    let ack = encode_message(api_setup(with_version(setup, VERSION, FEATURES)))?;
     write_with_length(io, &ack).await?;
     Ok(())
 }
 
+/// The result of a successful handshake
+pub(crate) struct Handshake {
+    /// Address and token of the client, unless it is anonymous
+    pub(crate) auth: Option<(Identity, Vec<u8>)>,
+    /// Protocol features supported by the client
+    pub(crate) features: Vec<String>,
+    /// Whether the client presented the daemon's control token, which
+    /// allows it to change the daemon's configuration
+    pub(crate) privileged: bool,
+}
+
+/// Compare a token in constant time
+fn token_matches(token: &[u8], expected: &[u8]) -> bool {
+    token.len() == expected.len()
+        && token
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
 /// Handle the initial handshake with the daemon
 ///
 /// Wait for a message to come in.  Either it is
@@ -53,11 +336,15 @@ async fn send_online_ack<Io: Write + Unpin>(io: &mut Io, id: Identity) -> ParseR
 /// 2. An `Online` without attached identity
 ///   - Assign an address
 ///   - Return address and auth token
-/// 3. Any other payload is invalid
+/// 3. An `Anonymous` message, optionally with a control token
+///   - Check that it matches `control_token`
+/// 4. Any other payload is invalid
 pub(crate) async fn handle_auth<Io: Read + Write + Unpin>(
     io: &mut Io,
     r: &Router,
-) -> ParseResult<Option<(Identity, Vec<u8>)>> {
+    queue: &QueueConfig,
+    control_token: &[u8],
+) -> ParseResult<Handshake> {
     debug!("Handle authentication request for new connection");
 
     let one_of = parse_message(io)
@@ -65,6 +352,21 @@ pub(crate) async fn handle_auth<Io: Read + Write + Unpin>(
         .map(|msg| msg.inner)?
         .ok_or(ParseError::InvalidAuth)?;
 
+    let features = match one_of {
+        ApiMessageEnum::setup(ref setup) => {
+            debug!("Client library version: {}", setup.get_version());
+            setup.features.to_vec()
+        }
+        _ => vec![],
+    };
+    let done = |auth, privileged| {
+        Ok(Handshake {
+            auth,
+            features,
+            privileged,
+        })
+    };
+
     match one_of {
         ApiMessageEnum::setup(setup) if setup.field_type == Setup_Type::ONLINE => {
             let id = setup._id;
@@ -76,63 +378,244 @@ pub(crate) async fn handle_auth<Io: Read + Write + Unpin>(
                     let id = Identity::from_bytes(id.as_slice());
                     let _ = r.add_user(id).await;
                     r.online(id).await.unwrap();
-                    send_online_ack(io, id).await?;
+                    send_ack(io, Some(id), queue).await?;
                     debug!("Authorisation for known client");
-                    Ok(Some((id, vec![])))
+                    done(Some((id, vec![])), false)
                 }
                 (None, None) => {
                     let id = Identity::random();
                     r.add_user(id).await.unwrap();
                     r.online(id).await.unwrap();
-                    send_online_ack(io, id).await?;
+                    send_ack(io, Some(id), queue).await?;
                     debug!("Authorisation for new client");
-                    Ok(Some((id, vec![])))
+                    done(Some((id, vec![])), false)
                 }
                 _ => {
                     debug!("Failed to authenticate client");
-                    Err(ParseError::InvalidAuth)
+                    reject_auth(io).await
                 }
             }
         }
         // If the client wants to remain anonymous we don't return an ID/token pair
         ApiMessageEnum::setup(setup) if setup.field_type == Setup_Type::ANONYMOUS => {
+            let privileged = setup.has_token();
+            if privileged && !token_matches(setup.get_token(), control_token) {
+                debug!("Anonymous client presented an invalid control token");
+                return reject_auth(io).await;
+            }
+
+            send_ack(io, None, queue).await?;
             debug!("Authorisation for anonymous client");
-            Ok(None)
+            done(None, privileged)
         }
-        _ => Err(ParseError::InvalidAuth),
+        _ => reject_auth(io).await,
     }
 }
 
+/// Tell the client why its handshake was rejected
+async fn reject_auth<Io: Write + Unpin, T>(io: &mut Io) -> ParseResult<T> {
+    let err = api_error(error(ErrorCode::INVALID_AUTH, "invalid authentication"));
+    write_with_length(io, &encode_message(err)?).await?;
+    Err(ParseError::InvalidAuth)
+}
+
 /// Parse messages from a stream until it terminates
-pub(crate) async fn parse_stream(router: Router, mut io: Io) {
+///
+/// Every request that carries an ID is answered, either with the
+/// result of the request, an `Ack`, or an `Error` describing why it
+/// failed.  Requests without an ID only receive errors.
+///
+/// Only `privileged` clients may change the daemon's configuration.
+pub(crate) async fn parse_stream(
+    router: Router,
+    mut io: Io,
+    drivers: Drivers,
+    privileged: bool,
+    mut streams: Streams,
+) {
     loop {
         // Match on the msg type and call the appropriate handler
-        match parse_message(io.as_io()).await.map(|msg| msg.inner) {
-            Ok(Some(one_of)) => match one_of {
-                ApiMessageEnum::send(send) => handle_send(&router, send).await,
-                ApiMessageEnum::setup(setup) => handle_setup(&mut io, &router, setup).await,
-                ApiMessageEnum::peers(peers) => handle_peers(&mut io, &router, peers).await,
-                ApiMessageEnum::recv(_) => continue, // Ignore "Receive" messages
+        let (id, res) = match parse_message(&mut io).await.map(|msg| (msg.id, msg.inner)) {
+            Ok((id, Some(one_of))) => match one_of {
+                ApiMessageEnum::send(send) if send.stream != 0 => {
+                    (id, streams.start(id, send).await)
+                }
+                ApiMessageEnum::send(send) => (id, respond(handle_send(&router, send).await)),
+                ApiMessageEnum::chunk(c) => match streams.push(c).await {
+                    Ok(Some((id, send))) => (id, respond(handle_send(&router, send).await)),
+                    Ok(None) => continue,
+                    Err((id, e)) => (id, Err(e)),
+                },
+                ApiMessageEnum::setup(setup) => {
+                    (id, respond(handle_setup(&mut io, &router, setup).await))
+                }
+                ApiMessageEnum::peers(peers) => (id, respond(handle_peers(&router, peers).await)),
+                ApiMessageEnum::manage(_) if !privileged => {
+                    warn!("Client without the control token tried to manage the daemon");
+                    let msg = "changing the daemon's configuration requires its control token";
+                    (id, Err(error(ErrorCode::NOT_PERMITTED, msg)))
+                }
+                ApiMessageEnum::manage(m) => (id, handle_manage(&router, &drivers, m).await),
+                // Ignore messages that only the daemon sends
+                ApiMessageEnum::recv(_) | ApiMessageEnum::error(_) | ApiMessageEnum::ack(_) => {
+                    continue
+                }
             },
-            Ok(None) => {
+            Ok((id, None)) => {
                 warn!("Received invalid message: empty payload");
-                continue;
+                (id, Err(error(ErrorCode::INVALID_REQUEST, "empty payload")))
+            }
+            // We can't know where the next frame starts, so the
+            // connection is closed after reporting the error
+            Err(ParseError::FrameTooLarge(len)) => {
+                warn!("Client sent a frame of {} bytes; closing connection", len);
+                let err = error(
+                    ErrorCode::PAYLOAD_TOO_LARGE,
+                    ParseError::FrameTooLarge(len).to_string(),
+                );
+                let _ = send_response(&mut io, 0, api_error(err)).await;
+                break;
             }
             Err(e) => {
                 trace!("Error: {:?}", e);
                 info!("Stream was dropped by client");
                 break;
             }
+        };
+
+        let response = match res {
+            // Plain ACKs are only useful to clients that wait for them
+            Ok(Some(msg)) if id != 0 || !msg.has_ack() => msg,
+            Ok(_) => continue,
+            Err(e) => api_error(e),
+        };
+
+        if let Err(e) = send_response(&mut io, id, response).await {
+            warn!("Failed to send response: {}", e);
+            break;
         }
-        .unwrap_or_else(|e| error!("Failed to execute command: {:?}", e));
     }
 }
 
-pub(crate) async fn forward_recv<Io: Write + Unpin>(io: &mut Io, r: Receive) -> ParseResult<()> {
-    let api = types::api::api_recv(r);
-    trace!("Encoding received message...");
-    let msg = types::encode_message(api)?;
-    trace!("Forwarding payload through stream");
-    write_with_length(io, &msg).await?;
+/// Stream IDs used for payloads sent to clients
+static NEXT_STREAM: AtomicU64 = AtomicU64::new(1);
+
+/// Forward a received message to a client
+///
+/// Payloads larger than `MAX_PAYLOAD_LEN` are split into chunks if
+/// the client supports streams.
+pub(crate) async fn forward_recv(io: &mut Io, mut r: Receive, streams: bool) -> ParseResult<()> {
+    let lock = io.write_lock();
+    let _lock = lock.lock().await;
+    let payload_len = r.get_msg().get_payload().len();
+    if !streams || payload_len <= MAX_PAYLOAD_LEN {
+        trace!("Encoding received message...");
+        let msg = encode_message(api_recv(r))?;
+        trace!("Forwarding payload through stream");
+        write_with_length(io, &msg).await?;
+        return Ok(());
+    }
+
+    let stream = NEXT_STREAM.fetch_add(1, Ordering::Relaxed);
+    debug!(
+        "Streaming {} byte payload as stream {}",
+        payload_len, stream
+    );
+    let payload = r.mut_msg().take_payload();
+    r.set_stream(stream);
+    write_with_length(io, &encode_message(api_recv(r))?).await?;
+
+    let num = payload_len.div_ceil(MAX_PAYLOAD_LEN);
+    for (seq, data) in payload.chunks(MAX_PAYLOAD_LEN).enumerate() {
+        let c = chunk(stream, seq as u64, data.to_vec(), seq + 1 == num);
+        write_with_length(io, &encode_message(api_chunk(c))?).await?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+fn test_streams(max_len: u64) -> Streams {
+    let dir = std::env::temp_dir().join(format!("ratmand-streams-{}", Identity::random()));
+    std::fs::create_dir_all(&dir).unwrap();
+    Streams::new(dir, max_len)
+}
+
+#[async_std::test]
+async fn stream_chunks_in_order() {
+    let msg = types::message::new(Identity::random(), vec![], vec![], vec![]);
+    let mut send = types::api::send_flood(msg);
+    send.set_stream(1);
+
+    let mut streams = test_streams(DEFAULT_MAX_STREAM_LEN);
+    assert!(streams.start(13, send.clone()).await.is_ok());
+    assert!(streams.start(14, send).await.is_err());
+
+    assert!(streams
+        .push(chunk(1, 0, vec![1, 3], false))
+        .await
+        .unwrap()
+        .is_none());
+    let (id, send) = streams
+        .push(chunk(1, 1, vec![1, 2], true))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((id, send.stream), (13, 0));
+    assert_eq!(send.get_msg().get_payload(), &[1, 3, 1, 2]);
+
+    // Chunks for finished streams and chunks out of order are rejected
+    assert!(streams.push(chunk(1, 2, vec![], true)).await.is_err());
+
+    // Spooled payloads are removed once they are no longer needed
+    assert_eq!(std::fs::read_dir(&streams.dir).unwrap().count(), 0);
+}
+
+#[async_std::test]
+async fn stream_length_limit() {
+    let msg = types::message::new(Identity::random(), vec![], vec![], vec![]);
+    let mut send = types::api::send_flood(msg);
+    send.set_stream(1);
+
+    let mut streams = test_streams(64);
+    assert!(streams.start(13, send).await.is_ok());
+
+    for seq in 0..4 {
+        let c = chunk(1, seq, vec![0; 16], false);
+        assert!(streams.push(c).await.unwrap().is_none());
+    }
+
+    // The error is addressed to the request which started the stream
+    let (id, err) = streams.push(chunk(1, 4, vec![0], true)).await.unwrap_err();
+    assert_eq!((id, err.get_code()), (13, ErrorCode::PAYLOAD_TOO_LARGE));
+    assert!(streams.pending.is_empty());
+    assert_eq!(std::fs::read_dir(&streams.dir).unwrap().count(), 0);
+}
+
+#[async_std::test]
+async fn stream_count_limit() {
+    let msg = types::message::new(Identity::random(), vec![], vec![], vec![]);
+    let mut streams = test_streams(DEFAULT_MAX_STREAM_LEN);
+
+    for stream in 1..=MAX_STREAMS as u64 + 1 {
+        let mut send = types::api::send_flood(msg.clone());
+        send.set_stream(stream);
+
+        let res = streams.start(stream, send).await;
+        match stream as usize <= MAX_STREAMS {
+            true => assert!(res.is_ok()),
+            false => assert_eq!(res.unwrap_err().get_code(), ErrorCode::INVALID_REQUEST),
+        }
+    }
+
+    // Finishing a stream makes room for a new one
+    let done = streams.push(chunk(1, 0, vec![], true)).await.unwrap();
+    assert!(done.is_some());
+    let mut send = types::api::send_flood(msg);
+    send.set_stream(1312);
+    assert!(streams.start(1312, send).await.is_ok());
+
+    // Unfinished payloads are removed when the connection closes
+    let dir = streams.dir.clone();
+    drop(streams);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+}
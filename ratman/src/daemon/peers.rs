@@ -1,8 +1,117 @@
-use netmod_inet::{Endpoint as InetEndpoint, Result as InetResult};
+use crate::daemon::state::default_data_dir;
+use async_std::sync::Arc;
+use netmod_inet::{Endpoint as InetEndpoint, Error as InetError, Keypair};
+use netmod_udp::{Endpoint as UdpEndpoint, Error as UdpError};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+};
+
+/// Load the keypair of the inet driver, or generate a new one
+///
+/// The secret key is stored as `inet.key` in the data directory, so
+/// that the node keeps its public key across restarts.
+pub fn load_keypair() -> io::Result<Keypair> {
+    let dir = default_data_dir();
+    let path = dir.join("inet.key");
+    match fs::read(&path) {
+        Ok(secret) => Keypair::from_bytes(&secret)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("Generating new inet keypair in {:?}", path);
+            let keypair = Keypair::generate();
+            fs::create_dir_all(dir)?;
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)?
+                .write_all(&keypair.to_bytes())?;
+            Ok(keypair)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The overlay drivers that peers can be added to
+///
+/// Each driver is `None` if it isn't running.
+#[derive(Clone, Default)]
+pub struct Drivers {
+    pub inet: Option<Arc<InetEndpoint>>,
+    pub udp: Option<Arc<UdpEndpoint>>,
+}
+
+impl Drivers {
+    /// Stop all running drivers
+    pub async fn stop(&self) {
+        if let Some(ref inet) = self.inet {
+            inet.stop().await;
+        }
+        if let Some(ref udp) = self.udp {
+            udp.stop().await;
+        }
+    }
+}
+
+/// An error while adding or removing peers
+#[derive(Debug, thiserror::Error)]
+pub enum PeerError {
+    #[error("the driver for '{0}#' peers is not running")]
+    NotRunning(&'static str),
+    #[error(transparent)]
+    Inet(#[from] InetError),
+    #[error(transparent)]
+    Udp(#[from] UdpError),
+}
 
 /// Parse a peer and introduce it to the appropriate netmod metadata
-pub async fn attach_peers(ep: &InetEndpoint, p: Vec<&str>) -> InetResult<()> {
+///
+/// Peers of all running drivers are added, even if another driver
+/// isn't running or failed.
+pub async fn attach_peers(drivers: &Drivers, p: Vec<&str>) -> Result<(), PeerError> {
+    let (inet, udp) = select_peers(p);
+    for peer in inet.iter() {
+        debug!("Initialising 'inet' peering session with: '{}'", peer);
+    }
+    for peer in udp.iter() {
+        debug!("Initialising 'quic' peering session with: '{}'", peer);
+    }
+
+    let inet = match (&drivers.inet, inet.is_empty()) {
+        (_, true) => Ok(()),
+        (Some(ep), false) => ep.add_peers(inet).await.map_err(Into::into),
+        (None, false) => Err(PeerError::NotRunning("inet")),
+    };
+    let udp = match (&drivers.udp, udp.is_empty()) {
+        (_, true) => Ok(()),
+        (Some(ep), false) => ep.add_peers(udp).await.map_err(Into::into),
+        (None, false) => Err(PeerError::NotRunning("quic")),
+    };
+    inet.and(udp)
+}
+
+/// Parse a peer and remove it from the appropriate netmod metadata
+pub async fn detach_peers(drivers: &Drivers, p: Vec<&str>) -> Result<(), PeerError> {
+    let (inet, udp) = select_peers(p);
+    let inet = match (&drivers.inet, inet.is_empty()) {
+        (_, true) => Ok(()),
+        (Some(ep), false) => ep.remove_peers(inet).await.map_err(Into::into),
+        (None, false) => Err(PeerError::NotRunning("inet")),
+    };
+    let udp = match (&drivers.udp, udp.is_empty()) {
+        (_, true) => Ok(()),
+        (Some(ep), false) => ep.remove_peers(udp).await.map_err(Into::into),
+        (None, false) => Err(PeerError::NotRunning("quic")),
+    };
+    inet.and(udp)
+}
+
+/// Select the addresses of all `inet` and `quic` peers
+fn select_peers(p: Vec<&str>) -> (Vec<String>, Vec<String>) {
     let mut tcp = vec![];
+    let mut udp = vec![];
     for peer in p {
         if peer == "" {
             continue;
@@ -26,10 +135,8 @@ pub async fn attach_peers(ep: &InetEndpoint, p: Vec<&str>) -> InetResult<()> {
         };
 
         match nmtt {
-            &"inet" => {
-                debug!("Initialising '{}' peering session with: '{}'", nmtt, rest);
-                tcp.push(rest)
-            }
+            &"inet" => tcp.push(rest),
+            &"quic" => udp.push(rest),
             tt => {
                 warn!("Unknown peer type: {}", tt);
                 continue;
@@ -37,5 +144,17 @@ pub async fn attach_peers(ep: &InetEndpoint, p: Vec<&str>) -> InetResult<()> {
         }
     }
 
-    ep.add_peers(tcp).await
+    (tcp, udp)
+}
+
+/// Check that a peer is written in PEER SYNTAX
+pub(crate) fn check_peer(peer: &str) -> Result<(), String> {
+    match peer.split_once('#') {
+        Some(("inet", "")) | Some(("quic", "")) => {
+            Err(format!("peer '{}' is missing an address", peer))
+        }
+        Some(("inet", _)) | Some(("quic", _)) => Ok(()),
+        Some((tt, _)) => Err(format!("unknown peer type '{}' in '{}'", tt, peer)),
+        None => Err(format!("peer '{}' is missing a driver identifier", peer)),
+    }
 }
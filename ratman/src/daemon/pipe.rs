@@ -0,0 +1,151 @@
+//! An in-memory connection for the client API
+//!
+//! This allows clients in the same process to talk to the daemon
+//! without opening a socket, which is mostly useful for tests.
+
+use async_std::io::{self, Read, Write};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// The number of bytes buffered in each direction
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Bytes in flight in one direction
+#[derive(Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    closed: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Buffer {
+    fn close(&mut self) {
+        self.closed = true;
+        wake(&mut self.reader);
+        wake(&mut self.writer);
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+type Pipe = Arc<Mutex<Buffer>>;
+
+/// One end of a connection, which closes it when dropped
+struct End {
+    rx: Pipe,
+    tx: Pipe,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.rx.lock().unwrap().close();
+        self.tx.lock().unwrap().close();
+    }
+}
+
+/// One end of an in-memory connection
+///
+/// Clones refer to the same end of the connection, which is closed
+/// once all of them have been dropped.
+#[derive(Clone)]
+pub(crate) struct MemStream(Arc<End>);
+
+impl MemStream {
+    /// Create two connected streams
+    pub(crate) fn pair() -> (Self, Self) {
+        let (a, b) = (Pipe::default(), Pipe::default());
+        let first = End {
+            rx: Arc::clone(&a),
+            tx: Arc::clone(&b),
+        };
+        (Self(Arc::new(first)), Self(Arc::new(End { rx: b, tx: a })))
+    }
+}
+
+impl Read for MemStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = self.0.rx.lock().unwrap();
+        if buf.data.is_empty() {
+            if buf.closed {
+                return Poll::Ready(Ok(0));
+            }
+
+            buf.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = out.len().min(buf.data.len());
+        for (to, from) in out.iter_mut().zip(buf.data.drain(..len)) {
+            *to = from;
+        }
+
+        wake(&mut buf.writer);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl Write for MemStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = self.0.tx.lock().unwrap();
+        if buf.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let len = data.len().min(PIPE_CAPACITY - buf.data.len());
+        if len == 0 && !data.is_empty() {
+            buf.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        buf.data.extend(&data[..len]);
+        wake(&mut buf.reader);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.tx.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_std::test]
+async fn pipe_round_trip() {
+    use async_std::io::{ReadExt, WriteExt};
+
+    let (mut a, mut b) = MemStream::pair();
+    let data: Vec<u8> = (0..PIPE_CAPACITY * 3).map(|i| i as u8).collect();
+
+    let expected = data.clone();
+    let writer = async_std::task::spawn(async move {
+        a.write_all(&data).await.unwrap();
+    });
+
+    let mut read = vec![0; expected.len()];
+    b.read_exact(&mut read).await.unwrap();
+    assert_eq!(read, expected);
+
+    // The connection is closed once the other end is dropped
+    writer.await;
+    assert_eq!(b.read(&mut read).await.unwrap(), 0);
+}
@@ -0,0 +1,361 @@
+//! Bounded per-client message queues
+//!
+//! Messages for a connected client are buffered in a queue of fixed
+//! size and written to the client stream by a separate task.  This
+//! way a slow client can't make the daemon's memory usage grow
+//! without limit.  What happens when a queue is full is determined
+//! by its [`QueuePolicy`].
+//!
+//! Messages spilled to disk are limited to the same quota as the
+//! inbox of each address.  When a spill file is full, its oldest
+//! messages are dropped.
+
+use crate::daemon::{inbox, parse, state::Io};
+use async_std::{
+    channel::{bounded, Receiver, Sender, TrySendError},
+    fs,
+    sync::{Arc, Mutex},
+    task,
+};
+use identity::Identity;
+use std::{fmt, path::PathBuf, str::FromStr};
+use types::{
+    api::{self, Receive},
+    Result,
+};
+
+/// Decide what happens to new messages when a client queue is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Remove the oldest queued message to make room
+    DropOldest,
+    /// Wait for the client to catch up, which blocks the router
+    Block,
+    /// Write new messages to disk until the queue has been drained,
+    /// and drop the oldest spilled messages when over quota
+    Spill,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "block" => Ok(Self::Block),
+            "spill" => Ok(Self::Spill),
+            p => Err(format!("unknown queue policy '{}'", p)),
+        }
+    }
+}
+
+impl fmt::Display for QueuePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::DropOldest => "drop-oldest",
+            Self::Block => "block",
+            Self::Spill => "spill",
+        })
+    }
+}
+
+impl From<QueuePolicy> for api::QueuePolicy {
+    fn from(p: QueuePolicy) -> Self {
+        match p {
+            QueuePolicy::DropOldest => Self::DROP_OLDEST,
+            QueuePolicy::Block => Self::BLOCK,
+            QueuePolicy::Spill => Self::SPILL,
+        }
+    }
+}
+
+/// Size and overflow policy used for all client queues
+#[derive(Copy, Clone, Debug)]
+pub struct QueueConfig {
+    pub size: usize,
+    pub policy: QueuePolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            size: 256,
+            policy: QueuePolicy::Block,
+        }
+    }
+}
+
+/// Messages that didn't fit into a queue, stored on disk
+struct Spill {
+    path: PathBuf,
+    /// Maximum size of the spill file in bytes
+    quota: u64,
+    /// Number of messages currently in the spill file
+    pending: Mutex<usize>,
+}
+
+impl Spill {
+    /// Append a message to the spill file
+    ///
+    /// If the file would grow beyond its quota the oldest spilled
+    /// messages are dropped.  A message which is larger than the
+    /// whole quota is handed back to the caller.
+    async fn write(&self, pending: &mut usize, recv: Receive) -> Result<Option<Receive>> {
+        let (buf, len) = inbox::encode(recv)?;
+        if len > self.quota {
+            return Ok(inbox::decode(&buf));
+        }
+
+        let size = fs::metadata(&self.path).await.map(|m| m.len()).unwrap_or(0);
+        if size + len > self.quota {
+            self.trim(pending, len).await?;
+        }
+
+        inbox::write_to(&self.path, &buf).await?;
+        *pending += 1;
+        Ok(None)
+    }
+
+    /// Drop the oldest spilled messages to make room for `len` bytes
+    ///
+    /// Frees a quarter of the quota at once, so that the file isn't
+    /// rewritten for every new message while the client is behind.
+    async fn trim(&self, pending: &mut usize, len: u64) -> Result<()> {
+        let target = (self.quota - self.quota / 4).saturating_sub(len);
+        let mut bufs = vec![];
+        for recv in inbox::read_all(&self.path, self.quota).await? {
+            bufs.push(inbox::encode(recv)?);
+        }
+
+        let mut size: u64 = bufs.iter().map(|(_, len)| len).sum();
+        let mut dropped = 0;
+        while size > target && dropped < bufs.len() {
+            size -= bufs[dropped].1;
+            dropped += 1;
+        }
+
+        warn!("Spill file is full: dropping {} oldest messages", dropped);
+        let bufs: Vec<_> = bufs.drain(dropped..).map(|(buf, _)| buf).collect();
+        inbox::write_all(&self.path, &bufs).await?;
+        *pending = bufs.len();
+        Ok(())
+    }
+
+    /// Take all spilled messages without forwarding them
+    async fn take(&self) -> Result<Vec<Receive>> {
+        let mut pending = self.pending.lock().await;
+        if *pending == 0 {
+            return Ok(vec![]);
+        }
+
+        let msgs = inbox::read_all(&self.path, self.quota).await?;
+        fs::remove_file(&self.path).await?;
+        *pending = 0;
+        Ok(msgs)
+    }
+
+    /// Forward all spilled messages to the client
+    ///
+    /// The spill file is moved out of the way first, so that new
+    /// messages can go into the queue again while this is running.
+    async fn drain(&self, io: &mut Io, streams: bool) -> Result<()> {
+        let drain_path = self.path.with_extension("drain");
+        {
+            let mut pending = self.pending.lock().await;
+            if *pending == 0 {
+                return Ok(());
+            }
+
+            debug!("Forwarding {} spilled messages", *pending);
+            fs::rename(&self.path, &drain_path).await?;
+            *pending = 0;
+        }
+
+        for recv in inbox::read_all(&drain_path, self.quota).await? {
+            parse::forward_recv(io, recv, streams).await?;
+        }
+
+        fs::remove_file(&drain_path).await?;
+        Ok(())
+    }
+}
+
+/// A bounded message queue for a single connected client
+pub(crate) struct ClientQueue {
+    tx: Sender<Receive>,
+    rx: Receiver<Receive>,
+    policy: QueuePolicy,
+    spill: Arc<Spill>,
+}
+
+impl ClientQueue {
+    /// Create a new queue and spawn the task writing it to `io`
+    ///
+    /// `streams` indicates whether the client accepts large payloads
+    /// as a stream of chunks.  At most `quota` bytes of messages are
+    /// spilled to disk.
+    pub(crate) fn spawn(
+        id: Identity,
+        io: Io,
+        cfg: &QueueConfig,
+        spill_dir: PathBuf,
+        quota: u64,
+        streams: bool,
+    ) -> Arc<Self> {
+        let (tx, rx) = bounded(cfg.size);
+        let spill = Arc::new(Spill {
+            path: spill_dir.join(format!("{}.spill", id)),
+            quota,
+            pending: Mutex::new(0),
+        });
+
+        // Remove messages left over from a previous connection
+        let _ = std::fs::remove_file(&spill.path);
+
+        task::spawn(run_writer(io, rx.clone(), Arc::clone(&spill), streams));
+        Arc::new(Self {
+            tx,
+            rx,
+            policy: cfg.policy,
+            spill,
+        })
+    }
+
+    /// Queue a message for the client
+    ///
+    /// If the queue is full the configured policy is applied.  If the
+    /// queue was closed the message is handed back to the caller.
+    pub(crate) async fn push(&self, recv: Receive) -> std::result::Result<(), Receive> {
+        match self.policy {
+            QueuePolicy::Block => self.tx.send(recv).await.map_err(|e| e.into_inner()),
+            QueuePolicy::DropOldest => self.drop_oldest(recv),
+            QueuePolicy::Spill => {
+                // Once messages have been spilled all new messages
+                // need to go to disk too to keep them in order
+                let mut pending = self.spill.pending.lock().await;
+                let recv = match *pending {
+                    0 => match self.tx.try_send(recv) {
+                        Ok(()) => return Ok(()),
+                        Err(TrySendError::Full(r)) => r,
+                        Err(TrySendError::Closed(r)) => return Err(r),
+                    },
+                    _ if self.tx.is_closed() => return Err(recv),
+                    _ => recv,
+                };
+
+                // Messages that can't be spilled at all still get a
+                // place in the queue
+                match self.spill.write(&mut pending, recv).await {
+                    Ok(Some(recv)) => return self.drop_oldest(recv),
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to spill message to disk: {}", e),
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Queue a message, removing the oldest ones to make room
+    fn drop_oldest(&self, mut recv: Receive) -> std::result::Result<(), Receive> {
+        loop {
+            match self.tx.try_send(recv) {
+                Ok(()) => break Ok(()),
+                Err(TrySendError::Full(r)) => recv = r,
+                Err(TrySendError::Closed(r)) => break Err(r),
+            }
+
+            if self.rx.try_recv().is_ok() {
+                warn!("Client queue is full: dropping oldest message");
+            }
+        }
+    }
+
+    /// Check whether this queue has been closed
+    pub(crate) fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Close the queue, which stops its writer task
+    ///
+    /// Messages that haven't been forwarded yet are returned, oldest
+    /// first.  They may include messages which were spilled to disk.
+    pub(crate) async fn close(&self) -> Vec<Receive> {
+        self.tx.close();
+
+        let mut msgs = vec![];
+        while let Ok(recv) = self.rx.try_recv() {
+            msgs.push(recv);
+        }
+
+        match self.spill.take().await {
+            Ok(spilled) => msgs.extend(spilled),
+            Err(e) => error!("Failed to read spilled messages: {}", e),
+        }
+        msgs
+    }
+}
+
+async fn run_writer(mut io: Io, rx: Receiver<Receive>, spill: Arc<Spill>, streams: bool) {
+    while let Ok(recv) = rx.recv().await {
+        if let Err(e) = parse::forward_recv(&mut io, recv, streams).await {
+            error!("Failed to forward received message: {}", e);
+            break;
+        }
+
+        if rx.is_empty() {
+            if let Err(e) = spill.drain(&mut io, streams).await {
+                error!("Failed to forward spilled messages: {}", e);
+                break;
+            }
+        }
+    }
+
+    rx.close();
+}
+
+#[test]
+fn parse_queue_policy() {
+    for p in &[
+        QueuePolicy::DropOldest,
+        QueuePolicy::Block,
+        QueuePolicy::Spill,
+    ] {
+        assert_eq!(p.to_string().parse::<QueuePolicy>(), Ok(*p));
+    }
+    assert!("drop-newest".parse::<QueuePolicy>().is_err());
+}
+
+#[async_std::test]
+async fn spill_is_bounded() {
+    let id = Identity::random();
+    let recv = |payload| {
+        let msg = types::message::received(id, id, Some(id), payload, String::new(), vec![]);
+        api::receive_default(msg)
+    };
+
+    // Large enough to hold exactly eight messages
+    let len = inbox::encode(recv(vec![0; 32])).unwrap().1;
+    let path = std::env::temp_dir().join(format!("ratmand-spill-{}", id));
+    let spill = Spill {
+        path: path.clone(),
+        quota: 8 * len,
+        pending: Mutex::new(0),
+    };
+
+    let mut pending = spill.pending.lock().await;
+    for i in 0..20 {
+        let rejected = spill.write(&mut pending, recv(vec![i; 32])).await.unwrap();
+        assert!(rejected.is_none());
+        assert!(fs::metadata(&path).await.unwrap().len() <= spill.quota);
+    }
+
+    // A message larger than the quota is handed back
+    let big = recv(vec![0; 8 * len as usize]);
+    assert!(spill.write(&mut pending, big).await.unwrap().is_some());
+    drop(pending);
+
+    let msgs = spill.take().await.unwrap();
+    assert_eq!(msgs.len(), 8);
+    assert_eq!(msgs[0].get_msg().get_payload(), &[12; 32]);
+    assert_eq!(msgs[7].get_msg().get_payload(), &[19; 32]);
+}
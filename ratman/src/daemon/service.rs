@@ -0,0 +1,97 @@
+//! Integration with service managers
+//!
+//! ratmand shuts down gracefully when it receives `SIGTERM` or
+//! `SIGINT`, and reports its state to systemd (or any other service
+//! manager implementing the `sd_notify` protocol) when started with
+//! `NOTIFY_SOCKET` set.
+
+use async_std::{io, task::spawn_blocking};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{env, os::unix::net::UnixDatagram};
+
+/// Install the handlers for signals which shut the daemon down
+///
+/// From this point on these signals no longer terminate the process,
+/// so this should happen before the daemon reports that it is ready.
+pub(crate) fn install_signal_handlers() -> io::Result<Signals> {
+    Signals::new([SIGTERM, SIGINT])
+}
+
+/// Wait until the daemon is asked to shut down
+///
+/// A second signal received while shutting down terminates the
+/// process immediately.
+pub(crate) async fn shutdown_signal(mut signals: Signals) {
+    let sig = spawn_blocking(move || {
+        let sig = signals.forever().next();
+        std::thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                warn!("Received second signal, exiting immediately");
+                std::process::exit(1);
+            }
+        });
+        sig
+    })
+    .await;
+
+    if let Some(sig) = sig {
+        info!("Received signal {}, shutting down", sig);
+    }
+}
+
+/// Send a state update (e.g. `READY=1`) to the service manager
+///
+/// Does nothing if ratmand wasn't started by a service manager that
+/// expects notifications.
+pub(crate) fn notify(state: &str) -> io::Result<()> {
+    match env::var("NOTIFY_SOCKET") {
+        Ok(path) => notify_to(&path, state),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Send a state update to the notification socket at `path`
+///
+/// Paths starting with `@` refer to an abstract socket address.
+fn notify_to(path: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn notify_socket() {
+    let path = env::temp_dir().join(format!("ratmand-notify-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixDatagram::bind(&path).unwrap();
+
+    notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+
+    let mut buf = [0; 64];
+    let len = listener.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[async_std::test]
+async fn signal_after_install() {
+    let signals = install_signal_handlers().unwrap();
+
+    // Arrives before anyone waits for it, but must not be lost
+    signal_hook::low_level::raise(SIGTERM).unwrap();
+    shutdown_signal(signals).await;
+}
@@ -1,35 +1,215 @@
-use crate::{daemon::parse, Router};
+use crate::{
+    daemon::{
+        inbox::Inbox,
+        parse::{self, Handshake, Streams},
+        queue::{ClientQueue, QueueConfig},
+    },
+    Error, Router,
+};
 use async_std::{
-    io::Result,
-    net::{Incoming, TcpListener, TcpStream},
-    stream::StreamExt,
+    io::{self, Read, Result, Write},
+    net::TcpStream,
+    stream::{Stream, StreamExt},
     sync::{Arc, Mutex},
-    task::{block_on, spawn_blocking},
+    task::{block_on, spawn, spawn_blocking},
 };
 use directories::ProjectDirs;
+
+#[cfg(feature = "harness")]
+use crate::daemon::pipe::MemStream;
 use identity::Identity;
 use std::{
     collections::{BTreeMap, BTreeSet},
     fs::{File, OpenOptions},
-    io::{Read, Write},
-    path::PathBuf,
+    io::{Read as _, Write as _},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::atomic::AtomicUsize,
+    task::{Context, Poll},
 };
+use types::api::{self, Receive};
+
+pub(crate) type OnlineMap = Arc<Mutex<BTreeMap<Identity, Option<Arc<ClientQueue>>>>>;
+
+/// Mark a client as online, after forwarding its inbox
+///
+/// New messages are stored in the inbox until the client's queue has
+/// been added to the online map, so that they are delivered in order.
+pub(crate) async fn set_online(
+    online: OnlineMap,
+    inbox: Inbox,
+    id: Identity,
+    queue: Arc<ClientQueue>,
+) {
+    loop {
+        let msgs = {
+            let mut map = online.lock().await;
+            match inbox.take(id).await {
+                Ok(msgs) if !msgs.is_empty() => msgs,
+                res => {
+                    if let Err(e) = res {
+                        error!("Failed to read inbox for {}: {}", id, e);
+                    }
 
-pub(crate) type OnlineMap = Arc<Mutex<BTreeMap<Identity, Option<Io>>>>;
+                    if !queue.is_closed() {
+                        map.insert(id, Some(queue));
+                    }
+                    return;
+                }
+            }
+        };
 
+        debug!("Forwarding {} messages from inbox of {}", msgs.len(), id);
+        let mut msgs = msgs.into_iter();
+        while let Some(recv) = msgs.next() {
+            // The client disconnected again: put the messages back
+            if let Err(recv) = queue.push(recv).await {
+                let _map = online.lock().await;
+                for recv in Some(recv).into_iter().chain(msgs) {
+                    if let Err(e) = inbox.store(id, recv).await {
+                        error!("Failed to store message for {}: {}", id, e);
+                    }
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Mark a client as offline after its connection was dropped
+///
+/// Because a client may already have re-connected, this only
+/// removes the entry if it still refers to the given queue.  Any
+/// messages which weren't forwarded yet are moved to the inbox.
+pub(crate) async fn set_offline(
+    online: &OnlineMap,
+    inbox: &Inbox,
+    id: Identity,
+    queue: &Arc<ClientQueue>,
+) {
+    let mut map = online.lock().await;
+    if let Some(entry) = map.get_mut(&id) {
+        if entry.as_ref().map(|q| Arc::ptr_eq(q, queue)) == Some(true) {
+            *entry = None;
+        }
+    }
+
+    for recv in queue.close().await {
+        if let Err(e) = inbox.store(id, recv).await {
+            error!("Failed to store message for {}: {}", id, e);
+        }
+    }
+}
+
+/// Deliver a message to a client, or store it in its inbox
+///
+/// Messages for unknown addresses are dropped.
+pub(crate) async fn deliver(online: &OnlineMap, inbox: &Inbox, id: Identity, mut recv: Receive) {
+    let mut failed: Option<Arc<ClientQueue>> = None;
+    loop {
+        let map = online.lock().await;
+        let queue = match map.get(&id) {
+            Some(Some(q)) if failed.as_ref().map(|f| Arc::ptr_eq(f, q)) != Some(true) => {
+                Arc::clone(q)
+            }
+            None => return,
+            // The client is offline or its queue was closed
+            Some(_) => {
+                debug!("Storing message for offline client {}", id);
+                if let Err(e) = inbox.store(id, recv).await {
+                    error!("Failed to store message for {}: {}", id, e);
+                }
+                return;
+            }
+        };
+
+        // Don't hold the lock while pushing because this may block
+        // until the client has caught up
+        drop(map);
+        match queue.push(recv).await {
+            Ok(()) => return,
+            Err(r) => {
+                recv = r;
+                failed = Some(queue);
+            }
+        }
+    }
+}
+
+/// A client connection, shared between the tasks writing to it
 #[derive(Clone)]
 pub(crate) enum Io {
-    Tcp(TcpStream),
+    Tcp(TcpStream, Arc<Mutex<()>>),
+    #[cfg(feature = "harness")]
+    Mem(MemStream, Arc<Mutex<()>>),
 }
 
 impl Io {
-    pub(crate) fn as_io(&mut self) -> &mut (impl async_std::io::Write + async_std::io::Read) {
+    pub(crate) fn tcp(stream: TcpStream) -> Self {
+        Self::Tcp(stream, Arc::new(Mutex::new(())))
+    }
+
+    #[cfg(feature = "harness")]
+    pub(crate) fn mem(stream: MemStream) -> Self {
+        Self::Mem(stream, Arc::new(Mutex::new(())))
+    }
+
+    /// Get the lock which guards writes to this connection
+    ///
+    /// Hold this lock while writing frames to make sure that frames
+    /// written by different tasks don't interleave.
+    pub(crate) fn write_lock(&self) -> Arc<Mutex<()>> {
         match self {
-            Self::Tcp(ref mut stream) => stream,
+            Self::Tcp(_, ref lock) => Arc::clone(lock),
+            #[cfg(feature = "harness")]
+            Self::Mem(_, ref lock) => Arc::clone(lock),
+        }
+    }
+}
+
+impl Read for Io {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(ref mut stream, _) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "harness")]
+            Self::Mem(ref mut stream, _) => Pin::new(stream).poll_read(cx, buf),
         }
     }
 }
 
+impl Write for Io {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(ref mut stream, _) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "harness")]
+            Self::Mem(ref mut stream, _) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(ref mut stream, _) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "harness")]
+            Self::Mem(ref mut stream, _) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(ref mut stream, _) => Pin::new(stream).poll_close(cx),
+            #[cfg(feature = "harness")]
+            Self::Mem(ref mut stream, _) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+/// A stream of new client connections
+pub(crate) type Connections<'a> = Pin<Box<dyn Stream<Item = io::Result<Io>> + Send + Sync + 'a>>;
+
 async fn load_users(router: &Router, path: PathBuf) -> Vec<Identity> {
     debug!("Loading registered users from file {:?}", path);
     let mut f = match File::open(path) {
@@ -47,7 +227,11 @@ async fn load_users(router: &Router, path: PathBuf) -> Vec<Identity> {
         Ok(vec) => {
             for addr in &vec {
                 trace!("Loading addr {}", addr);
-                let e1 = router.add_user(*addr).await;
+                // Users may already be known from a router snapshot
+                let e1 = match router.add_user(*addr).await {
+                    Err(Error::DuplicateUser) => Ok(()),
+                    res => res,
+                };
                 let e2 = router.online(*addr).await;
 
                 if e1.is_err() || e2.is_err() {
@@ -61,27 +245,83 @@ async fn load_users(router: &Router, path: PathBuf) -> Vec<Identity> {
     }
 }
 
-fn data_path(dirs: &ProjectDirs) -> PathBuf {
-    let data_dir = dirs.data_dir();
+/// The directory used to store daemon state unless configured otherwise
+pub(crate) fn default_data_dir() -> PathBuf {
+    ProjectDirs::from("org", "irdest", "ratmand")
+        .expect("Failed to initialise project directories")
+        .data_dir()
+        .to_path_buf()
+}
+
+fn data_path(data_dir: &Path) -> PathBuf {
     trace!("Ensure data directory exists: {:?}", data_dir);
-    let _ = std::fs::create_dir(&data_dir);
-    PathBuf::new().join(data_dir).join("users.json")
+    let _ = std::fs::create_dir_all(data_dir);
+    data_dir.join("users.json")
 }
 
 /// Keep track of current connections to stream messages to
 pub(crate) struct DaemonState<'a> {
     router: Router,
     online: OnlineMap,
-    listen: Incoming<'a>,
-    dirs: ProjectDirs,
+    listen: Connections<'a>,
+    data_dir: PathBuf,
+    queue: QueueConfig,
+    inbox: Inbox,
+    /// Number of currently connected clients
+    clients: Arc<AtomicUsize>,
+    /// Lets clients change the daemon's configuration
+    control_token: Vec<u8>,
+    /// The maximum total payload length of a streamed message
+    max_stream_len: u64,
+}
+
+/// A client which completed the handshake
+pub(crate) struct Connection {
+    pub(crate) io: Io,
+    /// The address of an authenticated client, and the queue used to
+    /// forward messages to it
+    pub(crate) client: Option<(Identity, Arc<ClientQueue>)>,
+    /// Whether the client may change the daemon's configuration
+    pub(crate) privileged: bool,
+    /// Payloads the client is currently streaming
+    pub(crate) streams: Streams,
+}
+
+/// Create a new control token in the data directory
+///
+/// The file is only readable by the user running the daemon, so that
+/// other users can't reconfigure it.
+fn write_control_token(data_dir: &Path) -> Result<Vec<u8>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // An identity is 32 random bytes
+    let token = Identity::random().as_bytes().to_vec();
+    let path = data_dir.join(api::CONTROL_TOKEN_FILE);
+    let _ = std::fs::remove_file(&path);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(&token)?;
+    Ok(token)
 }
 
 impl<'a> DaemonState<'a> {
-    pub(crate) fn new(l: &'a TcpListener, router: Router) -> Self {
-        let dirs = ProjectDirs::from("org", "irdest", "ratmand")
-            .expect("Failed to initialise project directories");
+    pub(crate) fn new(
+        listen: Connections<'a>,
+        router: Router,
+        queue: QueueConfig,
+        inbox_quota: u64,
+        max_stream_len: u64,
+        data_dir: PathBuf,
+    ) -> Self {
+        let inbox = Inbox::new(data_dir.join("inbox"), inbox_quota);
 
-        let path = data_path(&dirs);
+        // Streams which were cut off by a restart are never finished
+        let _ = std::fs::remove_dir_all(data_dir.join("streams"));
+
+        let path = data_path(&data_dir);
         let r2 = router.clone();
         let online = block_on(async move {
             load_users(&r2, path)
@@ -91,11 +331,21 @@ impl<'a> DaemonState<'a> {
                 .collect()
         });
 
+        let control_token = write_control_token(&data_dir).unwrap_or_else(|e| {
+            warn!("Failed to write control token: {}", e);
+            Identity::random().as_bytes().to_vec()
+        });
+
         Self {
             online: Arc::new(Mutex::new(online)),
-            listen: l.incoming(),
+            listen,
             router,
-            dirs,
+            data_dir,
+            queue,
+            inbox,
+            clients: Default::default(),
+            control_token,
+            max_stream_len,
         }
     }
 
@@ -120,44 +370,124 @@ impl<'a> DaemonState<'a> {
             Ok(())
         }
 
-        let path = data_path(&self.dirs);
+        let path = data_path(&self.data_dir);
         let ids: Vec<_> = self.online.lock().await.iter().map(|(k, _)| *k).collect();
         spawn_blocking(move || sync_blocking(path, ids)).await?;
         Ok(())
     }
 
+    /// Mark all addresses as offline before shutting down
+    ///
+    /// Messages still waiting in client queues are moved to the
+    /// inbox, so that they are delivered after the next start.
+    pub(crate) async fn offline_all(&self) {
+        let clients: Vec<_> = self
+            .online
+            .lock()
+            .await
+            .iter()
+            .map(|(id, queue)| (*id, queue.clone()))
+            .collect();
+
+        for (id, queue) in clients {
+            if let Err(e) = self.router.offline(id).await {
+                debug!("Failed to mark {} as offline: {}", id, e);
+            }
+            if let Some(queue) = queue {
+                set_offline(&self.online, &self.inbox, id, &queue).await;
+            }
+        }
+    }
+
     pub(crate) async fn get_online(&self) -> OnlineMap {
         Arc::clone(&self.online)
     }
 
-    /// Listen for new connections on a socket address
-    pub(crate) async fn listen_for_connections(&mut self) -> Result<Option<Io>> {
-        while let Some(stream) = self.listen.next().await {
-            let mut stream = stream?;
+    pub(crate) fn get_inbox(&self) -> Inbox {
+        self.inbox.clone()
+    }
+
+    pub(crate) fn get_clients(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.clients)
+    }
+
+    fn spill_dir(&self) -> PathBuf {
+        let spill_dir = self.data_dir.join("spill");
+        let _ = std::fs::create_dir_all(&spill_dir);
+        spill_dir
+    }
 
-            let (id, _) = match parse::handle_auth(&mut stream, &self.router).await {
-                Ok(Some(pair)) => {
-                    debug!("Successfully authenticated: {:?}", pair.0);
-                    pair
+    fn streams(&self) -> Streams {
+        let stream_dir = self.data_dir.join("streams");
+        let _ = std::fs::create_dir_all(&stream_dir);
+        Streams::new(stream_dir, self.max_stream_len)
+    }
+
+    /// Wait for the next client connection
+    ///
+    /// Returns `None` once no more connections can be accepted.
+    pub(crate) async fn listen_for_connections(&mut self) -> Result<Option<Connection>> {
+        while let Some(io) = self.listen.next().await {
+            let mut io = io?;
+
+            let handshake =
+                parse::handle_auth(&mut io, &self.router, &self.queue, &self.control_token).await;
+            let (id, streams) = match handshake {
+                Ok(Handshake {
+                    auth: Some((id, _)),
+                    features,
+                    ..
+                }) => {
+                    debug!("Successfully authenticated: {:?}", id);
+                    (id, features.iter().any(|f| f == api::features::STREAMS))
                 }
                 // An anonymous client doesn't need an entry in the
                 // lookup table because no message will ever be
                 // addressed to it
-                Ok(None) => return Ok(Some(Io::Tcp(stream))),
+                Ok(Handshake {
+                    auth: None,
+                    privileged,
+                    ..
+                }) => {
+                    return Ok(Some(Connection {
+                        io,
+                        client: None,
+                        privileged,
+                        streams: self.streams(),
+                    }))
+                }
                 Err(e) => {
                     error!("Encountered error during auth: {}", e);
-                    break;
+                    continue;
                 }
             };
 
-            let io = Io::Tcp(stream);
-            self.online.lock().await.insert(id, Some(io.clone()));
+            let queue = ClientQueue::spawn(
+                id,
+                io.clone(),
+                &self.queue,
+                self.spill_dir(),
+                self.inbox.quota(),
+                streams,
+            );
+            self.online.lock().await.entry(id).or_insert(None);
+            spawn(set_online(
+                Arc::clone(&self.online),
+                self.inbox.clone(),
+                id,
+                Arc::clone(&queue),
+            ));
 
             if let Err(e) = self.sync_users().await {
                 error!("Failed to sync known addresses: {}", e);
             }
 
-            return Ok(Some(io));
+            return Ok(Some(Connection {
+                io,
+                client: Some((id, queue)),
+                privileged: false,
+                streams: self.streams(),
+            }));
         }
 
         Ok(None)
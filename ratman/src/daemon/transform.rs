@@ -1,6 +1,6 @@
-use crate::{Message, MsgId, Recipient, TimePair};
+use crate::{Error, Message, MsgId, Recipient, TimePair};
 use identity::Identity;
-use types::api::{Send, Send_Type};
+use types::api::{self, ApiError, ErrorCode, Send, Send_Type};
 
 /// Turn an API `Send` to a `Message`
 pub(crate) fn send_to_message(s: Send) -> Vec<Message> {
@@ -31,3 +31,21 @@ pub(crate) fn send_to_message(s: Send) -> Vec<Message> {
         })
         .collect()
 }
+
+/// Turn a Ratman `Error` into an API `Error` with a matching code
+pub(crate) fn error_to_api(e: &Error) -> ApiError {
+    let code = match e {
+        Error::InitFailed => ErrorCode::INIT_FAILED,
+        Error::EncodeFailed => ErrorCode::ENCODE_FAILED,
+        Error::DecodeFailed => ErrorCode::DECODE_FAILED,
+        Error::DispatchFailed => ErrorCode::DISPATCH_FAILED,
+        Error::PayloadTooLarge => ErrorCode::PAYLOAD_TOO_LARGE,
+        Error::DuplicateUser => ErrorCode::DUPLICATE_USER,
+        Error::NoUser => ErrorCode::NO_USER,
+        Error::NoEndpoint => ErrorCode::NO_ENDPOINT,
+        Error::NotSupportedOnPlatform => ErrorCode::NOT_SUPPORTED,
+        Error::Io(_) => ErrorCode::UNKNOWN,
+    };
+
+    api::error(code, e.to_string())
+}
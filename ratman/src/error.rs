@@ -4,23 +4,37 @@
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// A Ratman error type
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// An error occured during router initialisation
+    #[error("failed to initialise the router")]
     InitFailed,
     /// While sending an encoding operation failed
+    #[error("failed to encode payload")]
     EncodeFailed,
     /// Decoding a payload failed
+    #[error("failed to decode payload")]
     DecodeFailed,
     /// While sending, a dispatch operation failed
+    #[error("failed to dispatch message")]
     DispatchFailed,
     /// The provided payload was too large and was rejected
+    #[error("the provided payload was too large")]
     PayloadTooLarge,
     /// An action failed because of a user collision
+    #[error("the provided address already exists")]
     DuplicateUser,
     /// An action failed because of a missing user
+    #[error("the provided address is unknown")]
     NoUser,
+    /// An action failed because of a missing endpoint
+    #[error("the provided endpoint is unknown")]
+    NoEndpoint,
+    /// Reading or writing stored router state failed
+    #[error("failed to access router state: {0}")]
+    Io(#[from] std::io::Error),
     /// Indicates that something isn't supported on the platform
+    #[error("operation not supported on this platform")]
     NotSupportedOnPlatform,
 }
 
@@ -119,6 +119,7 @@ pub mod clock;
 mod core;
 mod data;
 mod error;
+mod metrics;
 mod protocol;
 mod slicer;
 
@@ -133,18 +134,21 @@ pub(crate) type IoPair<T> = (Sender<T>, Receiver<T>);
 pub use crate::{
     data::{Message, MsgId, TimePair},
     error::{Error, Result},
+    metrics::{EndpointMetrics, Metrics},
     netmod::Recipient,
 };
 pub use identity::{Identity, ID_LEN};
 pub use netmod;
 
-use crate::core::Core;
+use crate::core::{Core, Snapshot};
 use async_std::{
     channel::{Receiver, Sender},
     sync::Arc,
+    task,
 };
 use clock::{ClockCtrl, Tasks};
 use netmod::Endpoint;
+use std::path::Path;
 
 /// Primary async ratman router handle
 ///
@@ -161,9 +165,9 @@ pub struct Router {
 impl Router {
     /// Create a new and empty message router
     ///
-    /// It's currently not possible to restore a router from stored
-    /// state, which means that all routing tables are lost when the
-    /// router is stopped.
+    /// To keep routing tables between sessions, take a
+    /// [`snapshot`](Self::snapshot) before stopping the router, and
+    /// [`restore`](Self::restore) it into a new one.
     pub fn new() -> Self {
         let proto = Protocol::new();
         let inner = Arc::new(Core::init());
@@ -197,6 +201,58 @@ impl Router {
         self.inner.rm_ep(id).await;
     }
 
+    /// Enable or disable an endpoint by ID
+    ///
+    /// A disabled endpoint keeps its ID, but no frames are sent via
+    /// it, and frames it receives are dropped, until it is enabled
+    /// again.  Returns an error if no such endpoint exists.
+    pub async fn set_endpoint_enabled(&self, id: usize, enabled: bool) -> Result<()> {
+        self.inner.set_ep_enabled(id, enabled).await
+    }
+
+    /// Read the current values of the router's internal counters
+    pub async fn metrics(&self) -> Metrics {
+        self.inner.metrics().await
+    }
+
+    /// Save the state of this router to a directory
+    ///
+    /// This includes the routing table with the time each route was
+    /// last updated, local users and whether they are online, frames
+    /// that couldn't be delivered yet, and the set of frames that were
+    /// already seen.  A previous snapshot in the same directory is
+    /// replaced.
+    pub async fn snapshot(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let mut snapshot = self.inner.snapshot().await;
+        snapshot.online = self.proto.online_ids().await;
+
+        let dir = dir.as_ref().to_path_buf();
+        task::spawn_blocking(move || snapshot.save(&dir)).await
+    }
+
+    /// Restore state that was saved via [`snapshot`](Self::snapshot)
+    ///
+    /// Routes refer to endpoints by their ID, so all endpoints need to
+    /// be added again, in the same order, before calling this
+    /// function.  Users that were online are marked as online again.
+    /// If the directory doesn't contain a snapshot, the router is left
+    /// unchanged.
+    pub async fn restore(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut snapshot = match task::spawn_blocking(move || Snapshot::load(&dir)).await? {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        let online = std::mem::take(&mut snapshot.online);
+        self.inner.restore(snapshot).await;
+        for id in online {
+            self.online(id).await?;
+        }
+
+        Ok(())
+    }
+
     /// Add an identity to the local set
     ///
     /// Ratman will listen for messages to local identities and offer
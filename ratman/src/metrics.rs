@@ -0,0 +1,32 @@
+//! Router counters for monitoring
+
+/// Frame counters of a single endpoint
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EndpointMetrics {
+    /// The ID returned by `Router::add_endpoint`
+    pub id: usize,
+    /// Whether the endpoint is currently enabled
+    pub enabled: bool,
+    /// Number of frames received via this endpoint
+    pub frames_in: u64,
+    /// Number of frames sent via this endpoint
+    pub frames_out: u64,
+}
+
+/// The current values of a router's internal counters
+///
+/// Frame counters only ever increase, while all other values
+/// describe the state of the router at the time they were read.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Counters of every endpoint that wasn't removed
+    pub endpoints: Vec<EndpointMetrics>,
+    /// Number of frames in the journal that couldn't be delivered yet
+    pub journal_frames: usize,
+    /// Number of messages which are currently being collected
+    pub collector_workers: usize,
+    /// Number of remote addresses with a known route
+    pub remote_routes: usize,
+    /// Number of local addresses
+    pub local_users: usize,
+}
@@ -83,6 +83,17 @@ impl Protocol {
             .map_or(Err(Error::NoUser), |_| Ok(()))
     }
 
+    /// Get all users which are currently marked as online
+    pub(crate) async fn online_ids(&self) -> Vec<Identity> {
+        self.online
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, b)| b.load(Ordering::Relaxed))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
     /// Try to parse a frame as an announcement
     pub(crate) fn is_announce(f: &Frame) -> Option<Identity> {
         let Frame { ref payload, .. } = f;
@@ -0,0 +1,65 @@
+//! Connect clients to an in-process test network
+//!
+//! This test needs the `harness` feature to run.
+
+#![cfg(feature = "harness")]
+
+use async_std::{future, task};
+use ratman::daemon::harness::TestNetwork;
+use ratman_client::Error;
+use std::time::Duration;
+use types::api::ErrorCode;
+
+#[async_std::test]
+async fn clients_on_test_network() {
+    let net = TestNetwork::new(2).await;
+    let alice = net.node(0).client().await.unwrap();
+    let bob = net.node(1).client().await.unwrap();
+
+    // Wait for the routers to announce bob's address
+    future::timeout(Duration::from_secs(10), async {
+        while !alice.get_peers().await.unwrap().contains(&bob.address()) {
+            task::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("bob was never discovered");
+
+    alice
+        .send_to(bob.address(), b"Hello Bob!".to_vec())
+        .await
+        .unwrap();
+
+    let (_, msg) = bob.next().await.unwrap();
+    assert_eq!(msg.get_sender(), alice.address().as_bytes());
+    assert_eq!(msg.get_payload(), b"Hello Bob!");
+}
+
+#[async_std::test]
+async fn manage_running_node() {
+    let net = TestNetwork::new(2).await;
+
+    // Only clients with the control token may manage the node
+    let client = net.node(0).client().await.unwrap();
+    match client.set_log_level("debug").await {
+        Err(Error::Remote(code, _)) => assert_eq!(code, ErrorCode::NOT_PERMITTED),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    let client = net.node(0).control().await.unwrap();
+
+    client.set_endpoint_enabled(0, false).await.unwrap();
+    client.set_endpoint_enabled(0, true).await.unwrap();
+
+    match client.set_endpoint_enabled(13, false).await {
+        Err(Error::Remote(code, _)) => assert_eq!(code, ErrorCode::NO_ENDPOINT),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // Test nodes don't run the inet driver
+    let peers = vec!["inet#127.0.0.1:9000".to_owned()];
+    match client.add_peers(peers).await {
+        Err(Error::Remote(code, _)) => assert_eq!(code, ErrorCode::NOT_SUPPORTED),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
@@ -0,0 +1,52 @@
+//! Restore a router from a snapshot
+//!
+//! A router learns about a remote user, and is then replaced by a new
+//! router which restores the state of the old one.  The new router
+//! knows the remote user, and its local user, without any network
+//! traffic.
+
+use netmod_mem::MemMod;
+use ratman::{Identity, Result, Router};
+
+#[async_std::test]
+async fn snapshot_and_restore() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("ratman-snapshot-{}", Identity::random()));
+
+    let (mm1, mm2) = MemMod::make_pair();
+    let r1 = Router::new();
+    let r2 = Router::new();
+    r1.add_endpoint(mm1).await;
+    r2.add_endpoint(mm2).await;
+
+    let u1 = Identity::random();
+    r1.add_user(u1).await?;
+    r1.online(u1).await?;
+
+    let u2 = Identity::random();
+    r2.add_user(u2).await?;
+    r2.online(u2).await?;
+
+    assert_eq!(r1.discover().await, u2);
+    r1.snapshot(&dir).await?;
+
+    // A new router with the same endpoints picks up the old state
+    let (mm1, _mm2) = MemMod::make_pair();
+    let restored = Router::new();
+    restored.add_endpoint(mm1).await;
+    restored.restore(&dir).await?;
+
+    assert!(restored.known(u2).await.is_ok());
+    assert!(restored.known_addresses().await.contains(&u1));
+
+    // The local user was online and can't be added a second time
+    assert!(restored.add_user(u1).await.is_err());
+    assert!(restored.offline(u1).await.is_ok());
+
+    // Restoring from a directory without a snapshot changes nothing
+    let empty = Router::new();
+    empty.restore(dir.join("missing")).await?;
+    assert!(empty.known_addresses().await.is_empty());
+
+    let _ = std::fs::remove_dir_all(&dir);
+    Ok(())
+}
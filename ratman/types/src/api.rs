@@ -2,8 +2,10 @@
 
 use crate::message::Message;
 pub use crate::proto::api::{
-    ApiMessage, ApiMessage_oneof_inner as ApiMessageEnum, Peers, Peers_Type, Receive, Receive_Type,
-    Send, Send_Type, Setup, Setup_Type, Setup_oneof__id, Setup_oneof__token,
+    Ack, ApiMessage, ApiMessage_oneof_inner as ApiMessageEnum, Chunk, Error as ApiError,
+    Error_Code as ErrorCode, Manage, Manage_Type, Peers, Peers_Type, Receive, Receive_Type, Send,
+    Send_Type, Setup, Setup_QueuePolicy as QueuePolicy, Setup_Type, Setup_oneof__id,
+    Setup_oneof__token,
 };
 use ratman_identity::Identity;
 
@@ -85,6 +87,79 @@ pub fn anonymous() -> Setup {
     setup
 }
 
+/// The file in the daemon's data directory holding its control token
+///
+/// It is only readable by the user running the daemon, and a new
+/// token is created every time the daemon starts.
+pub const CONTROL_TOKEN_FILE: &str = "control.token";
+
+/// Create a setup message for an anonymous client with the daemon's
+/// control token, which allows it to change the daemon's configuration
+pub fn control(token: Vec<u8>) -> Setup {
+    let mut setup = anonymous();
+    setup.set_token(token);
+    setup
+}
+
+/// Create an ack message for an anonymous client
+pub fn anonymous_ack() -> Setup {
+    let mut setup = Setup::new();
+    setup.set_field_type(Setup_Type::ACK);
+    setup
+}
+
+/// Attach version and feature information to a setup message
+///
+/// Clients send their library version during the handshake, while
+/// the daemon replies with its own version and the set of protocol
+/// features it supports (see [`features`](crate::api::features)).
+pub fn with_version(mut setup: Setup, version: &str, features: &[&str]) -> Setup {
+    setup.set_version(version.to_owned());
+    setup.set_features(features.iter().map(|f| f.to_string()).collect());
+    setup
+}
+
+/// Attach the daemon's queue configuration to a setup message
+pub fn with_queue(mut setup: Setup, policy: QueuePolicy, size: u32) -> Setup {
+    setup.set_queue_policy(policy);
+    setup.set_queue_size(size);
+    setup
+}
+
+/// Protocol features a daemon can announce during the handshake
+pub mod features {
+    /// Responses carry the request ID of the request that caused them
+    pub const REQUEST_ID: &str = "request-id";
+    /// Requests are answered with an `Ack` or an `Error`
+    pub const ERRORS: &str = "errors";
+    /// Large payloads can be sent as a stream of `Chunk` messages
+    pub const STREAMS: &str = "streams";
+    /// The daemon can be reconfigured via `Manage` messages
+    pub const MANAGE: &str = "manage";
+}
+
+//////////// CHUNK type
+
+/// Create a new chunk for a streamed payload
+pub fn chunk(stream: u64, seq: u64, data: Vec<u8>, last: bool) -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.set_stream(stream);
+    chunk.set_seq(seq);
+    chunk.set_data(data);
+    chunk.set_last(last);
+    chunk
+}
+
+//////////// ERROR type
+
+/// Create a new error message
+pub fn error<S: Into<String>>(code: ErrorCode, msg: S) -> ApiError {
+    let mut err = ApiError::new();
+    err.set_code(code);
+    err.set_message(msg.into());
+    err
+}
+
 //////////// PEERS type
 
 /// Create a new discovery message
@@ -120,6 +195,45 @@ pub fn peers_req() -> Peers {
     peers
 }
 
+//////////// MANAGE type
+
+fn manage(t: Manage_Type) -> Manage {
+    let mut manage = Manage::new();
+    manage.set_field_type(t);
+    manage
+}
+
+/// Add a set of peers to the daemon's drivers
+pub fn add_peers(peers: Vec<String>) -> Manage {
+    let mut manage = manage(Manage_Type::ADD_PEERS);
+    manage.set_peers(peers.into());
+    manage
+}
+
+/// Remove a set of peers from the daemon's drivers
+pub fn remove_peers(peers: Vec<String>) -> Manage {
+    let mut manage = manage(Manage_Type::REMOVE_PEERS);
+    manage.set_peers(peers.into());
+    manage
+}
+
+/// Enable or disable one of the daemon's endpoints
+pub fn set_endpoint(id: u64, enabled: bool) -> Manage {
+    let mut manage = manage(match enabled {
+        true => Manage_Type::ENABLE_ENDPOINT,
+        false => Manage_Type::DISABLE_ENDPOINT,
+    });
+    manage.set_endpoint(id);
+    manage
+}
+
+/// Change the log level of the daemon
+pub fn log_level<S: Into<String>>(level: S) -> Manage {
+    let mut manage = manage(Manage_Type::LOG_LEVEL);
+    manage.set_level(level.into());
+    manage
+}
+
 //////////// APIMESAGE type
 
 pub fn api_send(s: Send) -> ApiMessage {
@@ -134,6 +248,12 @@ pub fn api_recv(r: Receive) -> ApiMessage {
     msg
 }
 
+pub fn api_chunk(c: Chunk) -> ApiMessage {
+    let mut msg = ApiMessage::new();
+    msg.set_chunk(c);
+    msg
+}
+
 pub fn api_setup(s: Setup) -> ApiMessage {
     let mut msg = ApiMessage::new();
     msg.set_setup(s);
@@ -145,3 +265,31 @@ pub fn api_peers(p: Peers) -> ApiMessage {
     msg.set_peers(p);
     msg
 }
+
+pub fn api_manage(m: Manage) -> ApiMessage {
+    let mut msg = ApiMessage::new();
+    msg.set_manage(m);
+    msg
+}
+
+pub fn api_error(e: ApiError) -> ApiMessage {
+    let mut msg = ApiMessage::new();
+    msg.set_error(e);
+    msg
+}
+
+pub fn api_ack() -> ApiMessage {
+    let mut msg = ApiMessage::new();
+    msg.set_ack(Ack::new());
+    msg
+}
+
+/// Attach a request ID to an API message
+///
+/// The daemon copies this ID into the response it sends for a
+/// request, which allows a client to match responses to requests
+/// even when other messages are interleaved on the same stream.
+pub fn with_id(mut msg: ApiMessage, id: u64) -> ApiMessage {
+    msg.set_id(id);
+    msg
+}
@@ -1,4 +1,5 @@
-use async_std::io;
+use crate::api::{ApiError, ErrorCode};
+use std::io;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -10,13 +11,31 @@ pub enum Error {
     Proto(#[from] protobuf::ProtobufError),
     #[error("failed to provide correct authentication in handshake")]
     InvalidAuth,
+    #[error("connection to the daemon was lost before a response arrived")]
+    ConnectionLost,
+    #[error("daemon version `{0}` is incompatible with client version `{1}`")]
+    IncompatibleVersion(String, String),
+    #[error("received an unexpected response from the daemon")]
+    UnexpectedResponse,
+    #[error("daemon failed to execute request: {1} ({0:?})")]
+    Remote(ErrorCode, String),
+    #[error("frame of {0} bytes exceeds the maximum frame length")]
+    FrameTooLarge(u64),
+    #[error("daemon doesn't support the `{0}` feature")]
+    Unsupported(&'static str),
+}
+
+impl From<ApiError> for Error {
+    fn from(mut e: ApiError) -> Self {
+        Self::Remote(e.get_code(), e.take_message())
+    }
 }
 
 impl From<Error> for io::Error {
     fn from(e: Error) -> Self {
         match e {
             Error::Io(e) => e,
-            e => panic!("unexpected IPC error: {}", e),
+            e => io::Error::other(e),
         }
     }
 }
@@ -12,13 +12,19 @@ pub use error::{Error, Result};
 pub use ratman_identity::Identity;
 
 use api::ApiMessage;
-use async_std::{
-    io::{Read, Write},
-    prelude::*,
-};
 use byteorder::{BigEndian, ByteOrder};
+use futures::io::{AsyncRead as Read, AsyncReadExt, AsyncWrite as Write, AsyncWriteExt};
 use protobuf::Message;
 
+/// The maximum length of a frame accepted by [`read_with_length`]
+pub const MAX_FRAME_LEN: u64 = 16 * 1024 * 1024;
+
+/// Payloads larger than this are split into `Chunk` messages
+///
+/// This leaves room for message metadata in a frame, and is also
+/// used as the size of each chunk.
+pub const MAX_PAYLOAD_LEN: usize = 8 * 1024 * 1024;
+
 /// First write the length as big-endian u64, then write the provided buffer
 pub async fn write_with_length<T: Write + Unpin>(t: &mut T, buf: &Vec<u8>) -> Result<usize> {
     let mut len = vec![0; 8];
@@ -29,12 +35,26 @@ pub async fn write_with_length<T: Write + Unpin>(t: &mut T, buf: &Vec<u8>) -> Re
 }
 
 /// First read a big-endian u64, then read the number of bytes
+///
+/// Frames longer than [`MAX_FRAME_LEN`] are rejected.
 pub async fn read_with_length<T: Read + Unpin>(r: &mut T) -> Result<Vec<u8>> {
+    read_with_limit(r, MAX_FRAME_LEN).await
+}
+
+/// Read a length-prefixed buffer, rejecting frames longer than `max`
+///
+/// The length is checked before allocating a buffer for the frame.
+/// Use this function to read frames from a trusted source which may
+/// exceed [`MAX_FRAME_LEN`].
+pub async fn read_with_limit<T: Read + Unpin>(r: &mut T, max: u64) -> Result<Vec<u8>> {
     let mut len_buf = vec![0; 8];
     r.read_exact(&mut len_buf).await?;
     let len = BigEndian::read_u64(&len_buf);
+    if len > max || len > usize::MAX as u64 {
+        return Err(Error::FrameTooLarge(len));
+    }
 
-    let mut vec = vec![0; len as usize]; // FIXME: this might break on 32bit systems
+    let mut vec = vec![0; len as usize];
     r.read_exact(&mut vec).await?;
     Ok(vec)
 }